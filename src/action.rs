@@ -0,0 +1,188 @@
+use std::process::{Child, Command, ExitStatus};
+use std::time::Duration;
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::{CloseHandle, ERROR_SUCCESS, HANDLE, HWND, LPARAM, WAIT_OBJECT_0, WAIT_TIMEOUT, WPARAM};
+use windows::Win32::System::JobObjects::{AssignProcessToJobObject, CreateJobObjectW, TerminateJobObject};
+use windows::Win32::System::Registry::{RegGetValueW, HKEY_CURRENT_USER, RRF_RT_REG_DWORD};
+use windows::Win32::System::Shutdown::LockWorkStation;
+use windows::Win32::System::Threading::WaitForSingleObject;
+use windows::Win32::UI::WindowsAndMessaging::{PostMessageW, SC_MONITORPOWER, WM_APP, WM_SYSCOMMAND};
+
+use crate::wide_string;
+
+/// Posted when a command started with [`spawn_command`] finishes; wparam is the
+/// command's tag and lparam a `Box<CommandOutcome>` the receiver takes ownership of.
+pub(crate) const WM_COMMAND_DONE: u32 = WM_APP + 5;
+/// Tag of the `run_command` action in a sequence.
+pub(crate) const SEQUENCE_COMMAND: usize = 0;
+/// Tag of the panic hotkey's command.
+pub(crate) const PANIC_COMMAND: usize = 1;
+/// Tag of `on_lid_open_command`.
+pub(crate) const LID_OPEN_COMMAND: usize = 2;
+
+// Not exported by the windows crate
+const HWND_BROADCAST: HWND = HWND(0xffff);
+// SC_MONITORPOWER argument that powers displays off
+const MONITOR_OFF: isize = 2;
+// Set by the "Remove Lock Computer" group policy
+const LOCK_POLICY_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Policies\System";
+const LOCK_POLICY_VALUE: &str = "DisableLockWorkstation";
+
+pub(crate) fn lock_workstation() -> Result<(), String> {
+    if unsafe { LockWorkStation() }.as_bool() {
+        Ok(())
+    } else {
+        Err(windows::core::Error::from_win32().message().to_string())
+    }
+}
+
+/// Whether the `DisableLockWorkstation` policy stops `LockWorkStation` for this user.
+pub(crate) fn lock_disabled_by_policy() -> bool {
+    let key = wide_string(LOCK_POLICY_KEY);
+    let value = wide_string(LOCK_POLICY_VALUE);
+    let mut data = 0u32;
+    let mut size = std::mem::size_of::<u32>() as u32;
+    let status = unsafe {
+        RegGetValueW(
+            HKEY_CURRENT_USER,
+            PCWSTR(key.as_ptr()),
+            PCWSTR(value.as_ptr()),
+            RRF_RT_REG_DWORD,
+            None,
+            Some(&mut data as *mut u32 as *mut _),
+            Some(&mut size),
+        )
+    };
+    status == ERROR_SUCCESS && data != 0
+}
+
+pub(crate) enum CommandOutcome {
+    Exited(ExitStatus),
+    /// Still running after its timeout and terminated.
+    TimedOut(Duration),
+    Failed(String),
+}
+
+impl CommandOutcome {
+    pub(crate) fn into_result(self) -> Result<(), String> {
+        match self {
+            CommandOutcome::Exited(status) if status.success() => Ok(()),
+            CommandOutcome::Exited(status) => Err(format!("command exited with {}", status)),
+            CommandOutcome::TimedOut(timeout) => {
+                Err(format!("command timed out after {}s and was terminated", timeout.as_secs()))
+            }
+            CommandOutcome::Failed(e) => Err(e),
+        }
+    }
+}
+
+/// Starts `command` through `cmd /C` without a console window and waits for it
+/// on a worker thread, so the message loop is never blocked. The outcome is
+/// posted to `notify` as `WM_COMMAND_DONE` with `tag`.
+///
+/// The process runs in a job object so a timeout terminates anything it
+/// started as well, not just `cmd`.
+pub(crate) fn spawn_command(command: &str, timeout: Duration, notify: HWND, tag: usize) -> Result<(), String> {
+    let job = unsafe { CreateJobObjectW(None, PCWSTR::null()) }.map_err(|e| e.message().to_string())?;
+    let mut child = match Command::new("cmd")
+        .args(["/C", command])
+        .no_window()
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            unsafe { CloseHandle(job) };
+            return Err(e.to_string());
+        }
+    };
+    unsafe {
+        AssignProcessToJobObject(job, process_handle(&child));
+    }
+
+    // HANDLE isn't Send, so the job crosses to the worker thread as its raw value
+    let job = job.0;
+    std::thread::spawn(move || {
+        let job = HANDLE(job);
+        let outcome = wait_with_timeout(&mut child, timeout, job);
+        unsafe {
+            CloseHandle(job);
+            let outcome = Box::into_raw(Box::new(outcome));
+            if !PostMessageW(notify, WM_COMMAND_DONE, WPARAM(tag), LPARAM(outcome as isize)).as_bool() {
+                // Nobody is left to receive it
+                drop(Box::from_raw(outcome));
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The std extensions that hide the console and expose the process handle only
+/// exist on Windows; elsewhere these fall back to no-ops so the crate still checks.
+trait NoWindow {
+    fn no_window(&mut self) -> &mut Self;
+}
+
+#[cfg(windows)]
+impl NoWindow for Command {
+    fn no_window(&mut self) -> &mut Self {
+        use std::os::windows::process::CommandExt;
+        use windows::Win32::System::Threading::CREATE_NO_WINDOW;
+        self.creation_flags(CREATE_NO_WINDOW.0)
+    }
+}
+
+#[cfg(not(windows))]
+impl NoWindow for Command {
+    fn no_window(&mut self) -> &mut Self {
+        self
+    }
+}
+
+#[cfg(windows)]
+fn process_handle(child: &Child) -> HANDLE {
+    use std::os::windows::io::AsRawHandle;
+    HANDLE(child.as_raw_handle() as isize)
+}
+
+#[cfg(not(windows))]
+fn process_handle(_child: &Child) -> HANDLE {
+    HANDLE::default()
+}
+
+fn wait_with_timeout(child: &mut Child, timeout: Duration, job: HANDLE) -> CommandOutcome {
+    let process = process_handle(child);
+    let millis = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX);
+    match unsafe { WaitForSingleObject(process, millis) } {
+        WAIT_OBJECT_0 => match child.wait() {
+            Ok(status) => CommandOutcome::Exited(status),
+            Err(e) => CommandOutcome::Failed(e.to_string()),
+        },
+        WAIT_TIMEOUT => {
+            unsafe {
+                TerminateJobObject(job, 1);
+            }
+            // Covers a process that escaped the job before it was assigned
+            let _ = child.kill();
+            let _ = child.wait();
+            CommandOutcome::TimedOut(timeout)
+        }
+        _ => CommandOutcome::Failed(windows::core::Error::from_win32().message().to_string()),
+    }
+}
+
+pub(crate) fn display_off() -> Result<(), String> {
+    // Posted rather than sent so a hung top-level window can't block the message loop
+    let posted = unsafe {
+        PostMessageW(
+            HWND_BROADCAST,
+            WM_SYSCOMMAND,
+            WPARAM(SC_MONITORPOWER as usize),
+            LPARAM(MONITOR_OFF),
+        )
+    };
+    if posted.as_bool() {
+        Ok(())
+    } else {
+        Err(windows::core::Error::from_win32().message().to_string())
+    }
+}
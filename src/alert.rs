@@ -0,0 +1,129 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::cell::RefCell;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::AlertDeliveryConfig;
+use crate::http::post_json;
+use crate::logger::Logger;
+
+/// Posts Teams/Slack compatible `{"text": ...}` messages to an incoming webhook.
+///
+/// Messages are queued to a background thread so a slow or unreachable webhook
+/// never blocks the message loop. The queue is bounded; when it is full new
+/// alerts are dropped and logged instead.
+pub(crate) struct AlertSender {
+    // None once stopped
+    sender: RefCell<Option<SyncSender<String>>>,
+    logger: Arc<Logger>,
+}
+
+impl AlertSender {
+    pub(crate) fn start(url: String, delivery: AlertDeliveryConfig, logger: Arc<Logger>) -> Self {
+        // A zero-sized channel only hands over to a waiting receiver, which would drop almost every alert
+        let (sender, receiver) = mpsc::sync_channel::<String>(delivery.queue_size.max(1));
+        let thread_logger = Arc::clone(&logger);
+
+        std::thread::spawn(move || {
+            let logger = thread_logger;
+            let max_attempts = delivery.max_attempts.max(1);
+
+            for text in receiver {
+                let body = serde_json::json!({ "text": text }).to_string();
+
+                for attempt in 1..=max_attempts {
+                    match post_json(&url, &body) {
+                        Ok(status) if (200..300).contains(&status) => {
+                            logger.log(&format!("Alert webhook delivered (HTTP {})", status));
+                            break;
+                        }
+                        Ok(status) => logger.log(&format!(
+                            "Alert webhook attempt {}/{} returned HTTP {}",
+                            attempt, max_attempts, status
+                        )),
+                        Err(e) => logger.log(&format!(
+                            "Alert webhook attempt {}/{} failed: {}",
+                            attempt, max_attempts, e
+                        )),
+                    }
+
+                    if attempt == max_attempts {
+                        logger.error("Giving up on alert webhook");
+                    } else {
+                        std::thread::sleep(retry_delay(&delivery, attempt));
+                    }
+                }
+            }
+        });
+
+        AlertSender {
+            sender: RefCell::new(Some(sender)),
+            logger,
+        }
+    }
+
+    pub(crate) fn send(&self, text: String) {
+        let result = match &*self.sender.borrow() {
+            Some(sender) => sender.try_send(text),
+            None => Err(TrySendError::Disconnected(text)),
+        };
+        match result {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => self.logger.log("Alert queue full, dropping alert"),
+            Err(TrySendError::Disconnected(_)) => self.logger.log("Alert sender stopped, dropping alert"),
+        }
+    }
+
+    /// Stops taking alerts. Queued ones are still delivered, but not waited for,
+    /// since an unreachable webhook could hold up the exit for minutes.
+    pub(crate) fn stop(&self) {
+        self.sender.borrow_mut().take();
+    }
+}
+
+// Exponential backoff capped at max_retry_delay_ms, plus up to jitter_ms of random delay
+fn retry_delay(delivery: &AlertDeliveryConfig, attempt: u32) -> Duration {
+    let backoff = delivery
+        .retry_delay_ms
+        .saturating_mul(1u64 << (attempt - 1).min(32))
+        .min(delivery.max_retry_delay_ms);
+    let jitter = match delivery.jitter_ms {
+        0 => 0,
+        max => random() % max.saturating_add(1),
+    };
+    Duration::from_millis(backoff.saturating_add(jitter))
+}
+
+// RandomState is seeded randomly per instance, which is plenty for jitter
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_backs_off_up_to_the_cap() {
+        let delivery = AlertDeliveryConfig {
+            jitter_ms: 0,
+            ..AlertDeliveryConfig::default()
+        };
+        assert_eq!(retry_delay(&delivery, 1), Duration::from_millis(2000));
+        assert_eq!(retry_delay(&delivery, 2), Duration::from_millis(4000));
+        assert_eq!(retry_delay(&delivery, 40), Duration::from_millis(60_000));
+    }
+
+    #[test]
+    fn retry_delay_saturates_on_huge_settings() {
+        let delivery = AlertDeliveryConfig {
+            retry_delay_ms: u64::MAX,
+            max_retry_delay_ms: u64::MAX,
+            jitter_ms: u64::MAX,
+            ..AlertDeliveryConfig::default()
+        };
+        assert_eq!(retry_delay(&delivery, 3), Duration::from_millis(u64::MAX));
+    }
+}
@@ -0,0 +1,93 @@
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::*;
+use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+use windows::Win32::UI::WindowsAndMessaging::*;
+
+use crate::{unique_class_name, wide_string};
+
+/// Posted to the owner when the hardware profile changed, e.g. on dock or undock.
+pub(crate) const WM_DOCK_CHANGED: u32 = WM_APP + 7;
+/// Sent to the owner when the session is ending, with the `WM_ENDSESSION`
+/// lparam; lidlock may be terminated right after it returns.
+pub(crate) const WM_SESSION_ENDING: u32 = WM_APP + 10;
+
+const BROADCAST_CLASS_NAME: &str = "lidlock_broadcast";
+
+/// Hidden top-level window that forwards broadcasts to the owner.
+///
+/// `WM_DEVICECHANGE` and `WM_ENDSESSION` only go to top-level windows, so the
+/// main message-only window never sees them.
+pub(crate) struct BroadcastWindow {
+    window: HWND,
+    class_name: Vec<u16>,
+}
+
+impl BroadcastWindow {
+    pub(crate) fn start(owner: HWND) -> windows::core::Result<Self> {
+        unsafe {
+            let instance = GetModuleHandleW(None)?;
+            let class_name = wide_string(&unique_class_name(BROADCAST_CLASS_NAME));
+            let wc = WNDCLASSEXW {
+                cbSize: std::mem::size_of::<WNDCLASSEXW>() as u32,
+                lpfnWndProc: Some(broadcast_proc),
+                hInstance: instance,
+                lpszClassName: PCWSTR(class_name.as_ptr()),
+                ..Default::default()
+            };
+            if RegisterClassExW(&wc) == 0 {
+                return Err(windows::core::Error::from_win32());
+            }
+
+            let window = CreateWindowExW(
+                WS_EX_TOOLWINDOW,
+                PCWSTR(class_name.as_ptr()),
+                PCWSTR(class_name.as_ptr()),
+                WS_POPUP,
+                0,
+                0,
+                0,
+                0,
+                None,
+                None,
+                instance,
+                None,
+            );
+            if window.0 == 0 {
+                let error = windows::core::Error::from_win32();
+                UnregisterClassW(PCWSTR(class_name.as_ptr()), instance);
+                return Err(error);
+            }
+            SetWindowLongPtrW(window, GWLP_USERDATA, owner.0);
+            Ok(BroadcastWindow { window, class_name })
+        }
+    }
+}
+
+impl Drop for BroadcastWindow {
+    fn drop(&mut self) {
+        unsafe {
+            DestroyWindow(self.window);
+            if let Ok(instance) = GetModuleHandleW(None) {
+                UnregisterClassW(PCWSTR(self.class_name.as_ptr()), instance);
+            }
+        }
+    }
+}
+
+unsafe extern "system" fn broadcast_proc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    let owner = HWND(GetWindowLongPtrW(hwnd, GWLP_USERDATA));
+    if msg == WM_DEVICECHANGE && wparam.0 == DBT_CONFIGCHANGED as usize {
+        if owner.0 != 0 {
+            PostMessageW(owner, WM_DOCK_CHANGED, WPARAM(0), LPARAM(0));
+        }
+        return LRESULT(1);
+    }
+    // Sent rather than posted, the process may be ended as soon as this returns
+    if msg == WM_ENDSESSION && wparam.0 != 0 {
+        if owner.0 != 0 {
+            SendMessageW(owner, WM_SESSION_ENDING, WPARAM(0), lparam);
+        }
+        return LRESULT(0);
+    }
+    DefWindowProcW(hwnd, msg, wparam, lparam)
+}
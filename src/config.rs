@@ -0,0 +1,781 @@
+use serde::{Deserialize, Serialize};
+
+use crate::event::{LockAction, Trigger};
+use crate::hotkey::Hotkey;
+use crate::logger::{LogFailurePolicy, LogFormat, LogLevel};
+use crate::power::PowerSource;
+use crate::schedule::{ScheduledLock, TimeWindow, WeeklySchedule};
+use crate::secret::Secret;
+use std::path::{Path, PathBuf};
+
+const CONFIG_FILE_NAME: &str = "lidlock.toml";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    pub log_format: LogFormat,
+    /// Make every decision but skip the actions, reporting what would have happened.
+    pub dry_run: bool,
+    /// Line format for text logs, e.g. `[{ts}] [{level}] [{trigger}] {msg}`.
+    pub log_template: Option<String>,
+    /// Buffer log writes instead of flushing every line.
+    pub log_batching: Option<LogBatchConfig>,
+    /// Where log lines go; empty logs to the file given on the command line.
+    pub log_sinks: Vec<LogSinkConfig>,
+    /// What to do once a log file can no longer be written.
+    pub on_log_failure: LogFailurePolicy,
+    /// Power notifications to register for.
+    pub triggers: TriggerConfig,
+    /// Recent events kept in memory for `--dump`.
+    pub event_history_size: usize,
+    pub action: LockAction,
+    /// Ordered actions to run instead of the single `action`.
+    pub actions: Option<Vec<LockAction>>,
+    /// Overrides `action`/`actions` when the lid closes.
+    pub lid_actions: Option<Vec<LockAction>>,
+    /// Overrides `action`/`actions` when the display turns off, e.g. for a screensaver.
+    pub monitor_actions: Option<Vec<LockAction>>,
+    /// Keep running later actions after one fails.
+    pub continue_on_failure: bool,
+    /// Command line for the `run_command` action.
+    pub command: Option<String>,
+    /// Overrides `action_timeout_seconds` for `command`.
+    pub command_timeout_seconds: Option<u64>,
+    /// Run when the lid opens after having been closed.
+    pub on_lid_open_command: Option<String>,
+    /// Spawned processes still running after this long are terminated.
+    pub action_timeout_seconds: u64,
+    /// Never lock while the local time is inside this window.
+    pub disable_between: Option<TimeWindow>,
+    /// Per-weekday `disable_between` windows and active triggers.
+    pub weekly_schedule: Option<WeeklySchedule>,
+    /// Run the actions at these times of day.
+    pub scheduled_locks: Vec<ScheduledLock>,
+    /// Rewritten after every processed event for external monitoring.
+    pub state_file: Option<PathBuf>,
+    /// Per-day decision counts reported by `--stats`.
+    pub stats_file: Option<PathBuf>,
+    /// One JSON line per decision with every input it was made on.
+    pub decision_log: Option<PathBuf>,
+    /// Delay before suspending with `action = "lock_then_sleep"`.
+    pub sleep_delay_seconds: u32,
+    /// Try suspending again when a driver or program vetoes it.
+    pub sleep_retry: Option<SleepRetryConfig>,
+    /// How long the display has to stay off before `action = "display_off_then_lock"` locks.
+    pub display_off_lock_seconds: u32,
+    /// Triggers arriving this soon after an acted-on trigger are folded into it.
+    pub coalesce_window_ms: u64,
+    /// Stop acting once the actions ran this often within a minute.
+    pub max_locks_per_minute: Option<u32>,
+    /// Send the failure alert when `max_locks_per_minute` starts suppressing locks.
+    pub alert_on_throttle: bool,
+    /// Only lock once the lid has stayed closed this long.
+    pub lid_closed_seconds: u64,
+    /// Only act on a lid close once there was no input for this long.
+    pub lid_requires_idle_seconds: Option<u64>,
+    /// Hold a lock back while there is still keyboard or mouse input.
+    pub defer_on_input: Option<InputDeferConfig>,
+    /// Ignore triggers until lidlock has been running this long.
+    pub min_uptime_seconds: u64,
+    /// Wait this long before registering the power notifications.
+    pub startup_delay_ms: u64,
+    /// Registering the power notifications is retried this often, with backoff.
+    pub registration_retries: u32,
+    pub mqtt: Option<MqttConfig>,
+    /// Teams/Slack incoming webhook notified when a lock attempt fails.
+    pub alert_webhook_url: Option<Secret>,
+    pub alert_delivery: AlertDeliveryConfig,
+    pub idle_lock: Option<IdleLockConfig>,
+    pub panic: Option<PanicConfig>,
+    /// Lock once more while stopping, unless the user is signing out.
+    pub lock_on_stop: bool,
+    /// Log events without acting on them until armed.
+    pub start_disarmed: bool,
+    /// Hotkey that arms lidlock while it is disarmed.
+    pub arm_hotkey: Option<Hotkey>,
+    /// Ignore lid and display triggers while a convertible is in tablet mode.
+    pub skip_in_tablet_mode: bool,
+    /// Act in remote sessions too, e.g. on VDI or thin clients.
+    pub lock_in_remote_session: bool,
+    /// Run the actions when the hardware profile changes to undocked.
+    pub lock_on_undock: bool,
+    pub wifi: Option<WifiConfig>,
+    /// Run the actions when the user turns absent, judged from several inputs.
+    pub presence: Option<PresenceConfig>,
+    /// Check that the session really locked after every lock.
+    pub confirm_lock: Option<LockConfirmConfig>,
+    /// Show a message on the sign-in screen while locked.
+    pub lock_screen_message: Option<LockScreenMessageConfig>,
+    /// Check the lid again after an unlock in case it was closed while locked.
+    pub recheck_on_unlock: bool,
+    /// Check the lid again after resuming in case its change was missed while asleep.
+    pub recheck_on_resume: bool,
+    /// Serve the loopback HTTP status endpoint on this port.
+    pub status_port: Option<u16>,
+    pub clamshell: Option<ClamshellPolicy>,
+    /// Show an icon in the notification area.
+    pub tray_icon: bool,
+    /// Count down the `lid_closed_seconds` wait in the tray icon's tooltip.
+    pub tray_countdown: bool,
+    /// Also show a notification when the countdown starts.
+    pub countdown_notification: bool,
+    /// Clicking the countdown notification cancels the lock.
+    pub countdown_click_cancels: bool,
+    /// Tell the user lidlock is running, once per user unless `_every_launch` is set.
+    pub show_startup_notification: bool,
+    pub startup_notification_every_launch: bool,
+    /// Ask before acting on a lid close while an external display is attached.
+    pub external_display_prompt: Option<PromptConfig>,
+}
+
+/// Actions for a lid close, chosen by power source and attached displays.
+///
+/// Unset entries fall back to the regular actions; an empty list does nothing.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ClamshellPolicy {
+    pub ac_with_external_display: Option<Vec<LockAction>>,
+    pub battery_with_external_display: Option<Vec<LockAction>>,
+    pub ac: Option<Vec<LockAction>>,
+    pub battery: Option<Vec<LockAction>>,
+}
+
+impl ClamshellPolicy {
+    /// Returns the name of the matching rule and its actions, if set.
+    /// An unknown power source is treated as battery so the lid still locks.
+    pub(crate) fn select(
+        &self,
+        source: PowerSource,
+        external_displays: usize,
+    ) -> (&'static str, Option<&[LockAction]>) {
+        match source {
+            PowerSource::Ac if external_displays > 0 => {
+                ("ac_with_external_display", self.ac_with_external_display.as_deref())
+            }
+            PowerSource::Ac => ("ac", self.ac.as_deref()),
+            PowerSource::Battery | PowerSource::Unknown if external_displays > 0 => {
+                ("battery_with_external_display", self.battery_with_external_display.as_deref())
+            }
+            PowerSource::Battery | PowerSource::Unknown => ("battery", self.battery.as_deref()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_topic")]
+    pub topic: String,
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            log_format: LogFormat::default(),
+            dry_run: false,
+            log_template: None,
+            log_batching: None,
+            log_sinks: Vec::new(),
+            on_log_failure: LogFailurePolicy::default(),
+            triggers: TriggerConfig::default(),
+            event_history_size: 100,
+            action: LockAction::default(),
+            actions: None,
+            lid_actions: None,
+            monitor_actions: None,
+            continue_on_failure: false,
+            command: None,
+            command_timeout_seconds: None,
+            on_lid_open_command: None,
+            action_timeout_seconds: 60,
+            disable_between: None,
+            weekly_schedule: None,
+            scheduled_locks: Vec::new(),
+            state_file: None,
+            stats_file: None,
+            decision_log: None,
+            sleep_delay_seconds: 60,
+            sleep_retry: None,
+            display_off_lock_seconds: 30,
+            max_locks_per_minute: None,
+            alert_on_throttle: false,
+            coalesce_window_ms: 2000,
+            min_uptime_seconds: 0,
+            startup_delay_ms: 0,
+            registration_retries: 3,
+            lid_closed_seconds: 0,
+            lid_requires_idle_seconds: None,
+            defer_on_input: None,
+            mqtt: None,
+            alert_webhook_url: None,
+            alert_delivery: AlertDeliveryConfig::default(),
+            idle_lock: None,
+            panic: None,
+            lock_on_stop: false,
+            start_disarmed: false,
+            arm_hotkey: None,
+            skip_in_tablet_mode: false,
+            lock_in_remote_session: false,
+            lock_on_undock: false,
+            wifi: None,
+            presence: None,
+            confirm_lock: None,
+            lock_screen_message: None,
+            recheck_on_unlock: false,
+            recheck_on_resume: true,
+            status_port: None,
+            clamshell: None,
+            tray_icon: false,
+            tray_countdown: false,
+            countdown_notification: false,
+            countdown_click_cancels: false,
+            show_startup_notification: true,
+            startup_notification_every_launch: false,
+            external_display_prompt: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PromptConfig {
+    #[serde(default = "default_prompt_timeout_seconds")]
+    pub timeout_seconds: u32,
+    /// What happens when nobody answers before the timeout.
+    #[serde(default)]
+    pub default: PromptDefault,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptDefault {
+    #[default]
+    Lock,
+    KeepWorking,
+}
+
+fn default_prompt_timeout_seconds() -> u32 {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LogBatchConfig {
+    pub flush_interval_ms: u64,
+    pub max_buffered_bytes: usize,
+}
+
+impl Default for LogBatchConfig {
+    fn default() -> Self {
+        LogBatchConfig {
+            flush_interval_ms: 1000,
+            max_buffered_bytes: 8192,
+        }
+    }
+}
+
+/// Hotkey that locks immediately, ignoring pause and every other check, and
+/// runs `command` alongside.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PanicConfig {
+    pub hotkey: Hotkey,
+    /// E.g. dismounting an encrypted volume; limited by `action_timeout_seconds`.
+    pub command: Option<String>,
+}
+
+/// How long to wait for the session to report locked, and how often to retry.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LockConfirmConfig {
+    pub timeout_seconds: u32,
+    /// Further lock attempts before alerting.
+    pub retries: u32,
+}
+
+impl Default for LockConfirmConfig {
+    fn default() -> Self {
+        LockConfirmConfig {
+            timeout_seconds: 5,
+            retries: 1,
+        }
+    }
+}
+
+/// How often to try suspending again after a failed attempt, and how long to wait in between.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SleepRetryConfig {
+    /// Further attempts after the first one fails.
+    pub retries: u32,
+    pub interval_seconds: u32,
+}
+
+impl Default for SleepRetryConfig {
+    fn default() -> Self {
+        SleepRetryConfig {
+            retries: 2,
+            interval_seconds: 5,
+        }
+    }
+}
+
+/// Signal that can tell the user is gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceInput {
+    /// The lid is closed.
+    Lid,
+    /// The display is off.
+    Display,
+    /// No input for `idle_seconds`.
+    Idle,
+}
+
+impl PresenceInput {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PresenceInput::Lid => "lid",
+            PresenceInput::Display => "display",
+            PresenceInput::Idle => "idle",
+        }
+    }
+}
+
+/// Inputs combined into user presence; any one of them reporting absence
+/// makes the user absent.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PresenceConfig {
+    pub inputs: Vec<PresenceInput>,
+    pub idle_seconds: u64,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        PresenceConfig {
+            inputs: vec![PresenceInput::Lid, PresenceInput::Display, PresenceInput::Idle],
+            idle_seconds: 300,
+        }
+    }
+}
+
+/// Wireless networks lidlock reacts to, by SSID.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WifiConfig {
+    /// Run the actions when the connection to one of these drops.
+    pub lock_on_disconnect: Vec<String>,
+    /// Never lock while connected to one of these.
+    pub no_lock: Vec<String>,
+}
+
+/// Where the lock message is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockScreenMessageMode {
+    /// A small window in the session, seen on unlock; needs no rights.
+    #[default]
+    Overlay,
+    /// The legal notice policy values on the sign-in screen. Needs administrator
+    /// rights and applies to every user of the machine.
+    LegalNotice,
+}
+
+/// Message telling the user why lidlock locked.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LockScreenMessageConfig {
+    pub mode: LockScreenMessageMode,
+    pub caption: String,
+    /// `{time}` and `{trigger}` are replaced when locking.
+    pub text: String,
+    /// How long the overlay stays up after unlocking, unless clicked away.
+    pub overlay_seconds: u32,
+}
+
+impl Default for LockScreenMessageConfig {
+    fn default() -> Self {
+        LockScreenMessageConfig {
+            mode: LockScreenMessageMode::default(),
+            caption: "lidlock".to_string(),
+            text: "Locked automatically at {time} ({trigger})".to_string(),
+            overlay_seconds: 10,
+        }
+    }
+}
+
+/// Power triggers lidlock listens for. Disabled ones are never registered.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TriggerConfig {
+    pub lid_switch: bool,
+    pub monitor_power: bool,
+    /// Act when the battery runs low; off unless configured.
+    pub low_battery: Option<LowBatteryConfig>,
+    /// Act when the accelerometer sees the machine being moved; needs the
+    /// `motion` feature.
+    pub motion: Option<MotionConfig>,
+}
+
+impl Default for TriggerConfig {
+    fn default() -> Self {
+        TriggerConfig {
+            lid_switch: true,
+            monitor_power: true,
+            low_battery: None,
+            motion: None,
+        }
+    }
+}
+
+/// Sensitivity of the motion trigger.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MotionConfig {
+    /// Change in acceleration between two readings that counts as motion.
+    pub threshold_g: f64,
+    pub poll_ms: u64,
+    /// Motion is reported at most once this often.
+    pub debounce_ms: u64,
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        MotionConfig {
+            threshold_g: 0.3,
+            poll_ms: 200,
+            debounce_ms: 5000,
+        }
+    }
+}
+
+/// Battery levels for the low battery trigger. It fires once when the charge
+/// falls to `percent` and only re-arms after it is back at `rearm_percent`,
+/// so a level hovering at the threshold doesn't act over and over.
+/// `rearm_percent` must be above `percent`, otherwise one reading both fires
+/// and re-arms.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "LowBatteryLevels")]
+pub struct LowBatteryConfig {
+    pub percent: u32,
+    pub rearm_percent: u32,
+}
+
+impl Default for LowBatteryConfig {
+    fn default() -> Self {
+        LowBatteryConfig {
+            percent: 15,
+            rearm_percent: 20,
+        }
+    }
+}
+
+// LowBatteryConfig as written, before checking the two levels against each other
+#[derive(Deserialize)]
+#[serde(default)]
+struct LowBatteryLevels {
+    percent: u32,
+    rearm_percent: u32,
+}
+
+impl Default for LowBatteryLevels {
+    fn default() -> Self {
+        let LowBatteryConfig { percent, rearm_percent } = LowBatteryConfig::default();
+        LowBatteryLevels { percent, rearm_percent }
+    }
+}
+
+impl TryFrom<LowBatteryLevels> for LowBatteryConfig {
+    type Error = String;
+
+    fn try_from(levels: LowBatteryLevels) -> Result<Self, Self::Error> {
+        if levels.rearm_percent <= levels.percent {
+            return Err(format!(
+                "rearm_percent ({}) must be above percent ({})",
+                levels.rearm_percent, levels.percent
+            ));
+        }
+        Ok(LowBatteryConfig {
+            percent: levels.percent,
+            rearm_percent: levels.rearm_percent,
+        })
+    }
+}
+
+/// One log destination and the lowest level it records.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogSinkConfig {
+    #[serde(flatten)]
+    pub kind: LogSinkKind,
+    /// Defaults to `info`, or `debug` when started with `--debug`.
+    pub level: Option<LogLevel>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LogSinkKind {
+    /// Without a `path`, the log file given on the command line.
+    File { path: Option<PathBuf> },
+    /// Standard error of the console lidlock was started from.
+    Console,
+    /// The Windows Application event log.
+    EventLog,
+    /// `\\.\pipe\<name>`, served by a log viewer.
+    Pipe { name: String },
+    /// The last `lines` lines, kept in memory only and read with `--log-tail`.
+    Memory {
+        #[serde(default = "default_memory_log_lines")]
+        lines: usize,
+    },
+}
+
+impl LogSinkKind {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            LogSinkKind::File { .. } => "file",
+            LogSinkKind::Console => "console",
+            LogSinkKind::EventLog => "event_log",
+            LogSinkKind::Pipe { .. } => "pipe",
+            LogSinkKind::Memory { .. } => "memory",
+        }
+    }
+}
+
+fn default_memory_log_lines() -> usize {
+    1000
+}
+
+/// Retry and queueing limits for the alert webhook sender.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AlertDeliveryConfig {
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubled on every further retry.
+    pub retry_delay_ms: u64,
+    pub max_retry_delay_ms: u64,
+    /// Random extra delay of up to this much, so a fleet doesn't retry in lockstep.
+    pub jitter_ms: u64,
+    /// Alerts waiting to be sent; further alerts are dropped while the queue is full.
+    /// At least one is always queued.
+    pub queue_size: usize,
+}
+
+impl Default for AlertDeliveryConfig {
+    fn default() -> Self {
+        AlertDeliveryConfig {
+            max_attempts: 3,
+            retry_delay_ms: 2000,
+            max_retry_delay_ms: 60_000,
+            jitter_ms: 1000,
+            queue_size: 16,
+        }
+    }
+}
+
+/// Lock after `seconds` without input, blanking the screen for `blank_seconds` first.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IdleLockConfig {
+    pub seconds: u32,
+    #[serde(default = "default_blank_seconds")]
+    pub blank_seconds: u32,
+}
+
+/// Settings applied on top of the config through `--overlay` and dropped
+/// again after `minutes`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigOverlay {
+    pub minutes: u64,
+    /// Triggers skipped while the overlay lasts.
+    pub ignore_triggers: Vec<Trigger>,
+    /// Replaces `idle_lock.seconds`; needs `[idle_lock]`.
+    pub idle_seconds: Option<u32>,
+}
+
+impl Default for ConfigOverlay {
+    fn default() -> Self {
+        ConfigOverlay {
+            minutes: 60,
+            ignore_triggers: Vec::new(),
+            idle_seconds: None,
+        }
+    }
+}
+
+impl ConfigOverlay {
+    /// The settings it changes, e.g. `ignore_triggers=[lid_switch] idle_seconds=1800`.
+    pub(crate) fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if !self.ignore_triggers.is_empty() {
+            let triggers = self.ignore_triggers.iter().map(Trigger::as_str).collect::<Vec<_>>().join(",");
+            parts.push(format!("ignore_triggers=[{}]", triggers));
+        }
+        if let Some(seconds) = self.idle_seconds {
+            parts.push(format!("idle_seconds={}", seconds));
+        }
+        if parts.is_empty() {
+            "no settings".to_string()
+        } else {
+            parts.join(" ")
+        }
+    }
+}
+
+/// How long a lock waits for input to stop; it goes ahead after `max_defer_ms` regardless.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct InputDeferConfig {
+    /// Input within this long before the lock defers it.
+    pub recent_input_ms: u64,
+    pub max_defer_ms: u64,
+}
+
+impl Default for InputDeferConfig {
+    fn default() -> Self {
+        InputDeferConfig {
+            recent_input_ms: 1000,
+            max_defer_ms: 5000,
+        }
+    }
+}
+
+fn default_blank_seconds() -> u32 {
+    5
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_topic() -> String {
+    "lidlock/state".to_string()
+}
+
+impl Config {
+    pub fn actions(&self) -> Vec<LockAction> {
+        self.actions.clone().unwrap_or_else(|| vec![self.action])
+    }
+
+    /// `lid_actions` or `monitor_actions` for their trigger, the regular actions otherwise.
+    pub fn actions_for(&self, trigger: Trigger) -> Vec<LockAction> {
+        let specific = match trigger {
+            Trigger::LidSwitch => self.lid_actions.as_ref(),
+            Trigger::MonitorPower => self.monitor_actions.as_ref(),
+            _ => None,
+        };
+        specific.cloned().unwrap_or_else(|| self.actions())
+    }
+
+    /// Whether `action` appears in any of the configured action lists.
+    pub(crate) fn uses_action(&self, action: LockAction) -> bool {
+        let clamshell = self.clamshell.iter().flat_map(|policy| {
+            [
+                &policy.ac_with_external_display,
+                &policy.battery_with_external_display,
+                &policy.ac,
+                &policy.battery,
+            ]
+        });
+        self.actions().contains(&action)
+            || [&self.lid_actions, &self.monitor_actions]
+                .into_iter()
+                .chain(clamshell)
+                .flatten()
+                .any(|actions| actions.contains(&action))
+    }
+
+    /// The `[presence]` inputs that actually feed presence. The lid and display
+    /// are left to their own triggers while those are enabled, so the lid hold,
+    /// `lid_actions` and the clamshell policy still apply to them.
+    pub(crate) fn presence_inputs(&self) -> Vec<PresenceInput> {
+        let Some(presence) = &self.presence else {
+            return Vec::new();
+        };
+        presence
+            .inputs
+            .iter()
+            .copied()
+            .filter(|input| match input {
+                PresenceInput::Lid => !self.triggers.lid_switch,
+                PresenceInput::Display => !self.triggers.monitor_power,
+                PresenceInput::Idle => true,
+            })
+            .collect()
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+        toml::from_str(&text)
+            .map_err(|e| format!("Failed to parse config {}: {}", path.display(), e))
+    }
+
+    /// `lidlock.toml` next to the executable, used when no `--config` is given.
+    pub fn default_path() -> Option<PathBuf> {
+        std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(|dir| dir.join(CONFIG_FILE_NAME)))
+    }
+
+    /// An explicit `--config` must exist; the default next to the executable is optional.
+    pub fn resolve_path(explicit: Option<PathBuf>) -> Option<PathBuf> {
+        explicit.or_else(|| Self::default_path().filter(|p| p.exists()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamshell_picks_external_display_rules_on_either_power_source() {
+        let policy = ClamshellPolicy {
+            ac_with_external_display: Some(Vec::new()),
+            battery_with_external_display: Some(Vec::new()),
+            battery: Some(vec![LockAction::Lock]),
+            ..ClamshellPolicy::default()
+        };
+        assert_eq!(policy.select(PowerSource::Ac, 1), ("ac_with_external_display", Some(&[][..])));
+        assert_eq!(policy.select(PowerSource::Ac, 0), ("ac", None));
+        assert_eq!(policy.select(PowerSource::Battery, 1), ("battery_with_external_display", Some(&[][..])));
+        assert_eq!(policy.select(PowerSource::Unknown, 2), ("battery_with_external_display", Some(&[][..])));
+        assert_eq!(policy.select(PowerSource::Battery, 0), ("battery", Some(&[LockAction::Lock][..])));
+    }
+
+    #[test]
+    fn low_battery_rearm_must_be_above_threshold() {
+        let config: Config = toml::from_str("[triggers.low_battery]\npercent = 10").unwrap();
+        let low_battery = config.triggers.low_battery.unwrap();
+        assert_eq!((low_battery.percent, low_battery.rearm_percent), (10, 20));
+
+        for levels in ["percent = 15\nrearm_percent = 10", "percent = 15\nrearm_percent = 15", "percent = 25"] {
+            let text = format!("[triggers.low_battery]\n{}", levels);
+            assert!(toml::from_str::<Config>(&text).is_err(), "{}", levels);
+        }
+    }
+
+    #[test]
+    fn presence_leaves_lid_and_display_to_their_triggers() {
+        let mut config = Config {
+            presence: Some(PresenceConfig::default()),
+            ..Config::default()
+        };
+        assert_eq!(config.presence_inputs(), vec![PresenceInput::Idle]);
+
+        config.triggers.lid_switch = false;
+        config.triggers.monitor_power = false;
+        assert_eq!(
+            config.presence_inputs(),
+            vec![PresenceInput::Lid, PresenceInput::Display, PresenceInput::Idle]
+        );
+    }
+
+    #[test]
+    fn presence_inputs_empty_without_presence() {
+        assert!(Config::default().presence_inputs().is_empty());
+    }
+}
@@ -0,0 +1,271 @@
+use std::cell::RefCell;
+use std::path::Path;
+use std::time::Duration;
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::*;
+use windows::Win32::System::DataExchange::COPYDATASTRUCT;
+use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+use windows::Win32::System::Threading::{OpenProcess, WaitForSingleObject, PROCESS_SYNCHRONIZE};
+use windows::Win32::UI::WindowsAndMessaging::*;
+
+use crate::config::ConfigOverlay;
+use crate::{wide_string, APP_NAME};
+
+/// `WM_COPYDATA` tag asking a running instance to write its recent events to the
+/// UTF-16 path carried in the data. The instance replies with the number of
+/// events written, or -1 if writing failed.
+pub(crate) const DUMP_EVENTS_REQUEST: usize = 1;
+/// `WM_COPYDATA` tag asking a running instance for the last lines of its memory
+/// log sink. The data is the line count as a `u32` and wparam the window to send
+/// them to, as a [`LOG_TAIL_REPLY`]. The instance replies with the number of
+/// lines sent, or -1 if it has no memory sink.
+pub(crate) const LOG_TAIL_REQUEST: usize = 2;
+/// `WM_COPYDATA` tag of the lines sent back for a [`LOG_TAIL_REQUEST`], as UTF-8.
+pub(crate) const LOG_TAIL_REPLY: usize = 3;
+
+/// `WM_COPYDATA` tag carrying a [`ConfigOverlay`] as UTF-8 JSON, or `null` to
+/// drop the current one. The instance replies 1 once it is applied, or -1 if
+/// it couldn't be read.
+pub(crate) const OVERLAY_REQUEST: usize = 4;
+
+/// Posted to arm (wparam 1) or disarm (wparam 0) a running instance.
+pub(crate) const WM_ARM: u32 = WM_APP + 13;
+
+const LOG_TAIL_CLASS_NAME: &str = "lidlock_log_tail";
+
+thread_local! {
+    // Filled in by `log_tail_proc` while `tail_running_instance` waits for its reply
+    static LOG_TAIL: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Finds the message-only window of a lidlock instance running in this session.
+pub(crate) fn find_running_instance() -> Option<HWND> {
+    let name = wide_string(APP_NAME);
+    let hwnd = unsafe { FindWindowExW(HWND_MESSAGE, None, PCWSTR::null(), PCWSTR(name.as_ptr())) };
+    (hwnd.0 != 0).then_some(hwnd)
+}
+
+/// Asks a running instance to close its window and waits up to `timeout` for it to exit.
+/// Returns `Ok(false)` if no instance is running.
+pub fn stop_running_instance(timeout: Duration) -> windows::core::Result<bool> {
+    let Some(hwnd) = find_running_instance() else {
+        return Ok(false);
+    };
+
+    unsafe {
+        let mut pid = 0u32;
+        GetWindowThreadProcessId(hwnd, Some(&mut pid));
+        // Opened before posting so the process can't exit and have its id reused in between
+        let process = OpenProcess(PROCESS_SYNCHRONIZE, false, pid)?;
+
+        let result = PostMessageW(hwnd, WM_CLOSE, WPARAM(0), LPARAM(0))
+            .ok()
+            .map(|_| WaitForSingleObject(process, timeout.as_millis() as u32));
+        CloseHandle(process);
+
+        match result? {
+            WAIT_OBJECT_0 => Ok(true),
+            _ => Err(windows::core::Error::new(
+                WAIT_TIMEOUT.to_hresult(),
+                "Timed out waiting for lidlock to exit".into(),
+            )),
+        }
+    }
+}
+
+/// Arms or disarms a running instance. Returns `Ok(false)` if no instance is running.
+pub fn arm_running_instance(armed: bool) -> windows::core::Result<bool> {
+    let Some(hwnd) = find_running_instance() else {
+        return Ok(false);
+    };
+    unsafe { PostMessageW(hwnd, WM_ARM, WPARAM(armed as usize), LPARAM(0)) }.ok()?;
+    Ok(true)
+}
+
+/// Asks a running instance to write its recent events to `path`, which should be
+/// absolute since the instance has its own working directory. Returns the number
+/// of events written, or `Ok(None)` if no instance is running.
+pub fn dump_running_instance(path: &Path, timeout: Duration) -> windows::core::Result<Option<usize>> {
+    let Some(hwnd) = find_running_instance() else {
+        return Ok(None);
+    };
+
+    let path = wide_string(&path.to_string_lossy());
+    let data = COPYDATASTRUCT {
+        dwData: DUMP_EVENTS_REQUEST,
+        cbData: (path.len() * std::mem::size_of::<u16>()) as u32,
+        lpData: path.as_ptr() as *mut _,
+    };
+    let mut reply = 0usize;
+    let sent = unsafe {
+        SendMessageTimeoutW(
+            hwnd,
+            WM_COPYDATA,
+            WPARAM(0),
+            LPARAM(&data as *const COPYDATASTRUCT as isize),
+            SMTO_ABORTIFHUNG,
+            timeout.as_millis() as u32,
+            Some(&mut reply),
+        )
+    };
+    if sent.0 == 0 {
+        return Err(windows::core::Error::from_win32());
+    }
+    match reply as isize {
+        count if count >= 0 => Ok(Some(count as usize)),
+        _ => Err(windows::core::Error::new(
+            E_FAIL,
+            "lidlock could not write the events, see its log".into(),
+        )),
+    }
+}
+
+/// Applies a temporary overlay to a running instance, or drops its current one
+/// with `None`. Returns `Ok(false)` if no instance is running.
+pub fn overlay_running_instance(overlay: Option<&ConfigOverlay>, timeout: Duration) -> windows::core::Result<bool> {
+    let Some(hwnd) = find_running_instance() else {
+        return Ok(false);
+    };
+
+    let json = serde_json::to_string(&overlay).map_err(|e| windows::core::Error::new(E_FAIL, e.to_string().into()))?;
+    let data = COPYDATASTRUCT {
+        dwData: OVERLAY_REQUEST,
+        cbData: json.len() as u32,
+        lpData: json.as_ptr() as *mut _,
+    };
+    let mut reply = 0usize;
+    let sent = unsafe {
+        SendMessageTimeoutW(
+            hwnd,
+            WM_COPYDATA,
+            WPARAM(0),
+            LPARAM(&data as *const COPYDATASTRUCT as isize),
+            SMTO_ABORTIFHUNG,
+            timeout.as_millis() as u32,
+            Some(&mut reply),
+        )
+    };
+    if sent.0 == 0 {
+        return Err(windows::core::Error::from_win32());
+    }
+    match reply as isize {
+        1 => Ok(true),
+        _ => Err(windows::core::Error::new(
+            E_FAIL,
+            "lidlock rejected the overlay, see its log".into(),
+        )),
+    }
+}
+
+/// Fetches the last `lines` lines of a running instance's memory log sink, without
+/// anything touching the disk. Returns `Ok(None)` if no instance is running.
+pub fn tail_running_instance(lines: usize, timeout: Duration) -> windows::core::Result<Option<String>> {
+    let Some(hwnd) = find_running_instance() else {
+        return Ok(None);
+    };
+
+    let reply_window = ReplyWindow::create()?;
+    let count = lines.min(u32::MAX as usize) as u32;
+    let data = COPYDATASTRUCT {
+        dwData: LOG_TAIL_REQUEST,
+        cbData: std::mem::size_of::<u32>() as u32,
+        lpData: &count as *const u32 as *mut _,
+    };
+    LOG_TAIL.with(|tail| tail.borrow_mut().take());
+    let mut reply = 0usize;
+    // The instance sends the lines back before it replies, and incoming sent
+    // messages are handled while this call waits
+    let sent = unsafe {
+        SendMessageTimeoutW(
+            hwnd,
+            WM_COPYDATA,
+            WPARAM(reply_window.0 .0 as usize),
+            LPARAM(&data as *const COPYDATASTRUCT as isize),
+            SMTO_ABORTIFHUNG,
+            timeout.as_millis() as u32,
+            Some(&mut reply),
+        )
+    };
+    if sent.0 == 0 {
+        return Err(windows::core::Error::from_win32());
+    }
+    if (reply as isize) < 0 {
+        return Err(windows::core::Error::new(
+            E_FAIL,
+            "lidlock has no memory log sink configured".into(),
+        ));
+    }
+    Ok(Some(LOG_TAIL.with(|tail| tail.borrow_mut().take()).unwrap_or_default()))
+}
+
+/// Message-only window receiving the [`LOG_TAIL_REPLY`].
+struct ReplyWindow(HWND);
+
+impl ReplyWindow {
+    fn create() -> windows::core::Result<Self> {
+        unsafe {
+            let instance = GetModuleHandleW(None)?;
+            let class_name = wide_string(LOG_TAIL_CLASS_NAME);
+            let wc = WNDCLASSEXW {
+                cbSize: std::mem::size_of::<WNDCLASSEXW>() as u32,
+                lpfnWndProc: Some(log_tail_proc),
+                hInstance: instance,
+                lpszClassName: PCWSTR(class_name.as_ptr()),
+                ..Default::default()
+            };
+            if RegisterClassExW(&wc) == 0 {
+                return Err(windows::core::Error::from_win32());
+            }
+
+            let window = CreateWindowExW(
+                WINDOW_EX_STYLE::default(),
+                PCWSTR(class_name.as_ptr()),
+                PCWSTR(class_name.as_ptr()),
+                WINDOW_STYLE::default(),
+                0,
+                0,
+                0,
+                0,
+                HWND_MESSAGE,
+                None,
+                instance,
+                None,
+            );
+            if window.0 == 0 {
+                let error = windows::core::Error::from_win32();
+                UnregisterClassW(PCWSTR(class_name.as_ptr()), instance);
+                return Err(error);
+            }
+            Ok(ReplyWindow(window))
+        }
+    }
+}
+
+impl Drop for ReplyWindow {
+    fn drop(&mut self) {
+        unsafe {
+            DestroyWindow(self.0);
+            if let Ok(instance) = GetModuleHandleW(None) {
+                let class_name = wide_string(LOG_TAIL_CLASS_NAME);
+                UnregisterClassW(PCWSTR(class_name.as_ptr()), instance);
+            }
+        }
+    }
+}
+
+unsafe extern "system" fn log_tail_proc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    if msg == WM_COPYDATA {
+        let data = &*(lparam.0 as *const COPYDATASTRUCT);
+        if data.dwData == LOG_TAIL_REPLY {
+            let text = if data.lpData.is_null() {
+                String::new()
+            } else {
+                let bytes = std::slice::from_raw_parts(data.lpData as *const u8, data.cbData as usize);
+                String::from_utf8_lossy(bytes).into_owned()
+            };
+            LOG_TAIL.with(|tail| *tail.borrow_mut() = Some(text));
+            return LRESULT(1);
+        }
+    }
+    DefWindowProcW(hwnd, msg, wparam, lparam)
+}
@@ -0,0 +1,82 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::event::LastDecision;
+use crate::logger::Logger;
+use crate::writer::BackgroundWriter;
+
+/// Inputs a decision was made on, keyed by name.
+pub(crate) type DecisionInputs = serde_json::Map<String, serde_json::Value>;
+
+/// Appends one JSON object per decision to the `decision_log` file, with the
+/// outcome and every input the pipeline looked at on the way.
+pub(crate) struct DecisionLog {
+    writer: BackgroundWriter<String>,
+}
+
+impl DecisionLog {
+    pub(crate) fn start(path: PathBuf, logger: Arc<Logger>) -> Self {
+        DecisionLog {
+            writer: BackgroundWriter::start(path, logger, "Failed to write decision log", append),
+        }
+    }
+
+    /// Writes out what is queued and ends the thread.
+    pub(crate) fn stop(&self) {
+        self.writer.stop();
+    }
+
+    pub(crate) fn record(&self, last: &LastDecision, inputs: DecisionInputs) {
+        let mut record = last.to_json();
+        record["inputs"] = inputs.into();
+        self.writer.send(record.to_string());
+    }
+}
+
+fn append(path: &Path, line: String) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)
+}
+
+// The logger links against Win32, so these only run on Windows
+#[cfg(all(test, windows))]
+mod tests {
+    use super::*;
+    use crate::event::{Decision, LockAction, LockEvent, SkipReason, Trigger};
+
+    #[test]
+    fn one_line_per_decision_with_its_inputs() {
+        let path = std::env::temp_dir().join(format!("lidlock-decision-log-test-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = DecisionLog::start(path.clone(), Arc::new(Logger::new(None)));
+        let last = LastDecision {
+            timestamp: chrono::Local::now(),
+            event: LockEvent {
+                trigger: Trigger::LidSwitch,
+                state: 0,
+                actions: vec![LockAction::Lock],
+                decision: Decision::Skipped(SkipReason::Schedule),
+            },
+        };
+        for power_source in ["ac", "battery"] {
+            let mut inputs = DecisionInputs::new();
+            inputs.insert("power_source".to_string(), power_source.into());
+            log.record(&last, inputs);
+        }
+        // Stopping writes out everything queued
+        log.stop();
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["trigger"], "lid_switch");
+        assert_eq!(lines[0]["decision"], "skipped");
+        assert_eq!(lines[0]["inputs"]["power_source"], "ac");
+        assert_eq!(lines[1]["inputs"]["power_source"], "battery");
+    }
+}
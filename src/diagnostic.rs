@@ -0,0 +1,139 @@
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::*;
+use windows::Win32::Graphics::Gdi::*;
+use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+use windows::Win32::UI::Controls::{EM_REPLACESEL, EM_SETLIMITTEXT, EM_SETSEL};
+use windows::Win32::UI::WindowsAndMessaging::*;
+
+use crate::logger::WM_LOG_LINE;
+use crate::prompt::create_child;
+use crate::{unique_class_name, wide_string, APP_NAME};
+
+const DIAGNOSTIC_CLASS_NAME: &str = "lidlock_diagnostics";
+const ID_STATUS: usize = 1;
+const ID_LOG: usize = 2;
+const STATUS_HEIGHT: i32 = 24;
+// Older lines are dropped once the log grows past this many characters
+const MAX_LOG_CHARS: i32 = 200_000;
+
+/// Visible window for `--foreground` with the current decision on top and the
+/// live log below. Closing it ends the message loop.
+pub(crate) struct DiagnosticWindow {
+    window: HWND,
+    status: HWND,
+    class_name: Vec<u16>,
+}
+
+impl DiagnosticWindow {
+    pub(crate) fn show() -> windows::core::Result<Self> {
+        unsafe {
+            let instance = GetModuleHandleW(None)?;
+            let class_name = wide_string(&unique_class_name(DIAGNOSTIC_CLASS_NAME));
+            let wc = WNDCLASSEXW {
+                cbSize: std::mem::size_of::<WNDCLASSEXW>() as u32,
+                lpfnWndProc: Some(diagnostic_proc),
+                hInstance: instance,
+                hCursor: LoadCursorW(None, IDC_ARROW)?,
+                hbrBackground: HBRUSH((COLOR_BTNFACE.0 + 1) as isize),
+                lpszClassName: PCWSTR(class_name.as_ptr()),
+                ..Default::default()
+            };
+            if RegisterClassExW(&wc) == 0 {
+                return Err(windows::core::Error::from_win32());
+            }
+
+            let title = wide_string(&format!("{} diagnostics", APP_NAME));
+            let window = CreateWindowExW(
+                WINDOW_EX_STYLE(0),
+                PCWSTR(class_name.as_ptr()),
+                PCWSTR(title.as_ptr()),
+                WS_OVERLAPPEDWINDOW | WS_VISIBLE,
+                CW_USEDEFAULT,
+                CW_USEDEFAULT,
+                900,
+                500,
+                None,
+                None,
+                instance,
+                None,
+            );
+            if window.0 == 0 {
+                let error = windows::core::Error::from_win32();
+                UnregisterClassW(PCWSTR(class_name.as_ptr()), instance);
+                return Err(error);
+            }
+
+            let status = create_child(window, "STATIC", "No events yet", WINDOW_STYLE(0), 0, 0, 0, 0, ID_STATUS)?;
+            let log_style = WS_VSCROLL | WS_BORDER | WINDOW_STYLE((ES_MULTILINE | ES_READONLY | ES_AUTOVSCROLL) as u32);
+            let log = create_child(window, "EDIT", "", log_style, 0, 0, 0, 0, ID_LOG)?;
+            SendMessageW(log, EM_SETLIMITTEXT, WPARAM(0), LPARAM(0));
+            let font = GetStockObject(DEFAULT_GUI_FONT);
+            for child in [status, log] {
+                SendMessageW(child, WM_SETFONT, WPARAM(font.0 as usize), LPARAM(1));
+            }
+            layout(window);
+
+            Ok(DiagnosticWindow { window, status, class_name })
+        }
+    }
+
+    pub(crate) fn hwnd(&self) -> HWND {
+        self.window
+    }
+
+    pub(crate) fn set_status(&self, text: &str) {
+        let text = wide_string(text);
+        unsafe {
+            SetWindowTextW(self.status, PCWSTR(text.as_ptr()));
+        }
+    }
+}
+
+impl Drop for DiagnosticWindow {
+    fn drop(&mut self) {
+        unsafe {
+            if IsWindow(self.window).as_bool() {
+                DestroyWindow(self.window);
+            }
+            if let Ok(instance) = GetModuleHandleW(None) {
+                UnregisterClassW(PCWSTR(self.class_name.as_ptr()), instance);
+            }
+        }
+    }
+}
+
+unsafe fn layout(window: HWND) {
+    let mut rect = RECT::default();
+    GetClientRect(window, &mut rect);
+    let (width, height) = (rect.right - rect.left, rect.bottom - rect.top);
+    MoveWindow(GetDlgItem(window, ID_STATUS as i32), 6, 4, width - 12, STATUS_HEIGHT - 4, true);
+    MoveWindow(GetDlgItem(window, ID_LOG as i32), 0, STATUS_HEIGHT, width, height - STATUS_HEIGHT, true);
+}
+
+unsafe fn append_line(window: HWND, line: &str) {
+    let log = GetDlgItem(window, ID_LOG as i32);
+    let mut len = GetWindowTextLengthW(log);
+    if len > MAX_LOG_CHARS {
+        SendMessageW(log, EM_SETSEL, WPARAM(0), LPARAM((len / 2) as isize));
+        let empty = wide_string("");
+        SendMessageW(log, EM_REPLACESEL, WPARAM(0), LPARAM(empty.as_ptr() as isize));
+        len = GetWindowTextLengthW(log);
+    }
+    // The edit control only breaks lines on CRLF
+    let text = wide_string(&format!("{}\r\n", line.trim_end()));
+    SendMessageW(log, EM_SETSEL, WPARAM(len as usize), LPARAM(len as isize));
+    SendMessageW(log, EM_REPLACESEL, WPARAM(0), LPARAM(text.as_ptr() as isize));
+}
+
+unsafe extern "system" fn diagnostic_proc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    match msg {
+        WM_LOG_LINE => {
+            let line = Box::from_raw(lparam.0 as *mut String);
+            append_line(hwnd, &line);
+        }
+        WM_SIZE => layout(hwnd),
+        WM_DESTROY => PostQuitMessage(0),
+        _ => return DefWindowProcW(hwnd, msg, wparam, lparam),
+    }
+    LRESULT(0)
+}
@@ -0,0 +1,15 @@
+use windows::Win32::System::WindowsProgramming::{
+    GetCurrentHwProfileW, DOCKINFO_DOCKED, DOCKINFO_UNDOCKED, HW_PROFILE_INFOW,
+};
+
+/// Whether the machine is docked according to the current hardware profile,
+/// or None if the profile doesn't say.
+pub(crate) fn is_docked() -> Option<bool> {
+    let mut profile = HW_PROFILE_INFOW::default();
+    if !unsafe { GetCurrentHwProfileW(&mut profile) }.as_bool() {
+        return None;
+    }
+    let docked = profile.dwDockInfo & DOCKINFO_DOCKED != 0;
+    let undocked = profile.dwDockInfo & DOCKINFO_UNDOCKED != 0;
+    (docked != undocked).then_some(docked)
+}
@@ -0,0 +1,256 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+
+/// Event that started a decision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    LidSwitch,
+    MonitorPower,
+    Idle,
+    /// Requested through the status server.
+    Manual,
+    /// The panic hotkey; bypasses every check.
+    PanicHotkey,
+    /// The hardware profile changed to undocked.
+    Undock,
+    /// The battery charge fell to the `low_battery` threshold.
+    LowBattery,
+    /// One of the `scheduled_locks` times was reached.
+    Scheduled,
+    /// A wireless network listed in `lock_on_disconnect` dropped.
+    WifiDisconnect,
+    /// The accelerometer saw the machine being moved.
+    Motion,
+    /// The `presence` inputs turned from present to absent.
+    Presence,
+}
+
+impl Trigger {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Trigger::LidSwitch => "lid_switch",
+            Trigger::MonitorPower => "monitor_power",
+            Trigger::Idle => "idle",
+            Trigger::Manual => "manual",
+            Trigger::PanicHotkey => "panic_hotkey",
+            Trigger::Undock => "undock",
+            Trigger::LowBattery => "low_battery",
+            Trigger::Scheduled => "scheduled",
+            Trigger::WifiDisconnect => "wifi_disconnect",
+            Trigger::Motion => "motion",
+            Trigger::Presence => "presence",
+        }
+    }
+}
+
+impl std::fmt::Display for Trigger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Action lidlock takes once it decides to act on a trigger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockAction {
+    #[default]
+    Lock,
+    /// Lock immediately, then suspend after `sleep_delay_seconds`.
+    LockThenSleep,
+    /// Run the configured `command` and wait for it to exit before the next action.
+    RunCommand,
+    DisplayOff,
+    /// Turn the display off, then lock if it stays off for `display_off_lock_seconds`.
+    DisplayOffThenLock,
+}
+
+impl LockAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LockAction::Lock => "lock",
+            LockAction::LockThenSleep => "lock_then_sleep",
+            LockAction::RunCommand => "run_command",
+            LockAction::DisplayOff => "display_off",
+            LockAction::DisplayOffThenLock => "display_off_then_lock",
+        }
+    }
+
+    pub fn locks(&self) -> bool {
+        matches!(self, LockAction::Lock | LockAction::LockThenSleep)
+    }
+}
+
+impl std::fmt::Display for LockAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why the decision pipeline chose not to act.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    RemoteSession,
+    /// The session has no console or client attached.
+    DisconnectedSession,
+    NonZeroState,
+    Schedule,
+    Coalesced,
+    /// Arrived before `min_uptime_seconds` had passed.
+    Startup,
+    Paused,
+    /// The clamshell policy selected no actions.
+    Clamshell,
+    /// Answered "keep working" on the external display prompt, or clicked the
+    /// countdown notification.
+    Declined,
+    /// An earlier action sequence is still running and too many are already
+    /// queued behind it. A lock in the actions still happened right away.
+    Busy,
+    /// The lid reported closed again without opening in between.
+    LidUnchanged,
+    /// Every check passed, but `dry_run` is set.
+    DryRun,
+    /// The actions configured for the trigger are empty.
+    NoActions,
+    /// The actions already ran `max_locks_per_minute` times in the last minute.
+    Throttled,
+    /// A convertible is in tablet mode and `skip_in_tablet_mode` is set.
+    TabletMode,
+    /// The lid closed sooner than `lid_requires_idle_seconds` after the last input.
+    RecentInput,
+    /// Connected to a Wi-Fi network listed in `no_lock`.
+    TrustedNetwork,
+    /// The `DisableLockWorkstation` policy forbids locking.
+    LockDisabled,
+    /// Started with `start_disarmed` and not armed yet.
+    Disarmed,
+    /// Listed in `ignore_triggers` of the temporary overlay.
+    Overlay,
+}
+
+impl SkipReason {
+    /// Stable token used in `reason=` log fields.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::RemoteSession => "remote_session",
+            SkipReason::DisconnectedSession => "disconnected_session",
+            SkipReason::NonZeroState => "non_zero_state",
+            SkipReason::Schedule => "schedule",
+            SkipReason::Coalesced => "coalesced",
+            SkipReason::Startup => "startup",
+            SkipReason::Paused => "paused",
+            SkipReason::Clamshell => "clamshell",
+            SkipReason::Declined => "declined",
+            SkipReason::Busy => "busy",
+            SkipReason::LidUnchanged => "lid_unchanged",
+            SkipReason::DryRun => "dry_run",
+            SkipReason::NoActions => "no_actions",
+            SkipReason::Throttled => "throttled",
+            SkipReason::TabletMode => "tablet_mode",
+            SkipReason::RecentInput => "recent_input",
+            SkipReason::TrustedNetwork => "trusted_network",
+            SkipReason::LockDisabled => "lock_disabled",
+            SkipReason::Disarmed => "disarmed",
+            SkipReason::Overlay => "overlay",
+        }
+    }
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Result of running a trigger through the decision pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Every configured action ran successfully.
+    Locked,
+    Skipped(SkipReason),
+    Failed,
+    /// Waiting for an answer on the external display prompt or for a command
+    /// to finish; a second event reports the outcome.
+    Deferred,
+}
+
+impl Decision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decision::Locked => "locked",
+            Decision::Skipped(_) => "skipped",
+            Decision::Failed => "failed",
+            Decision::Deferred => "deferred",
+        }
+    }
+
+    pub fn reason(&self) -> Option<SkipReason> {
+        match self {
+            Decision::Skipped(reason) => Some(*reason),
+            _ => None,
+        }
+    }
+}
+
+/// Passed to every callback registered with `LidLockWindow::on_event`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockEvent {
+    pub trigger: Trigger,
+    pub state: u32,
+    pub actions: Vec<LockAction>,
+    pub decision: Decision,
+}
+
+impl LockEvent {
+    /// One-line description of what a real run would have done.
+    pub(crate) fn dry_run_summary(&self) -> String {
+        let actions = self.actions.iter().map(LockAction::as_str).collect::<Vec<_>>().join(", ");
+        match self.decision {
+            Decision::Skipped(SkipReason::DryRun) => {
+                format!("Would run {} (trigger: {})", actions, self.trigger)
+            }
+            Decision::Skipped(reason) => format!("Would skip (trigger: {}, reason: {})", self.trigger, reason),
+            _ => self.summary(),
+        }
+    }
+
+    /// One-line description of the decision, e.g. `skipped (trigger: idle, reason: paused)`.
+    pub(crate) fn summary(&self) -> String {
+        match self.decision {
+            Decision::Skipped(reason) => format!("skipped (trigger: {}, reason: {})", self.trigger, reason),
+            decision => {
+                let actions = self.actions.iter().map(LockAction::as_str).collect::<Vec<_>>().join(", ");
+                format!("{} (trigger: {}, actions: {})", decision.as_str(), self.trigger, actions)
+            }
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "trigger": self.trigger.as_str(),
+            "state": self.state,
+            "actions": self.actions.iter().map(LockAction::as_str).collect::<Vec<_>>(),
+            "decision": self.decision.as_str(),
+            "reason": self.decision.reason().map(|reason| reason.as_str()),
+        })
+    }
+}
+
+/// The most recently processed event, for status reporting.
+#[derive(Debug, Clone)]
+pub struct LastDecision {
+    pub timestamp: chrono::DateTime<chrono::Local>,
+    pub event: LockEvent,
+}
+
+impl LastDecision {
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let mut record = self.event.to_json();
+        record["timestamp"] = self.timestamp.to_rfc3339().into();
+        record
+    }
+}
+
+/// Written by the message loop thread, read by the status threads.
+pub(crate) type SharedLastDecision = Arc<RwLock<Option<LastDecision>>>;
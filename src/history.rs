@@ -0,0 +1,75 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::event::LastDecision;
+
+/// The most recently processed events, kept in memory so they can be dumped
+/// after the fact without verbose logging.
+///
+/// Recorded on the message loop thread and read from the status server thread.
+#[derive(Clone)]
+pub(crate) struct EventHistory {
+    events: Arc<Mutex<VecDeque<LastDecision>>>,
+    capacity: usize,
+}
+
+impl EventHistory {
+    pub(crate) fn new(capacity: usize) -> Self {
+        EventHistory {
+            events: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub(crate) fn record(&self, last: &LastDecision) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Ok(mut events) = self.events.lock() {
+            if events.len() == self.capacity {
+                events.pop_front();
+            }
+            events.push_back(last.clone());
+        }
+    }
+
+    /// Oldest first.
+    pub(crate) fn snapshot(&self) -> Vec<LastDecision> {
+        self.events
+            .lock()
+            .map(|events| events.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Writes one JSON object per event, oldest first, and returns how many were written.
+    ///
+    /// Any process can ask for a dump, so an existing file is never overwritten.
+    pub(crate) fn write_to(&self, path: &Path) -> std::io::Result<usize> {
+        let events = self.snapshot();
+        let file = std::fs::OpenOptions::new().write(true).create_new(true).open(path)?;
+        let mut file = std::io::BufWriter::new(file);
+        for event in &events {
+            writeln!(file, "{}", event.to_json())?;
+        }
+        file.flush()?;
+        Ok(events.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_to_refuses_existing_file() {
+        let path = std::env::temp_dir().join(format!("lidlock-history-test-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let history = EventHistory::new(4);
+        assert_eq!(history.write_to(&path).unwrap(), 0);
+        let error = history.write_to(&path).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+        let _ = std::fs::remove_file(&path);
+    }
+}
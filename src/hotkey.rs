@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+use windows::Win32::UI::Input::KeyboardAndMouse::*;
+
+/// Global hotkey written like `Ctrl+Alt+L`. At least one of `Ctrl`, `Alt`,
+/// `Shift` or `Win` is required; the key is a letter, digit, `F1`-`F24`,
+/// `Space` or `Pause`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Hotkey {
+    pub(crate) modifiers: HOT_KEY_MODIFIERS,
+    pub(crate) key: u32,
+    text: String,
+}
+
+impl std::fmt::Display for Hotkey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl From<Hotkey> for String {
+    fn from(hotkey: Hotkey) -> Self {
+        hotkey.text
+    }
+}
+
+impl TryFrom<String> for Hotkey {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let mut parts: Vec<&str> = value.split('+').map(str::trim).collect();
+        let key = parts.pop().filter(|key| !key.is_empty());
+        let key = key.ok_or_else(|| format!("hotkey '{}' has no key", value))?;
+
+        let mut modifiers = HOT_KEY_MODIFIERS(0);
+        for modifier in parts {
+            modifiers |= match modifier.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => MOD_CONTROL,
+                "alt" => MOD_ALT,
+                "shift" => MOD_SHIFT,
+                "win" => MOD_WIN,
+                _ => return Err(format!("unknown modifier '{}' in hotkey '{}'", modifier, value)),
+            };
+        }
+        if modifiers.0 == 0 {
+            return Err(format!("hotkey '{}' needs at least one modifier", value));
+        }
+
+        Ok(Hotkey {
+            modifiers,
+            key: virtual_key(key).ok_or_else(|| format!("unknown key '{}' in hotkey '{}'", key, value))?,
+            text: value,
+        })
+    }
+}
+
+fn virtual_key(key: &str) -> Option<u32> {
+    let upper = key.to_ascii_uppercase();
+    match upper.as_str() {
+        "SPACE" => return Some(VK_SPACE.0 as u32),
+        "PAUSE" => return Some(VK_PAUSE.0 as u32),
+        _ => {}
+    }
+    // Letters and digits share their virtual key codes with ASCII
+    if let [c] = upper.as_bytes() {
+        return c.is_ascii_alphanumeric().then_some(*c as u32);
+    }
+    let number: u16 = upper.strip_prefix('F')?.parse().ok()?;
+    (1..=24).contains(&number).then(|| (VK_F1.0 + number - 1) as u32)
+}
@@ -0,0 +1,113 @@
+use std::ffi::c_void;
+use windows::core::{w, PCWSTR};
+use windows::Win32::Networking::WinHttp::*;
+
+use crate::wide_string;
+
+const TIMEOUT_MS: i32 = 5000;
+
+struct Handle(*mut c_void);
+
+impl Handle {
+    fn open(handle: *mut c_void) -> windows::core::Result<Self> {
+        if handle.is_null() {
+            Err(windows::core::Error::from_win32())
+        } else {
+            Ok(Handle(handle))
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe {
+            WinHttpCloseHandle(self.0);
+        }
+    }
+}
+
+/// POSTs a JSON body with WinHTTP and returns the HTTP status code.
+pub(crate) fn post_json(url: &str, body: &str) -> windows::core::Result<u32> {
+    let url_wide: Vec<u16> = url.encode_utf16().collect();
+
+    unsafe {
+        // Lengths of u32::MAX make WinHttpCrackUrl point the components into url_wide
+        let mut components = URL_COMPONENTS {
+            dwStructSize: std::mem::size_of::<URL_COMPONENTS>() as u32,
+            dwHostNameLength: u32::MAX,
+            dwUrlPathLength: u32::MAX,
+            dwExtraInfoLength: u32::MAX,
+            ..Default::default()
+        };
+        WinHttpCrackUrl(&url_wide, 0, &mut components).ok()?;
+
+        let host = std::slice::from_raw_parts(
+            components.lpszHostName.0,
+            components.dwHostNameLength as usize,
+        );
+        // The query string directly follows the path in the original URL
+        let path = std::slice::from_raw_parts(
+            components.lpszUrlPath.0,
+            (components.dwUrlPathLength + components.dwExtraInfoLength) as usize,
+        );
+        let host = wide_string(&String::from_utf16_lossy(host));
+        let path = wide_string(&String::from_utf16_lossy(path));
+
+        let session = Handle::open(WinHttpOpen(
+            w!("lidlock"),
+            WINHTTP_ACCESS_TYPE_DEFAULT_PROXY,
+            PCWSTR::null(),
+            PCWSTR::null(),
+            0,
+        ))?;
+        WinHttpSetTimeouts(session.0, TIMEOUT_MS, TIMEOUT_MS, TIMEOUT_MS, TIMEOUT_MS).ok()?;
+
+        let connection = Handle::open(WinHttpConnect(
+            session.0,
+            PCWSTR(host.as_ptr()),
+            components.nPort,
+            0,
+        ))?;
+
+        let flags = if components.nScheme == WINHTTP_INTERNET_SCHEME_HTTPS {
+            WINHTTP_FLAG_SECURE
+        } else {
+            WINHTTP_OPEN_REQUEST_FLAGS(0)
+        };
+        let request = Handle::open(WinHttpOpenRequest(
+            connection.0,
+            w!("POST"),
+            PCWSTR(path.as_ptr()),
+            PCWSTR::null(),
+            PCWSTR::null(),
+            std::ptr::null(),
+            flags,
+        ))?;
+
+        let headers: Vec<u16> = "Content-Type: application/json\r\n".encode_utf16().collect();
+        WinHttpSendRequest(
+            request.0,
+            Some(&headers),
+            Some(body.as_ptr() as *const c_void),
+            body.len() as u32,
+            body.len() as u32,
+            0,
+        )
+        .ok()?;
+        WinHttpReceiveResponse(request.0, std::ptr::null_mut()).ok()?;
+
+        let mut status = 0u32;
+        let mut length = std::mem::size_of::<u32>() as u32;
+        WinHttpQueryHeaders(
+            request.0,
+            WINHTTP_QUERY_STATUS_CODE | WINHTTP_QUERY_FLAG_NUMBER,
+            PCWSTR::null(),
+            Some(&mut status as *mut u32 as *mut c_void),
+            &mut length,
+            std::ptr::null_mut(),
+        )
+        .ok()?;
+
+        Ok(status)
+    }
+}
@@ -0,0 +1,238 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::*;
+use windows::Win32::Graphics::Gdi::{GetStockObject, BLACK_BRUSH, HBRUSH};
+use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+use windows::Win32::System::SystemInformation::GetTickCount;
+use windows::Win32::UI::Input::KeyboardAndMouse::{GetLastInputInfo, LASTINPUTINFO};
+use windows::Win32::UI::WindowsAndMessaging::*;
+
+use crate::config::IdleLockConfig;
+use crate::logger::Logger;
+use crate::{unique_class_name, wide_string};
+
+pub(crate) const IDLE_TIMER_ID: usize = 1;
+pub(crate) const BLANK_TIMER_ID: usize = 2;
+pub(crate) const WM_IDLE_INPUT: u32 = WM_APP + 1;
+
+const OVERLAY_CLASS_NAME: &str = "lidlock_overlay";
+const IDLE_POLL_MS: u32 = 1000;
+
+// Low-level hooks carry no user data, so the window to notify lives here.
+// It is cleared by the first hooked event so only one notification is posted.
+static HOOK_TARGET: AtomicIsize = AtomicIsize::new(0);
+
+/// Locks after a period without input, first blanking the screen with a
+/// topmost black overlay that any keyboard or mouse input dismisses.
+pub(crate) struct IdleMonitor {
+    config: IdleLockConfig,
+    // `config.seconds`, unless a temporary overlay replaces it
+    seconds: Cell<u32>,
+    owner: HWND,
+    // Empty without blanking, since no overlay class is registered then
+    class_name: Vec<u16>,
+    overlay: Cell<HWND>,
+    hooks: Cell<[HHOOK; 2]>,
+    fired: Cell<bool>,
+}
+
+impl IdleMonitor {
+    pub(crate) fn start(config: IdleLockConfig, owner: HWND) -> windows::core::Result<Self> {
+        let mut class_name = Vec::new();
+        unsafe {
+            if config.blank_seconds > 0 {
+                class_name = wide_string(&unique_class_name(OVERLAY_CLASS_NAME));
+                let wc = WNDCLASSEXW {
+                    cbSize: std::mem::size_of::<WNDCLASSEXW>() as u32,
+                    lpfnWndProc: Some(overlay_proc),
+                    hInstance: GetModuleHandleW(None)?,
+                    hbrBackground: HBRUSH(GetStockObject(BLACK_BRUSH).0),
+                    lpszClassName: PCWSTR(class_name.as_ptr()),
+                    ..Default::default()
+                };
+                if RegisterClassExW(&wc) == 0 {
+                    return Err(windows::core::Error::from_win32());
+                }
+            }
+
+            if SetTimer(owner, IDLE_TIMER_ID, IDLE_POLL_MS, None) == 0 {
+                let error = windows::core::Error::from_win32();
+                unregister_class(&class_name);
+                return Err(error);
+            }
+        }
+
+        Ok(IdleMonitor {
+            seconds: Cell::new(config.seconds),
+            config,
+            owner,
+            class_name,
+            overlay: Cell::new(HWND(0)),
+            hooks: Cell::new([HHOOK(0); 2]),
+            fired: Cell::new(false),
+        })
+    }
+
+    /// Called on every idle poll; returns true when the lock should happen now.
+    pub(crate) fn check(&self, logger: &Logger) -> bool {
+        let idle_seconds = idle_duration_ms() / 1000;
+        if idle_seconds < self.seconds.get() {
+            self.fired.set(false);
+            return false;
+        }
+        if self.fired.replace(true) {
+            return false;
+        }
+
+        if self.config.blank_seconds == 0 {
+            logger.log(&format!("Idle for {}s", idle_seconds));
+            return true;
+        }
+
+        logger.log(&format!(
+            "Idle for {}s, blanking screen for {}s before locking",
+            idle_seconds, self.config.blank_seconds
+        ));
+        if let Err(e) = self.show_overlay() {
+            // Without the overlay there is nothing to dismiss, so lock right away
+            logger.log(&format!("Failed to show idle overlay: {}", e));
+            self.hide_overlay();
+            return true;
+        }
+        false
+    }
+
+    /// Replaces the configured idle time; `None` goes back to it.
+    pub(crate) fn set_seconds(&self, seconds: Option<u32>) {
+        self.seconds.set(seconds.unwrap_or(self.config.seconds));
+    }
+
+    /// Called when input arrives while the overlay is shown; returns whether
+    /// it was shown.
+    pub(crate) fn cancel(&self, logger: &Logger) -> bool {
+        if self.overlay.get().0 == 0 {
+            return false;
+        }
+        logger.log("Input detected, idle lock cancelled");
+        self.hide_overlay();
+        true
+    }
+
+    /// Whether the screen is blanked, waiting for input before locking.
+    pub(crate) fn blanking(&self) -> bool {
+        self.overlay.get().0 != 0
+    }
+
+    /// Called when the blanking period ends without input; returns true if the lock should proceed.
+    pub(crate) fn finish_blank(&self) -> bool {
+        let blanking = self.overlay.get().0 != 0;
+        self.hide_overlay();
+        blanking
+    }
+
+    fn show_overlay(&self) -> windows::core::Result<()> {
+        unsafe {
+            let instance = GetModuleHandleW(None)?;
+            let overlay = CreateWindowExW(
+                WS_EX_TOPMOST | WS_EX_TOOLWINDOW,
+                PCWSTR(self.class_name.as_ptr()),
+                None,
+                WS_POPUP | WS_VISIBLE,
+                GetSystemMetrics(SM_XVIRTUALSCREEN),
+                GetSystemMetrics(SM_YVIRTUALSCREEN),
+                GetSystemMetrics(SM_CXVIRTUALSCREEN),
+                GetSystemMetrics(SM_CYVIRTUALSCREEN),
+                None,
+                None,
+                instance,
+                None,
+            );
+            if overlay.0 == 0 {
+                return Err(windows::core::Error::from_win32());
+            }
+            self.overlay.set(overlay);
+
+            HOOK_TARGET.store(self.owner.0, Ordering::SeqCst);
+            let keyboard = SetWindowsHookExW(WH_KEYBOARD_LL, Some(input_hook), instance, 0)?;
+            self.hooks.set([keyboard, HHOOK(0)]);
+            let mouse = SetWindowsHookExW(WH_MOUSE_LL, Some(input_hook), instance, 0)?;
+            self.hooks.set([keyboard, mouse]);
+
+            if SetTimer(self.owner, BLANK_TIMER_ID, self.config.blank_seconds.saturating_mul(1000), None) == 0 {
+                return Err(windows::core::Error::from_win32());
+            }
+            Ok(())
+        }
+    }
+
+    fn hide_overlay(&self) {
+        unsafe {
+            HOOK_TARGET.store(0, Ordering::SeqCst);
+            KillTimer(self.owner, BLANK_TIMER_ID);
+            for hook in self.hooks.replace([HHOOK(0); 2]) {
+                if hook.0 != 0 {
+                    UnhookWindowsHookEx(hook);
+                }
+            }
+            let overlay = self.overlay.replace(HWND(0));
+            if overlay.0 != 0 {
+                DestroyWindow(overlay);
+            }
+        }
+    }
+}
+
+impl Drop for IdleMonitor {
+    fn drop(&mut self) {
+        self.hide_overlay();
+        unsafe {
+            KillTimer(self.owner, IDLE_TIMER_ID);
+        }
+        unregister_class(&self.class_name);
+    }
+}
+
+fn unregister_class(class_name: &[u16]) {
+    if class_name.is_empty() {
+        return;
+    }
+    unsafe {
+        if let Ok(instance) = GetModuleHandleW(None) {
+            UnregisterClassW(PCWSTR(class_name.as_ptr()), instance);
+        }
+    }
+}
+
+pub(crate) fn idle_duration_ms() -> u32 {
+    let mut info = LASTINPUTINFO {
+        cbSize: std::mem::size_of::<LASTINPUTINFO>() as u32,
+        dwTime: 0,
+    };
+    unsafe {
+        if !GetLastInputInfo(&mut info).as_bool() {
+            return 0;
+        }
+        // Both are tick counts, so wrapping_sub stays correct across the 49.7 day rollover
+        GetTickCount().wrapping_sub(info.dwTime)
+    }
+}
+
+unsafe extern "system" fn overlay_proc(
+    hwnd: HWND,
+    msg: u32,
+    wparam: WPARAM,
+    lparam: LPARAM,
+) -> LRESULT {
+    DefWindowProcW(hwnd, msg, wparam, lparam)
+}
+
+unsafe extern "system" fn input_hook(code: i32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    if code >= 0 {
+        let target = HOOK_TARGET.swap(0, Ordering::SeqCst);
+        if target != 0 {
+            PostMessageW(HWND(target), WM_IDLE_INPUT, WPARAM(0), LPARAM(0));
+        }
+    }
+    CallNextHookEx(HHOOK(0), code, wparam, lparam)
+}
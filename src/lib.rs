@@ -0,0 +1,142 @@
+//! Lock a Windows laptop when the lid is closed.
+//!
+//! The `lidlock` binary is a thin wrapper around [`LidLockWindow`]. Embedders can
+//! create the window themselves and register callbacks with [`LidLockWindow::on_event`]
+//! to observe every decision made on a power event.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use windows::core::PWSTR;
+use windows::Win32::System::Diagnostics::Debug::{
+    FormatMessageW, FORMAT_MESSAGE_FROM_SYSTEM, FORMAT_MESSAGE_IGNORE_INSERTS,
+};
+use windows::Win32::System::RemoteDesktop::ProcessIdToSessionId;
+use windows::Win32::System::SystemInformation::{ComputerNameDnsHostname, GetComputerNameExW};
+use windows::Win32::System::Threading::GetCurrentProcessId;
+
+mod action;
+mod alert;
+mod broadcast;
+mod config;
+mod control;
+mod decision_log;
+mod diagnostic;
+mod dock;
+mod event;
+mod history;
+mod hotkey;
+mod http;
+mod idle;
+mod lockscreen;
+mod logger;
+mod machine;
+#[cfg(feature = "motion")]
+mod motion;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod power;
+mod prompt;
+mod schedule;
+mod secret;
+mod selftest;
+mod session;
+mod singleton;
+mod state;
+mod startup;
+mod stats;
+#[cfg(feature = "http-status")]
+mod status_server;
+mod tray;
+mod wifi;
+mod window;
+mod writer;
+
+pub use config::{
+    AlertDeliveryConfig, ClamshellPolicy, Config, ConfigOverlay, IdleLockConfig, InputDeferConfig, LockConfirmConfig,
+    LockScreenMessageConfig, LockScreenMessageMode, LogBatchConfig, LogSinkConfig, LogSinkKind, LowBatteryConfig,
+    MotionConfig, MqttConfig, PanicConfig, PresenceConfig, PresenceInput, PromptConfig, PromptDefault, SleepRetryConfig,
+    TriggerConfig, WifiConfig,
+};
+pub use control::{
+    arm_running_instance, dump_running_instance, overlay_running_instance, stop_running_instance, tail_running_instance,
+};
+pub use event::{Decision, LastDecision, LockAction, LockEvent, SkipReason, Trigger};
+pub use hotkey::Hotkey;
+pub use logger::{LogFailurePolicy, LogFormat, LogLevel, Logger, LOG_FAILURE_EXIT_CODE};
+pub use schedule::{DaySchedule, ScheduledLock, TimeOfDay, TimeWindow, WeeklySchedule};
+pub use secret::{protect_secret, Secret};
+pub use selftest::{self_test, SelfTestStep};
+pub use singleton::SingletonHandle;
+pub use stats::{recent_stats, DayStats, DecisionCounts};
+pub use window::LidLockWindow;
+
+pub(crate) const APP_NAME: &str = "lidlock";
+
+// Makes class names unique when one process creates several windows
+static CLASS_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// `<base>_<pid>_<n>`, a window class name no other window registers, so two
+/// instances in a process never unregister each other's class.
+pub(crate) fn unique_class_name(base: &str) -> String {
+    format!(
+        "{}_{}_{}",
+        base,
+        std::process::id(),
+        CLASS_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+pub(crate) fn hostname() -> String {
+    let mut buffer = [0u16; 256];
+    let mut size = buffer.len() as u32;
+    let ok = unsafe {
+        GetComputerNameExW(ComputerNameDnsHostname, PWSTR(buffer.as_mut_ptr()), &mut size)
+    };
+    if ok.as_bool() {
+        String::from_utf16_lossy(&buffer[..size as usize])
+    } else {
+        "unknown".to_string()
+    }
+}
+
+pub(crate) fn session_id() -> String {
+    let mut session = 0u32;
+    if unsafe { ProcessIdToSessionId(GetCurrentProcessId(), &mut session) }.as_bool() {
+        session.to_string()
+    } else {
+        "unknown".to_string()
+    }
+}
+
+pub(crate) fn wide_string(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+/// Formats a `GetLastError` code with its system description, e.g.
+/// `error 5 (Access is denied.)`.
+pub(crate) fn describe_win32_error(code: u32) -> String {
+    let mut buffer = [0u16; 512];
+    let len = unsafe {
+        FormatMessageW(
+            FORMAT_MESSAGE_FROM_SYSTEM | FORMAT_MESSAGE_IGNORE_INSERTS,
+            None,
+            code,
+            0,
+            PWSTR(buffer.as_mut_ptr()),
+            buffer.len() as u32,
+            None,
+        )
+    };
+    let description = String::from_utf16_lossy(&buffer[..len as usize]);
+    match description.trim_end() {
+        "" => format!("error {}", code),
+        description => format!("error {} ({})", code, description),
+    }
+}
+
+/// Like [`describe_win32_error`] for an error a windows API call already returned.
+pub(crate) fn describe_error(error: &windows::core::Error) -> String {
+    let hresult = error.code().0 as u32;
+    // HRESULT_FROM_WIN32 wraps the code in the low word
+    let code = if hresult & 0xFFFF_0000 == 0x8007_0000 { hresult & 0xFFFF } else { hresult };
+    describe_win32_error(code)
+}
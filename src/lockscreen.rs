@@ -0,0 +1,367 @@
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::path::PathBuf;
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::{
+    ERROR_ACCESS_DENIED, ERROR_FILE_NOT_FOUND, ERROR_SUCCESS, HWND, LPARAM, LRESULT, WIN32_ERROR, WPARAM,
+};
+use windows::Win32::Graphics::Gdi::{GetStockObject, COLOR_INFOBK, DEFAULT_GUI_FONT, HBRUSH};
+use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+use windows::Win32::System::Registry::*;
+use windows::Win32::UI::WindowsAndMessaging::*;
+
+use crate::config::{LockScreenMessageConfig, LockScreenMessageMode};
+use crate::event::Trigger;
+use crate::logger::Logger;
+use crate::prompt::create_child;
+use crate::{describe_win32_error, unique_class_name, wide_string, APP_NAME};
+
+// Windows shows these policy values on the sign-in screen; writing them needs
+// administrator rights
+const POLICY_KEY: &str = r"SOFTWARE\Microsoft\Windows\CurrentVersion\Policies\System";
+const CAPTION_VALUE: &str = "legalnoticecaption";
+const TEXT_VALUE: &str = "legalnoticetext";
+// Holds the user's own values while lidlock's are set, so a crash doesn't lose them
+const BACKUP_FILE_NAME: &str = "legal_notice_backup.json";
+
+const OVERLAY_CLASS_NAME: &str = "lidlock_lock_message";
+const OVERLAY_WIDTH: i32 = 420;
+const OVERLAY_HEIGHT: i32 = 60;
+const OVERLAY_TOP: i32 = 40;
+const OVERLAY_TIMER_ID: usize = 1;
+
+/// Caption and text that were set before lidlock replaced them; `None` if the
+/// value didn't exist.
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    caption: Option<String>,
+    text: Option<String>,
+}
+
+/// Tells the user why lidlock locked, either with a small window they see on
+/// unlock or, with `mode = "legal_notice"`, through the legal notice policy
+/// values on the sign-in screen, putting the previous values back afterwards.
+pub(crate) struct LockScreenMessage {
+    config: LockScreenMessageConfig,
+    saved: RefCell<Option<Saved>>,
+    // Set once writing the legal notice was refused, so it isn't retried on every lock
+    denied: Cell<bool>,
+    overlay: Cell<HWND>,
+    // Empty when the overlay class couldn't be registered
+    class_name: Vec<u16>,
+}
+
+impl LockScreenMessage {
+    pub(crate) fn new(config: LockScreenMessageConfig, logger: &Logger) -> Self {
+        let mut class_name = Vec::new();
+        match config.mode {
+            LockScreenMessageMode::Overlay => match register_overlay_class() {
+                Ok(name) => class_name = name,
+                Err(e) => logger.warn(&format!("Failed to register the lock message window class: {}", e.message())),
+            },
+            LockScreenMessageMode::LegalNotice => restore_stale(logger),
+        }
+        LockScreenMessage {
+            config,
+            saved: RefCell::new(None),
+            denied: Cell::new(false),
+            overlay: Cell::new(HWND(0)),
+            class_name,
+        }
+    }
+
+    /// Shows the message for a lock caused by `trigger`. Failing to show it is
+    /// logged and never stops the lock.
+    pub(crate) fn set(&self, trigger: Trigger, logger: &Logger) {
+        let text = self
+            .config
+            .text
+            .replace("{time}", &chrono::Local::now().format("%H:%M").to_string())
+            .replace("{trigger}", trigger.as_str());
+        match self.config.mode {
+            LockScreenMessageMode::Overlay => self.show_overlay(&text, logger),
+            LockScreenMessageMode::LegalNotice => self.set_legal_notice(&text, logger),
+        }
+    }
+
+    fn show_overlay(&self, text: &str, logger: &Logger) {
+        self.close_overlay();
+        match unsafe { create_overlay(&self.class_name, &self.config.caption, text) } {
+            Ok(window) => {
+                self.overlay.set(window);
+                logger.log(&format!("Showing lock message: {}: {}", self.config.caption, text));
+            }
+            Err(e) => logger.warn(&format!("Failed to show lock message: {}", e.message())),
+        }
+    }
+
+    fn set_legal_notice(&self, text: &str, logger: &Logger) {
+        if self.denied.get() {
+            logger.debug("Not setting the lock screen message, writing it was refused before");
+            return;
+        }
+        let result = PolicyKey::open(KEY_QUERY_VALUE | KEY_SET_VALUE).and_then(|key| {
+            // Only the values from before the first lock count as the user's own
+            if self.saved.borrow().is_none() {
+                let saved = Saved {
+                    caption: key.read(CAPTION_VALUE)?,
+                    text: key.read(TEXT_VALUE)?,
+                };
+                write_backup(&saved);
+                *self.saved.borrow_mut() = Some(saved);
+            }
+            key.write(CAPTION_VALUE, &self.config.caption)?;
+            key.write(TEXT_VALUE, text)
+        });
+        match result {
+            Ok(()) => logger.log(&format!("Set lock screen message: {}: {}", self.config.caption, text)),
+            Err(ERROR_ACCESS_DENIED) => {
+                self.denied.set(true);
+                logger.warn("Setting the lock screen message needs administrator rights, not trying again");
+            }
+            Err(code) => logger.warn(&format!(
+                "Failed to set lock screen message: {}",
+                describe_win32_error(code.0)
+            )),
+        }
+    }
+
+    /// Called on unlock: the overlay stays up for `overlay_seconds` more, the
+    /// legal notice goes back to the values from before the first lock.
+    pub(crate) fn restore(&self, logger: &Logger) {
+        let overlay = self.overlay.get();
+        if overlay.0 != 0 && unsafe { IsWindow(overlay) }.as_bool() {
+            let millis = self.config.overlay_seconds.saturating_mul(1000);
+            if millis == 0 || unsafe { SetTimer(overlay, OVERLAY_TIMER_ID, millis, None) } == 0 {
+                self.close_overlay();
+            }
+        }
+        self.restore_legal_notice(logger);
+    }
+
+    /// Called on shutdown: nothing may be left behind.
+    pub(crate) fn close(&self, logger: &Logger) {
+        self.close_overlay();
+        self.restore_legal_notice(logger);
+    }
+
+    fn close_overlay(&self) {
+        let overlay = self.overlay.replace(HWND(0));
+        unsafe {
+            if overlay.0 != 0 && IsWindow(overlay).as_bool() {
+                DestroyWindow(overlay);
+            }
+        }
+    }
+
+    fn restore_legal_notice(&self, logger: &Logger) {
+        let Some(saved) = self.saved.borrow_mut().take() else {
+            return;
+        };
+        match put_back(&saved) {
+            Ok(()) => {
+                remove_backup();
+                logger.log("Restored the previous lock screen message");
+            }
+            Err(code) => logger.warn(&format!(
+                "Failed to restore the previous lock screen message: {}",
+                describe_win32_error(code.0)
+            )),
+        }
+    }
+}
+
+impl Drop for LockScreenMessage {
+    fn drop(&mut self) {
+        self.close_overlay();
+        if !self.class_name.is_empty() {
+            unsafe {
+                if let Ok(instance) = GetModuleHandleW(None) {
+                    UnregisterClassW(PCWSTR(self.class_name.as_ptr()), instance);
+                }
+            }
+        }
+    }
+}
+
+fn put_back(saved: &Saved) -> Result<(), WIN32_ERROR> {
+    let key = PolicyKey::open(KEY_SET_VALUE)?;
+    key.restore(CAPTION_VALUE, saved.caption.as_deref())?;
+    key.restore(TEXT_VALUE, saved.text.as_deref())
+}
+
+fn backup_path() -> Option<PathBuf> {
+    let local_app_data = std::env::var_os("LOCALAPPDATA")?;
+    Some(PathBuf::from(local_app_data).join(APP_NAME).join(BACKUP_FILE_NAME))
+}
+
+// Best effort; without it only a crash loses the user's values
+fn write_backup(saved: &Saved) {
+    let (Some(path), Ok(json)) = (backup_path(), serde_json::to_string(saved)) else {
+        return;
+    };
+    if let Some(parent) = path.parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    let _ = std::fs::write(path, json);
+}
+
+fn remove_backup() {
+    if let Some(path) = backup_path() {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Puts back values an earlier run set and never restored, e.g. after a crash.
+fn restore_stale(logger: &Logger) {
+    let Some(json) = backup_path().and_then(|path| std::fs::read_to_string(path).ok()) else {
+        return;
+    };
+    let saved = match serde_json::from_str::<Saved>(&json) {
+        Ok(saved) => saved,
+        Err(e) => {
+            logger.warn(&format!("Ignoring an unreadable lock screen message backup: {}", e));
+            remove_backup();
+            return;
+        }
+    };
+    match put_back(&saved) {
+        Ok(()) => {
+            remove_backup();
+            logger.log("Restored the lock screen message an earlier run left behind");
+        }
+        Err(code) => logger.warn(&format!(
+            "Failed to restore the lock screen message an earlier run left behind: {}",
+            describe_win32_error(code.0)
+        )),
+    }
+}
+
+fn register_overlay_class() -> windows::core::Result<Vec<u16>> {
+    unsafe {
+        let class_name = wide_string(&unique_class_name(OVERLAY_CLASS_NAME));
+        let wc = WNDCLASSEXW {
+            cbSize: std::mem::size_of::<WNDCLASSEXW>() as u32,
+            lpfnWndProc: Some(overlay_proc),
+            hInstance: GetModuleHandleW(None)?,
+            hCursor: LoadCursorW(None, IDC_HAND)?,
+            hbrBackground: HBRUSH((COLOR_INFOBK.0 + 1) as isize),
+            lpszClassName: PCWSTR(class_name.as_ptr()),
+            ..Default::default()
+        };
+        if RegisterClassExW(&wc) == 0 {
+            return Err(windows::core::Error::from_win32());
+        }
+        Ok(class_name)
+    }
+}
+
+// Top center of the primary screen, without taking focus
+unsafe fn create_overlay(class_name: &[u16], caption: &str, text: &str) -> windows::core::Result<HWND> {
+    if class_name.is_empty() {
+        return Err(windows::core::Error::from_win32());
+    }
+    let window = CreateWindowExW(
+        WS_EX_TOPMOST | WS_EX_TOOLWINDOW | WS_EX_NOACTIVATE,
+        PCWSTR(class_name.as_ptr()),
+        None,
+        WS_POPUP | WS_BORDER | WS_VISIBLE,
+        (GetSystemMetrics(SM_CXSCREEN) - OVERLAY_WIDTH) / 2,
+        OVERLAY_TOP,
+        OVERLAY_WIDTH,
+        OVERLAY_HEIGHT,
+        None,
+        None,
+        GetModuleHandleW(None)?,
+        None,
+    );
+    if window.0 == 0 {
+        return Err(windows::core::Error::from_win32());
+    }
+    // Static controls let clicks through to the window, which closes on them
+    let message = format!("{}\n{}", caption, text);
+    let label = create_child(window, "STATIC", &message, WINDOW_STYLE(0), 12, 10, OVERLAY_WIDTH - 24, OVERLAY_HEIGHT - 20, 0)?;
+    let font = GetStockObject(DEFAULT_GUI_FONT);
+    SendMessageW(label, WM_SETFONT, WPARAM(font.0 as usize), LPARAM(1));
+    Ok(window)
+}
+
+unsafe extern "system" fn overlay_proc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    match msg {
+        WM_LBUTTONDOWN | WM_TIMER => {
+            DestroyWindow(hwnd);
+            LRESULT(0)
+        }
+        _ => DefWindowProcW(hwnd, msg, wparam, lparam),
+    }
+}
+
+struct PolicyKey(HKEY);
+
+impl PolicyKey {
+    fn open(access: REG_SAM_FLAGS) -> Result<Self, WIN32_ERROR> {
+        let path = wide_string(POLICY_KEY);
+        let mut key = HKEY::default();
+        check(unsafe { RegOpenKeyExW(HKEY_LOCAL_MACHINE, PCWSTR(path.as_ptr()), 0, access, &mut key) })?;
+        Ok(PolicyKey(key))
+    }
+
+    fn read(&self, name: &str) -> Result<Option<String>, WIN32_ERROR> {
+        let name = wide_string(name);
+        let mut size = 0u32;
+        let status = unsafe { RegQueryValueExW(self.0, PCWSTR(name.as_ptr()), None, None, None, Some(&mut size)) };
+        if status == ERROR_FILE_NOT_FOUND {
+            return Ok(None);
+        }
+        check(status)?;
+
+        let mut buffer = vec![0u16; (size as usize).div_ceil(2)];
+        check(unsafe {
+            RegQueryValueExW(
+                self.0,
+                PCWSTR(name.as_ptr()),
+                None,
+                None,
+                Some(buffer.as_mut_ptr().cast()),
+                Some(&mut size),
+            )
+        })?;
+        let value = String::from_utf16_lossy(&buffer[..size as usize / 2]);
+        Ok(Some(value.trim_end_matches('\0').to_string()))
+    }
+
+    fn write(&self, name: &str, value: &str) -> Result<(), WIN32_ERROR> {
+        let name = wide_string(name);
+        let data: Vec<u8> = wide_string(value).iter().flat_map(|unit| unit.to_le_bytes()).collect();
+        check(unsafe { RegSetValueExW(self.0, PCWSTR(name.as_ptr()), 0, REG_SZ, Some(&data)) })
+    }
+
+    fn restore(&self, name: &str, value: Option<&str>) -> Result<(), WIN32_ERROR> {
+        match value {
+            Some(value) => self.write(name, value),
+            None => {
+                let name = wide_string(name);
+                match unsafe { RegDeleteValueW(self.0, PCWSTR(name.as_ptr())) } {
+                    ERROR_FILE_NOT_FOUND => Ok(()),
+                    status => check(status),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for PolicyKey {
+    fn drop(&mut self) {
+        unsafe {
+            RegCloseKey(self.0);
+        }
+    }
+}
+
+fn check(status: WIN32_ERROR) -> Result<(), WIN32_ERROR> {
+    if status == ERROR_SUCCESS {
+        Ok(())
+    } else {
+        Err(status)
+    }
+}
@@ -0,0 +1,759 @@
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::{GetLastError, HWND, LPARAM, PSID, WPARAM};
+use windows::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+use windows::Win32::System::EventLog::*;
+use windows::Win32::UI::WindowsAndMessaging::{PostMessageW, WM_APP};
+
+use crate::config::{LogBatchConfig, LogSinkConfig, LogSinkKind};
+use crate::event::Trigger;
+use crate::{describe_win32_error, hostname, session_id, wide_string, APP_NAME};
+
+/// Posted to the window given to [`Logger::attach_window`] for every line;
+/// lparam is a `Box<String>` the receiver takes ownership of.
+pub(crate) const WM_LOG_LINE: u32 = WM_APP + 8;
+/// Posted to the window given to [`Logger::report_failures_to`] when a log file
+/// becomes unwritable and `on_log_failure` is `alert` or `exit`; lparam is a
+/// `Box<String>` with the error, which the receiver takes ownership of, and
+/// wparam is 1 when the window should shut down and exit.
+pub(crate) const WM_LOG_FAILED: u32 = WM_APP + 12;
+/// Exit code with `on_log_failure = "exit"`.
+pub const LOG_FAILURE_EXIT_CODE: i32 = 3;
+// Lines kept until a window is attached
+const MAX_WINDOW_PENDING_LINES: usize = 1000;
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+const DEFAULT_TEMPLATE: &str = "[{ts}] {msg}";
+// Lines kept in memory while the log file is unreachable; the oldest go first
+const MAX_PENDING_BYTES: usize = 256 * 1024;
+const MIN_REOPEN_DELAY: Duration = Duration::from_secs(1);
+const MAX_REOPEN_DELAY: Duration = Duration::from_secs(60);
+
+thread_local! {
+    // Decisions run on the message loop thread, so only its lines carry a trigger
+    static CURRENT_TRIGGER: Cell<Option<Trigger>> = const { Cell::new(None) };
+}
+
+/// Tags every line logged on this thread while `f` runs with `trigger`.
+pub(crate) fn with_trigger<R>(trigger: Trigger, f: impl FnOnce() -> R) -> R {
+    let previous = CURRENT_TRIGGER.with(|current| current.replace(Some(trigger)));
+    let result = f();
+    CURRENT_TRIGGER.with(|current| current.set(previous));
+    result
+}
+
+/// Severity of a log line, lowest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Info => "INFO",
+            LogLevel::Warn => "WARN",
+            LogLevel::Error => "ERROR",
+        }
+    }
+}
+
+// A parsed text template; `{session}` is resolved when the template is parsed
+enum Segment {
+    Literal(String),
+    Timestamp,
+    Level,
+    Trigger,
+    Message,
+}
+
+// Returns the segments and any placeholders that were kept literally
+fn parse_template(template: &str) -> (Vec<Segment>, Vec<String>) {
+    let mut segments = Vec::new();
+    let mut unknown = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        let placeholder = &rest[start..start + len + 1];
+        let segment = match &placeholder[1..placeholder.len() - 1] {
+            "ts" => Segment::Timestamp,
+            "level" => Segment::Level,
+            "trigger" => Segment::Trigger,
+            "msg" => Segment::Message,
+            "session" => Segment::Literal(session_id()),
+            _ => {
+                unknown.push(placeholder.to_string());
+                Segment::Literal(placeholder.to_string())
+            }
+        };
+        segments.push(Segment::Literal(rest[..start].to_string()));
+        segments.push(segment);
+        rest = &rest[start + len + 1..];
+    }
+    segments.push(Segment::Literal(rest.to_string()));
+    (segments, unknown)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, tagged with the host and session.
+    Json,
+}
+
+/// What to do once a log file can no longer be written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFailurePolicy {
+    /// Keep lines in memory and retry the file in the background.
+    #[default]
+    Continue,
+    /// As `continue`, but also send the alert webhook and a notification.
+    Alert,
+    /// Exit with [`LOG_FAILURE_EXIT_CODE`], for a watchdog to restart lidlock.
+    Exit,
+}
+
+// Resolved once when JSON logging is enabled rather than on every line
+struct Identity {
+    host: String,
+    session_id: String,
+}
+
+impl Identity {
+    /// One `log_format = "json"` record; `trigger` is null outside a decision.
+    fn record(
+        &self,
+        now: &chrono::DateTime<chrono::Local>,
+        level: LogLevel,
+        trigger: Option<Trigger>,
+        message: &str,
+    ) -> serde_json::Value {
+        serde_json::json!({
+            "ts": now.to_rfc3339(),
+            "host": self.host,
+            "session_id": self.session_id,
+            "level": level.as_str(),
+            "trigger": trigger.map(|trigger| trigger.as_str()),
+            "msg": message,
+        })
+    }
+}
+
+// Writes to a log file that may live on a flaky network share. After a failed
+// write the file is closed, lines are kept in memory and the file is reopened
+// with backoff; the kept lines are written first once it is back.
+struct LogFile {
+    path: PathBuf,
+    // None while the file is unreachable
+    writer: Option<BufWriter<File>>,
+    // Zero flushes after every line
+    max_buffered_bytes: usize,
+    pending: VecDeque<String>,
+    pending_bytes: usize,
+    dropped_lines: usize,
+    reopen_delay: Duration,
+    reopen_at: Instant,
+    // Set when a write fails, until the logger has seen it
+    failure: Option<String>,
+}
+
+impl LogFile {
+    fn write(&mut self, line: &str, flush: bool) {
+        if self.writer.is_none() && !self.reopen() {
+            self.keep(line.to_string());
+            return;
+        }
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        let mut result = writer.write_all(line.as_bytes());
+        if result.is_ok() && (flush || writer.buffer().len() >= self.max_buffered_bytes) {
+            result = writer.flush();
+        }
+        if let Err(e) = result {
+            self.disconnect(&e);
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.writer.is_none() && !self.reopen() {
+            return;
+        }
+        if let Some(Err(e)) = self.writer.as_mut().map(Write::flush) {
+            self.disconnect(&e);
+        }
+    }
+
+    fn disconnect(&mut self, error: &std::io::Error) {
+        if let Some(writer) = self.writer.take() {
+            // Whatever the failed flush left in the buffer was never written
+            let (_, unwritten) = writer.into_parts();
+            let unwritten = unwritten.unwrap_or_default();
+            if !unwritten.is_empty() {
+                self.keep(String::from_utf8_lossy(&unwritten).into_owned());
+            }
+        }
+        self.reopen_delay = MIN_REOPEN_DELAY;
+        self.reopen_at = Instant::now() + self.reopen_delay;
+        self.failure = Some(format!("{}: {}", self.path.display(), error));
+        fallback(&format!(
+            "Log file {} is unavailable, keeping up to {} KiB of lines in memory: {}",
+            self.path.display(),
+            MAX_PENDING_BYTES / 1024,
+            error
+        ));
+    }
+
+    fn reopen(&mut self) -> bool {
+        let now = Instant::now();
+        if now < self.reopen_at {
+            return false;
+        }
+        let file = match OpenOptions::new().create(true).append(true).open(&self.path) {
+            Ok(file) => file,
+            Err(_) => {
+                self.reopen_delay = (self.reopen_delay * 2).min(MAX_REOPEN_DELAY);
+                self.reopen_at = now + self.reopen_delay;
+                return false;
+            }
+        };
+
+        let mut writer = BufWriter::new(file);
+        if self.dropped_lines > 0 {
+            let _ = writeln!(
+                writer,
+                "[{}] {} log lines were dropped while the log file was unavailable",
+                chrono::Local::now().format(TIME_FORMAT),
+                self.dropped_lines
+            );
+        }
+        for line in self.pending.drain(..) {
+            let _ = writer.write_all(line.as_bytes());
+        }
+        self.pending_bytes = 0;
+        self.dropped_lines = 0;
+        self.writer = Some(writer);
+        fallback(&format!("Log file {} is available again", self.path.display()));
+        true
+    }
+
+    fn keep(&mut self, line: String) {
+        self.pending_bytes += line.len();
+        self.pending.push_back(line);
+        while self.pending_bytes > MAX_PENDING_BYTES {
+            let Some(oldest) = self.pending.pop_front() else {
+                break;
+            };
+            self.pending_bytes -= oldest.len();
+            self.dropped_lines += oldest.matches('\n').count();
+        }
+    }
+}
+
+// Reports log file trouble where it can still be seen
+fn fallback(message: &str) {
+    let _ = writeln!(std::io::stderr(), "lidlock: {}", message);
+}
+
+fn open_log_file(path: &Path) -> Option<Arc<Mutex<LogFile>>> {
+    let file = OpenOptions::new().create(true).append(true).open(path).ok()?;
+    Some(Arc::new(Mutex::new(LogFile {
+        path: path.to_path_buf(),
+        writer: Some(BufWriter::new(file)),
+        max_buffered_bytes: 0,
+        pending: VecDeque::new(),
+        pending_bytes: 0,
+        dropped_lines: 0,
+        reopen_delay: MIN_REOPEN_DELAY,
+        reopen_at: Instant::now(),
+        failure: None,
+    })))
+}
+
+// Client end of a named pipe served by a log viewer. The pipe is opened lazily
+// and reopened after a failed write, so the viewer can come and go.
+struct LogPipe {
+    path: String,
+    file: Option<File>,
+}
+
+impl LogPipe {
+    fn write(&mut self, line: &str) {
+        if self.file.is_none() {
+            self.file = OpenOptions::new().write(true).open(&self.path).ok();
+        }
+        if let Some(file) = &mut self.file {
+            if file.write_all(line.as_bytes()).is_err() {
+                self.file = None;
+            }
+        }
+    }
+}
+
+struct WindowSink {
+    // Zero until a window is attached
+    hwnd: isize,
+    pending: Vec<String>,
+}
+
+impl WindowSink {
+    fn post(&self, line: String) {
+        let line = Box::into_raw(Box::new(line));
+        unsafe {
+            if !PostMessageW(HWND(self.hwnd), WM_LOG_LINE, WPARAM(0), LPARAM(line as isize)).as_bool() {
+                drop(Box::from_raw(line));
+            }
+        }
+    }
+}
+
+enum Sink {
+    File(Arc<Mutex<LogFile>>),
+    Console,
+    EventLog(EventSource),
+    Pipe(Mutex<LogPipe>),
+    Window(Mutex<WindowSink>),
+    Memory(Mutex<MemoryLog>),
+}
+
+// Newest last
+struct MemoryLog {
+    lines: VecDeque<String>,
+    capacity: usize,
+}
+
+impl Sink {
+    // Returns the error of a log file that just became unwritable, also if a
+    // background flush found it
+    fn write(&self, level: LogLevel, line: &str) -> Option<String> {
+        match self {
+            Sink::File(file) => {
+                if let Ok(mut file) = file.lock() {
+                    file.write(line, level == LogLevel::Error);
+                    return file.failure.take();
+                }
+            }
+            Sink::Console => {
+                let _ = std::io::stderr().write_all(line.as_bytes());
+            }
+            Sink::EventLog(source) => {
+                let event_type = match level {
+                    LogLevel::Error => EVENTLOG_ERROR_TYPE,
+                    LogLevel::Warn => EVENTLOG_WARNING_TYPE,
+                    LogLevel::Debug | LogLevel::Info => EVENTLOG_INFORMATION_TYPE,
+                };
+                let message = wide_string(line.trim_end());
+                unsafe {
+                    ReportEventW(
+                        source.0,
+                        event_type,
+                        0,
+                        0,
+                        PSID::default(),
+                        0,
+                        Some(&[PCWSTR(message.as_ptr())]),
+                        None,
+                    );
+                }
+            }
+            Sink::Pipe(pipe) => {
+                if let Ok(mut pipe) = pipe.lock() {
+                    pipe.write(line);
+                }
+            }
+            Sink::Window(window) => {
+                if let Ok(mut window) = window.lock() {
+                    if window.hwnd != 0 {
+                        window.post(line.to_string());
+                    } else if window.pending.len() < MAX_WINDOW_PENDING_LINES {
+                        window.pending.push(line.to_string());
+                    }
+                }
+            }
+            Sink::Memory(memory) => {
+                if let Ok(mut memory) = memory.lock() {
+                    if memory.lines.len() >= memory.capacity {
+                        memory.lines.pop_front();
+                    }
+                    memory.lines.push_back(line.to_string());
+                }
+            }
+        }
+        None
+    }
+}
+
+struct EventSource(EventSourceHandle);
+
+impl Drop for EventSource {
+    fn drop(&mut self) {
+        unsafe {
+            DeregisterEventSource(self.0);
+        }
+    }
+}
+
+struct Output {
+    sink: Sink,
+    // None follows the logger default: INFO, or DEBUG with `--debug`
+    level: Option<LogLevel>,
+}
+
+pub struct Logger {
+    outputs: Vec<Output>,
+    identity: Option<Identity>,
+    template: Vec<Segment>,
+    debug: bool,
+    on_failure: LogFailurePolicy,
+    // Receives WM_LOG_FAILED, zero until one is set
+    failure_window: AtomicIsize,
+    // Set once the window was asked to exit, so it is only asked once
+    exiting: AtomicBool,
+}
+
+impl Logger {
+    pub fn new(path: Option<&str>) -> Self {
+        let outputs = path
+            .and_then(|path| open_log_file(Path::new(path)))
+            .map(|file| Output {
+                sink: Sink::File(file),
+                level: None,
+            })
+            .into_iter()
+            .collect();
+        Logger {
+            outputs,
+            identity: None,
+            template: parse_template(DEFAULT_TEMPLATE).0,
+            debug: false,
+            on_failure: LogFailurePolicy::default(),
+            failure_window: AtomicIsize::new(0),
+            exiting: AtomicBool::new(false),
+        }
+    }
+
+    /// Replaces the outputs with `sinks`. A file sink without a `path` keeps
+    /// writing to the log file given on the command line.
+    pub fn with_sinks(mut self, sinks: &[LogSinkConfig]) -> Self {
+        let mut default_file = self.outputs.drain(..).find_map(|output| match output.sink {
+            Sink::File(file) => Some(file),
+            _ => None,
+        });
+
+        let mut failures = Vec::new();
+        for config in sinks {
+            let sink = match &config.kind {
+                LogSinkKind::File { path: Some(path) } => open_log_file(path).map(Sink::File),
+                LogSinkKind::File { path: None } => default_file.take().map(Sink::File),
+                LogSinkKind::Console => {
+                    // The binary has no console of its own
+                    unsafe {
+                        AttachConsole(ATTACH_PARENT_PROCESS);
+                    }
+                    Some(Sink::Console)
+                }
+                LogSinkKind::EventLog => {
+                    let source = wide_string(APP_NAME);
+                    unsafe { RegisterEventSourceW(None, PCWSTR(source.as_ptr())) }
+                        .ok()
+                        .map(|source| Sink::EventLog(EventSource(source)))
+                }
+                LogSinkKind::Pipe { name } => Some(Sink::Pipe(Mutex::new(LogPipe {
+                    path: format!(r"\\.\pipe\{}", name),
+                    file: None,
+                }))),
+                LogSinkKind::Memory { lines } => Some(Sink::Memory(Mutex::new(MemoryLog {
+                    lines: VecDeque::new(),
+                    capacity: (*lines).max(1),
+                }))),
+            };
+            match sink {
+                Some(sink) => self.outputs.push(Output {
+                    sink,
+                    level: config.level,
+                }),
+                None => failures.push(config.kind.as_str()),
+            }
+        }
+        for kind in failures {
+            self.warn(&format!("Failed to open {} log sink", kind));
+        }
+        self
+    }
+
+    /// Adds a sink that posts every line to a window once one is attached with
+    /// [`Logger::attach_window`]; lines logged before then are kept.
+    pub fn with_window_sink(mut self) -> Self {
+        self.outputs.push(Output {
+            sink: Sink::Window(Mutex::new(WindowSink {
+                hwnd: 0,
+                pending: Vec::new(),
+            })),
+            level: None,
+        });
+        self
+    }
+
+    /// The last `count` lines kept by the memory sink, oldest first, or `None`
+    /// if there is no memory sink.
+    pub(crate) fn tail(&self, count: usize) -> Option<Vec<String>> {
+        self.outputs.iter().find_map(|output| match &output.sink {
+            Sink::Memory(memory) => memory.lock().ok().map(|memory| {
+                let skip = memory.lines.len().saturating_sub(count);
+                memory.lines.iter().skip(skip).cloned().collect()
+            }),
+            _ => None,
+        })
+    }
+
+    /// Sends window sink lines to `hwnd` as `WM_LOG_LINE`, starting with any
+    /// kept so far. `HWND(0)` detaches the window again.
+    pub(crate) fn attach_window(&self, hwnd: HWND) {
+        for output in &self.outputs {
+            let Sink::Window(window) = &output.sink else {
+                continue;
+            };
+            if let Ok(mut window) = window.lock() {
+                window.hwnd = hwnd.0;
+                if hwnd.0 != 0 {
+                    for line in std::mem::take(&mut window.pending) {
+                        window.post(line);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.identity = match format {
+            LogFormat::Text => None,
+            LogFormat::Json => Some(Identity {
+                host: hostname(),
+                session_id: session_id(),
+            }),
+        };
+        self
+    }
+
+    /// Sets the text mode line format. Supported placeholders are `{ts}`,
+    /// `{level}`, `{trigger}`, `{msg}` and `{session}`; anything else is kept as is.
+    pub fn with_template(mut self, template: &str) -> Self {
+        let (segments, unknown) = parse_template(template);
+        self.template = segments;
+        for placeholder in unknown {
+            self.warn(&format!("Unknown log template placeholder {}", placeholder));
+        }
+        self
+    }
+
+    /// Buffers file sink lines instead of flushing each one. The buffer is
+    /// flushed when it grows past `max_buffered_bytes`, every
+    /// `flush_interval_ms`, and after every [`Logger::error`] line.
+    pub fn with_batching(self, batching: &LogBatchConfig) -> Self {
+        for output in &self.outputs {
+            let Sink::File(file) = &output.sink else {
+                continue;
+            };
+            if let Ok(mut file) = file.lock() {
+                file.max_buffered_bytes = batching.max_buffered_bytes;
+            }
+
+            // Weak so the flush thread ends once the logger is dropped
+            let file = Arc::downgrade(file);
+            let interval = Duration::from_millis(batching.flush_interval_ms.max(1));
+            std::thread::spawn(move || loop {
+                std::thread::sleep(interval);
+                let Some(shared) = file.upgrade() else {
+                    break;
+                };
+                if let Ok(mut guard) = shared.lock() {
+                    guard.flush();
+                };
+            });
+        }
+        self
+    }
+
+    pub fn with_failure_policy(mut self, policy: LogFailurePolicy) -> Self {
+        self.on_failure = policy;
+        self
+    }
+
+    /// Sends `WM_LOG_FAILED` to `hwnd` under `on_log_failure = "alert"` or `"exit"`.
+    pub(crate) fn report_failures_to(&self, hwnd: HWND) {
+        self.failure_window.store(hwnd.0, Ordering::Relaxed);
+    }
+
+    /// Enables messages written with [`Logger::debug`] on sinks without their own level.
+    pub fn with_debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    /// Logs only to sinks that accept debug output.
+    pub fn debug(&self, message: &str) {
+        self.write(LogLevel::Debug, message);
+    }
+
+    pub fn log(&self, message: &str) {
+        self.write(LogLevel::Info, message);
+    }
+
+    pub fn warn(&self, message: &str) {
+        self.write(LogLevel::Warn, message);
+    }
+
+    /// Logs a failure and flushes immediately, so it survives a crash even
+    /// when batching is enabled.
+    pub fn error(&self, message: &str) {
+        self.write(LogLevel::Error, message);
+    }
+
+    /// Logs the `GetLastError` code left by a failed `operation` and returns it
+    /// as an error. Call it straight after the failure, before anything else
+    /// can overwrite the code.
+    pub(crate) fn last_win32_error(&self, operation: &str) -> windows::core::Error {
+        let code = unsafe { GetLastError() };
+        self.error(&format!("{} failed: {}", operation, describe_win32_error(code.0)));
+        windows::core::Error::from(code.to_hresult())
+    }
+
+    /// Writes out lines buffered by file sinks.
+    pub fn flush(&self) {
+        for output in &self.outputs {
+            if let Sink::File(file) = &output.sink {
+                if let Ok(mut file) = file.lock() {
+                    file.flush();
+                }
+            }
+        }
+    }
+
+    fn accepts(&self, output: &Output, level: LogLevel) -> bool {
+        let default = if self.debug { LogLevel::Debug } else { LogLevel::Info };
+        level >= output.level.unwrap_or(default)
+    }
+
+    fn format_text(&self, now: &chrono::DateTime<chrono::Local>, level: LogLevel, message: &str) -> String {
+        let mut line = String::new();
+        for segment in &self.template {
+            match segment {
+                Segment::Literal(text) => line.push_str(text),
+                Segment::Timestamp => line.push_str(&now.format(TIME_FORMAT).to_string()),
+                Segment::Level => line.push_str(level.as_str()),
+                Segment::Trigger => {
+                    let trigger = CURRENT_TRIGGER.with(Cell::get);
+                    line.push_str(trigger.map_or("-", |trigger| trigger.as_str()));
+                }
+                Segment::Message => line.push_str(message),
+            }
+        }
+        line.push('\n');
+        line
+    }
+
+    fn write(&self, level: LogLevel, message: &str) {
+        // Formatted once and only if some sink wants it
+        let mut log_line = None;
+        let mut failure = None;
+        for output in self.outputs.iter().filter(|output| self.accepts(output, level)) {
+            let line = log_line.get_or_insert_with(|| {
+                let now = chrono::Local::now();
+                match &self.identity {
+                    Some(identity) => {
+                        let record = identity.record(&now, level, CURRENT_TRIGGER.with(Cell::get), message);
+                        format!("{}\n", record)
+                    }
+                    None => self.format_text(&now, level, message),
+                }
+            });
+            failure = output.sink.write(level, line).or(failure);
+        }
+        if let Some(error) = failure {
+            self.log_file_failed(&error);
+        }
+    }
+
+    fn log_file_failed(&self, error: &str) {
+        match self.on_failure {
+            LogFailurePolicy::Continue => {}
+            LogFailurePolicy::Alert => {
+                // The failed file keeps this line for when it is back
+                self.error(&format!("Log file became unwritable: {}", error));
+                self.post_failure(error, false);
+            }
+            LogFailurePolicy::Exit => {
+                if self.exiting.swap(true, Ordering::SeqCst) {
+                    return;
+                }
+                self.error(&format!(
+                    "Log file became unwritable, exiting with code {}: {}",
+                    LOG_FAILURE_EXIT_CODE, error
+                ));
+                // This may run on any thread; the window shuts down in order before exiting
+                if !self.post_failure(error, true) {
+                    self.flush();
+                    std::process::exit(LOG_FAILURE_EXIT_CODE);
+                }
+            }
+        }
+    }
+
+    // Returns whether the failure window got the message
+    fn post_failure(&self, error: &str, exit: bool) -> bool {
+        let hwnd = HWND(self.failure_window.load(Ordering::Relaxed));
+        if hwnd.0 == 0 {
+            return false;
+        }
+        let error = Box::into_raw(Box::new(error.to_string()));
+        unsafe {
+            let posted = PostMessageW(hwnd, WM_LOG_FAILED, WPARAM(exit as usize), LPARAM(error as isize)).as_bool();
+            if !posted {
+                drop(Box::from_raw(error));
+            }
+            posted
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_record_carries_level_and_trigger() {
+        let identity = Identity {
+            host: "laptop".to_string(),
+            session_id: "1".to_string(),
+        };
+        let now = chrono::Local::now();
+        let record = identity.record(&now, LogLevel::Warn, Some(Trigger::LidSwitch), "Lid closed");
+        assert_eq!(record["level"], "WARN");
+        assert_eq!(record["trigger"], "lid_switch");
+        assert_eq!(record["msg"], "Lid closed");
+        assert_eq!(record["host"], "laptop");
+
+        let record = identity.record(&now, LogLevel::Debug, None, "Idle poll");
+        assert_eq!(record["level"], "DEBUG");
+        assert!(record["trigger"].is_null());
+    }
+}
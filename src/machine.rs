@@ -0,0 +1,996 @@
+// The decision pipeline and the state it keeps between events, with no Win32
+// dependencies. The window feeds [`Pipeline::step`] events and carries out the
+// [`Step`] it returns; timers, logging, system queries and locking stay there.
+//
+// The pipeline is made of small state machines: [`Gate`] covers disarmed and
+// paused, [`Coalescer`] triggers arriving together, [`LidHold`] a lid close
+// waiting out its grace period and [`Throttle`] the throttled state.
+// [`BatteryArm`] and [`PresenceEvaluator`] turn battery and presence readings
+// into triggers before they reach it.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, LowBatteryConfig, PresenceInput};
+use crate::event::{LockAction, SkipReason, Trigger};
+
+/// Runs counted by [`Throttle`] expire after this long.
+const THROTTLE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Whether triggers are acted on at all: `start_disarmed` until armed, and
+/// `POST /pause` until `POST /resume`. The two are independent, so a pause
+/// set while disarmed still holds once armed.
+///
+/// ```text
+/// Disarmed --arm--> Armed --disarm--> Disarmed
+/// Running --pause--> Paused --resume--> Running
+/// ```
+#[derive(Debug)]
+pub(crate) struct Gate {
+    armed: bool,
+    paused: bool,
+}
+
+impl Gate {
+    pub(crate) fn new(armed: bool) -> Self {
+        Gate { armed, paused: false }
+    }
+
+    /// Returns whether this changed anything.
+    pub(crate) fn set_armed(&mut self, armed: bool) -> bool {
+        std::mem::replace(&mut self.armed, armed) != armed
+    }
+
+    /// Returns whether this changed anything. Only the status server pauses.
+    #[cfg(any(test, feature = "http-status"))]
+    pub(crate) fn set_paused(&mut self, paused: bool) -> bool {
+        std::mem::replace(&mut self.paused, paused) != paused
+    }
+
+    pub(crate) fn armed(&self) -> bool {
+        self.armed
+    }
+
+    pub(crate) fn paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Disarmed stops everything; a pause doesn't stop an explicit lock request.
+    /// The pipeline checks the two at different points, see [`Gate::check_paused`].
+    pub(crate) fn check_armed(&self) -> Result<(), SkipReason> {
+        if self.armed {
+            Ok(())
+        } else {
+            Err(SkipReason::Disarmed)
+        }
+    }
+
+    pub(crate) fn check_paused(&self, trigger: Trigger) -> Result<(), SkipReason> {
+        if self.paused && trigger != Trigger::Manual {
+            Err(SkipReason::Paused)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Drops a trigger arriving within `coalesce_window_ms` of the last one acted
+/// on, since a single lid close often reports both the lid and the monitor.
+///
+/// Only triggers that were acted on are recorded, so one skipped later in the
+/// pipeline doesn't swallow the next. Manual and scheduled locks are explicit
+/// requests and are never coalesced.
+#[derive(Debug, Default)]
+pub(crate) struct Coalescer {
+    last: Option<(Trigger, Instant)>,
+}
+
+impl Coalescer {
+    /// The trigger `trigger` coalesces with and how long ago it was acted on, if any.
+    pub(crate) fn check(&self, trigger: Trigger, now: Instant, window: Duration) -> Option<(Trigger, Duration)> {
+        if matches!(trigger, Trigger::Manual | Trigger::Scheduled) {
+            return None;
+        }
+        let (previous, at) = self.last?;
+        let ago = now.saturating_duration_since(at);
+        (ago < window).then_some((previous, ago))
+    }
+
+    /// Called once `trigger` is acted on.
+    pub(crate) fn record(&mut self, trigger: Trigger, now: Instant) {
+        self.last = Some((trigger, now));
+    }
+}
+
+/// A lid close waiting out `lid_closed_seconds`.
+///
+/// ```text
+/// Idle --start--> Waiting --elapsed--> Confirming --take_confirming--> Idle
+///                    |                     |
+///                    '------cancel---------'-------------------------> Idle
+/// ```
+#[derive(Debug, Default)]
+pub(crate) enum LidHold {
+    #[default]
+    Idle,
+    /// The hold timer is running.
+    Waiting(Vec<LockAction>),
+    /// The timer expired and a fresh lid reading was requested.
+    Confirming(Vec<LockAction>),
+}
+
+impl LidHold {
+    pub(crate) fn start(&mut self, actions: Vec<LockAction>) {
+        *self = LidHold::Waiting(actions);
+    }
+
+    /// Moves a waiting hold on to confirming; returns false if nothing was waiting.
+    pub(crate) fn elapsed(&mut self) -> bool {
+        match std::mem::take(self) {
+            LidHold::Waiting(actions) => {
+                *self = LidHold::Confirming(actions);
+                true
+            }
+            other => {
+                *self = other;
+                false
+            }
+        }
+    }
+
+    /// Ends a confirming hold, returning the actions it held.
+    pub(crate) fn take_confirming(&mut self) -> Option<Vec<LockAction>> {
+        match std::mem::take(self) {
+            LidHold::Confirming(actions) => Some(actions),
+            other => {
+                *self = other;
+                None
+            }
+        }
+    }
+
+    /// Ends a waiting hold, returning the actions it held.
+    pub(crate) fn withdraw(&mut self) -> Option<Vec<LockAction>> {
+        match std::mem::take(self) {
+            LidHold::Waiting(actions) => Some(actions),
+            other => {
+                *self = other;
+                None
+            }
+        }
+    }
+
+    /// Drops a waiting or confirming hold; returns whether there was one.
+    pub(crate) fn cancel(&mut self) -> bool {
+        !matches!(std::mem::take(self), LidHold::Idle)
+    }
+}
+
+/// Result of [`Throttle::check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ThrottleCheck {
+    Allowed,
+    /// Allowed, and the first run allowed since the limit was hit.
+    Recovered,
+    /// Suppressed, and the first run suppressed since the limit was hit; carries
+    /// the number of runs in the window.
+    Started(usize),
+    Suppressed,
+}
+
+/// Counts runs within [`THROTTLE_WINDOW`] against `max_locks_per_minute`.
+///
+/// Allowed runs are counted, suppressed ones aren't, so the limit lifts once
+/// the oldest counted run is a minute old.
+#[derive(Debug, Default)]
+pub(crate) struct Throttle {
+    // Oldest first
+    runs: VecDeque<Instant>,
+    throttled: bool,
+}
+
+impl Throttle {
+    pub(crate) fn check(&mut self, now: Instant, limit: usize) -> ThrottleCheck {
+        while self.runs.front().is_some_and(|run| now.duration_since(*run) >= THROTTLE_WINDOW) {
+            self.runs.pop_front();
+        }
+
+        if self.runs.len() >= limit {
+            if std::mem::replace(&mut self.throttled, true) {
+                return ThrottleCheck::Suppressed;
+            }
+            return ThrottleCheck::Started(self.runs.len());
+        }
+
+        self.runs.push_back(now);
+        if std::mem::replace(&mut self.throttled, false) {
+            ThrottleCheck::Recovered
+        } else {
+            ThrottleCheck::Allowed
+        }
+    }
+}
+
+/// The settings [`Pipeline`] decides with.
+#[derive(Debug, Clone)]
+pub(crate) struct PipelineConfig {
+    pub(crate) min_uptime: Duration,
+    pub(crate) coalesce_window: Duration,
+    /// Whether a lid close waits out `lid_closed_seconds` before acting.
+    pub(crate) lid_hold: bool,
+    pub(crate) max_locks_per_minute: Option<usize>,
+    /// Whether no actions for a lid close come from the clamshell policy.
+    pub(crate) clamshell: bool,
+}
+
+impl PipelineConfig {
+    pub(crate) fn new(config: &Config) -> Self {
+        PipelineConfig {
+            min_uptime: Duration::from_secs(config.min_uptime_seconds),
+            coalesce_window: Duration::from_millis(config.coalesce_window_ms),
+            lid_hold: config.lid_closed_seconds > 0,
+            max_locks_per_minute: config.max_locks_per_minute.map(|limit| limit as usize),
+            clamshell: config.clamshell.is_some(),
+        }
+    }
+}
+
+/// Where the pipeline is between events, for the log and tests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PipelineState {
+    Idle,
+    Disarmed,
+    Paused,
+    /// A lid close is waiting out `lid_closed_seconds`.
+    GracePending,
+    /// The grace period ended and a fresh lid reading was asked for.
+    Confirming,
+    Throttled,
+}
+
+impl PipelineState {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            PipelineState::Idle => "idle",
+            PipelineState::Disarmed => "disarmed",
+            PipelineState::Paused => "paused",
+            PipelineState::GracePending => "grace_pending",
+            PipelineState::Confirming => "confirming",
+            PipelineState::Throttled => "throttled",
+        }
+    }
+}
+
+/// What happened, as far as the pipeline is concerned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum PipelineEvent {
+    /// A trigger fired, with the actions selected for it.
+    Trigger {
+        trigger: Trigger,
+        state: u32,
+        actions: Vec<LockAction>,
+    },
+    /// The lid hold timer ran out.
+    HoldElapsed,
+    /// No fresh lid reading could be had after [`Step::ConfirmLid`], so the
+    /// last one, closed, stands.
+    LidUnconfirmed,
+    /// The lid hold timer couldn't be started after [`Step::StartHold`].
+    HoldFailed,
+    /// The countdown notification was clicked.
+    HoldDeclined,
+}
+
+/// What the window has to do for an event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Step {
+    /// Nothing, e.g. the hold timer of a hold that was cancelled meanwhile.
+    Ignore,
+    Skip(SkipReason),
+    /// A trigger left its off state; skipped with [`SkipReason::NonZeroState`].
+    /// `lid_opened` if the lid was closed before, `hold_cancelled` if that
+    /// dropped a lid hold, and `unconfirmed` if it came in as the fresh
+    /// reading a confirming hold asked for.
+    Released {
+        lid_opened: bool,
+        hold_cancelled: bool,
+        unconfirmed: bool,
+    },
+    /// Skipped with [`SkipReason::Coalesced`].
+    Coalesced { previous: Trigger, ago: Duration },
+    /// Start the lid hold timer; the decision is deferred.
+    StartHold,
+    /// Ask for a fresh lid reading, which comes back as a lid trigger.
+    ConfirmLid,
+    /// Run the actions. `held` if they waited out a lid hold, `recovered` if
+    /// this is the first run after throttling stopped.
+    Act {
+        actions: Vec<LockAction>,
+        held: bool,
+        recovered: bool,
+    },
+    /// Skipped with [`SkipReason::Throttled`]; `started` carries the number of
+    /// runs in the last minute when throttling just started.
+    Throttled { started: Option<usize> },
+    /// The lid hold was declined from the countdown notification.
+    Declined(Vec<LockAction>),
+}
+
+/// Orders the checks a trigger goes through before anything runs:
+///
+/// ```text
+/// lid state -> armed -> startup guard -> coalescing -> paused -> system checks
+///     -> actions selected -> lid hold -> throttle -> act
+/// ```
+///
+/// The system checks (schedule, overlay, session, network, recent input) need
+/// Win32, so the window passes them in and they are only asked once
+/// everything before them passed.
+#[derive(Debug)]
+pub(crate) struct Pipeline {
+    config: PipelineConfig,
+    started: Instant,
+    gate: Gate,
+    coalescer: Coalescer,
+    hold: LidHold,
+    throttle: Throttle,
+    lid_closed: Option<bool>,
+    // Set while a lid reading was asked for to re-check after unlock or resume
+    recheck_pending: bool,
+}
+
+impl Pipeline {
+    pub(crate) fn new(config: PipelineConfig, armed: bool, now: Instant) -> Self {
+        Pipeline {
+            config,
+            started: now,
+            gate: Gate::new(armed),
+            coalescer: Coalescer::default(),
+            hold: LidHold::default(),
+            throttle: Throttle::default(),
+            lid_closed: None,
+            recheck_pending: false,
+        }
+    }
+
+    pub(crate) fn gate(&self) -> &Gate {
+        &self.gate
+    }
+
+    pub(crate) fn gate_mut(&mut self) -> &mut Gate {
+        &mut self.gate
+    }
+
+    /// The last lid state reported, if any.
+    pub(crate) fn lid_closed(&self) -> Option<bool> {
+        self.lid_closed
+    }
+
+    /// Makes the next lid reading count even if the lid was already closed.
+    pub(crate) fn expect_recheck(&mut self, pending: bool) {
+        self.recheck_pending = pending;
+    }
+
+    pub(crate) fn rechecking(&self) -> bool {
+        self.recheck_pending
+    }
+
+    /// The actions a lid hold is holding back, if one is pending.
+    pub(crate) fn held(&self) -> Option<&[LockAction]> {
+        match &self.hold {
+            LidHold::Idle => None,
+            LidHold::Waiting(actions) | LidHold::Confirming(actions) => Some(actions),
+        }
+    }
+
+    pub(crate) fn state(&self) -> PipelineState {
+        if !self.gate.armed() {
+            PipelineState::Disarmed
+        } else if self.gate.paused() {
+            PipelineState::Paused
+        } else {
+            match self.hold {
+                LidHold::Waiting(_) => PipelineState::GracePending,
+                LidHold::Confirming(_) => PipelineState::Confirming,
+                LidHold::Idle if self.throttle.throttled => PipelineState::Throttled,
+                LidHold::Idle => PipelineState::Idle,
+            }
+        }
+    }
+
+    /// Feeds one event through the pipeline. `checks` is asked for the first
+    /// system check that rules a trigger out, only once it got that far.
+    pub(crate) fn step(
+        &mut self,
+        event: PipelineEvent,
+        now: Instant,
+        checks: impl FnOnce(Trigger) -> Option<SkipReason>,
+    ) -> Step {
+        match event {
+            PipelineEvent::Trigger { trigger, state, actions } => self.trigger(trigger, state, actions, now, checks),
+            PipelineEvent::HoldElapsed => {
+                if self.hold.elapsed() {
+                    Step::ConfirmLid
+                } else {
+                    Step::Ignore
+                }
+            }
+            PipelineEvent::LidUnconfirmed => match self.hold.take_confirming() {
+                Some(actions) => self.act(actions, true, now),
+                None => Step::Ignore,
+            },
+            PipelineEvent::HoldFailed => match self.hold.withdraw() {
+                Some(actions) => self.act(actions, false, now),
+                None => Step::Ignore,
+            },
+            PipelineEvent::HoldDeclined => match self.hold.withdraw() {
+                Some(actions) => Step::Declined(actions),
+                None => Step::Ignore,
+            },
+        }
+    }
+
+    fn trigger(
+        &mut self,
+        trigger: Trigger,
+        state: u32,
+        actions: Vec<LockAction>,
+        now: Instant,
+        checks: impl FnOnce(Trigger) -> Option<SkipReason>,
+    ) -> Step {
+        // Only the open -> closed transition locks; a re-check after unlock
+        // deliberately re-reports the current state
+        let mut lid_opened = false;
+        let mut unconfirmed = false;
+        if trigger == Trigger::LidSwitch {
+            let was_closed = self.lid_closed.replace(state == 0);
+            lid_opened = state != 0 && was_closed == Some(true);
+            let rechecking = std::mem::take(&mut self.recheck_pending);
+            if let Some(held) = self.hold.take_confirming() {
+                if state == 0 {
+                    return self.act(held, true, now);
+                }
+                unconfirmed = true;
+            } else if state == 0 && was_closed == Some(true) && !rechecking {
+                return Step::Skip(SkipReason::LidUnchanged);
+            }
+        }
+        if state != 0 {
+            let hold_cancelled = trigger == Trigger::LidSwitch && self.hold.cancel();
+            return Step::Released {
+                lid_opened,
+                hold_cancelled,
+                unconfirmed,
+            };
+        }
+
+        // Logged like any other skip, so a disarmed instance still shows what it would see
+        if let Err(reason) = self.gate.check_armed() {
+            return Step::Skip(reason);
+        }
+        // Some displays report a spurious off state while initializing right after login
+        if trigger != Trigger::Manual && now.saturating_duration_since(self.started) < self.config.min_uptime {
+            return Step::Skip(SkipReason::Startup);
+        }
+        // A single lid close often reports both the lid and the monitor turning off
+        if let Some((previous, ago)) = self.coalescer.check(trigger, now, self.config.coalesce_window) {
+            return Step::Coalesced { previous, ago };
+        }
+        if let Err(reason) = self.gate.check_paused(trigger) {
+            return Step::Skip(reason);
+        }
+        if let Some(reason) = checks(trigger) {
+            return Step::Skip(reason);
+        }
+        if actions.is_empty() {
+            let from_policy = trigger == Trigger::LidSwitch && self.config.clamshell;
+            return Step::Skip(if from_policy { SkipReason::Clamshell } else { SkipReason::NoActions });
+        }
+
+        // Only now, so a trigger skipped above doesn't swallow the next one
+        self.coalescer.record(trigger, now);
+        if trigger == Trigger::LidSwitch && self.config.lid_hold {
+            self.hold.start(actions);
+            return Step::StartHold;
+        }
+        self.act(actions, false, now)
+    }
+
+    fn act(&mut self, actions: Vec<LockAction>, held: bool, now: Instant) -> Step {
+        let mut recovered = false;
+        if let Some(limit) = self.config.max_locks_per_minute {
+            match self.throttle.check(now, limit) {
+                ThrottleCheck::Allowed => {}
+                ThrottleCheck::Recovered => recovered = true,
+                ThrottleCheck::Started(runs) => return Step::Throttled { started: Some(runs) },
+                ThrottleCheck::Suppressed => return Step::Throttled { started: None },
+            }
+        }
+        Step::Act {
+            actions,
+            held,
+            recovered,
+        }
+    }
+}
+
+/// Result of [`BatteryArm::update`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BatteryCheck {
+    Unchanged,
+    /// The charge reached the threshold while armed; the trigger fires and disarms.
+    Fired,
+    /// The charge is back at `rearm_percent`.
+    Rearmed,
+}
+
+/// Hysteresis for the low battery trigger.
+///
+/// ```text
+/// Armed --percent <= threshold--> Disarmed (fires)
+/// Disarmed --percent >= rearm_percent--> Armed
+/// Disarmed --decision skipped--> Armed
+/// ```
+#[derive(Debug)]
+pub(crate) struct BatteryArm {
+    armed: bool,
+}
+
+impl Default for BatteryArm {
+    fn default() -> Self {
+        BatteryArm { armed: true }
+    }
+}
+
+impl BatteryArm {
+    pub(crate) fn update(&mut self, percent: u32, config: &LowBatteryConfig) -> BatteryCheck {
+        if self.armed && percent <= config.percent {
+            self.armed = false;
+            BatteryCheck::Fired
+        } else if !self.armed && percent >= config.rearm_percent {
+            self.armed = true;
+            BatteryCheck::Rearmed
+        } else {
+            BatteryCheck::Unchanged
+        }
+    }
+
+    /// Called when the decision for a crossing was skipped, e.g. by
+    /// `min_uptime_seconds`, so the next reading at or below the threshold
+    /// fires again instead of waiting for a recharge.
+    pub(crate) fn release(&mut self) {
+        self.armed = true;
+    }
+}
+
+/// Whether the user is at the machine, as far as the `presence` inputs tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum UserPresence {
+    #[default]
+    Present,
+    Absent,
+}
+
+impl UserPresence {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            UserPresence::Present => "present",
+            UserPresence::Absent => "absent",
+        }
+    }
+}
+
+/// Result of [`PresenceEvaluator::update`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PresenceUpdate {
+    /// The input reported what it did before.
+    Unchanged,
+    /// The input changed, the combined presence didn't.
+    Same(UserPresence),
+    Changed(UserPresence),
+}
+
+/// Combines the presence inputs: the user is absent as soon as any input
+/// reports absence, and present again once none does.
+///
+/// ```text
+/// Present --any input absent--> Absent --every input present--> Present
+/// ```
+#[derive(Debug, Default)]
+pub(crate) struct PresenceEvaluator {
+    // Inputs currently reporting absence, in the order they did
+    absent: Vec<PresenceInput>,
+    presence: UserPresence,
+}
+
+impl PresenceEvaluator {
+    pub(crate) fn update(&mut self, input: PresenceInput, absent: bool) -> PresenceUpdate {
+        if self.absent.contains(&input) == absent {
+            return PresenceUpdate::Unchanged;
+        }
+        if absent {
+            self.absent.push(input);
+        } else {
+            self.absent.retain(|other| *other != input);
+        }
+
+        let presence = if self.absent.is_empty() {
+            UserPresence::Present
+        } else {
+            UserPresence::Absent
+        };
+        if std::mem::replace(&mut self.presence, presence) == presence {
+            PresenceUpdate::Same(presence)
+        } else {
+            PresenceUpdate::Changed(presence)
+        }
+    }
+
+    pub(crate) fn absent_inputs(&self) -> &[PresenceInput] {
+        &self.absent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCK: &[LockAction] = &[LockAction::Lock];
+
+    #[test]
+    fn coalescer_ignores_triggers_that_were_not_acted_on() {
+        let window = Duration::from_secs(2);
+        let start = Instant::now();
+        let mut coalescer = Coalescer::default();
+        // The lid close is skipped later on, e.g. by the schedule, so it isn't recorded
+        assert_eq!(coalescer.check(Trigger::LidSwitch, start, window), None);
+        let monitor_off = start + Duration::from_secs(1);
+        assert_eq!(coalescer.check(Trigger::MonitorPower, monitor_off, window), None);
+        coalescer.record(Trigger::MonitorPower, monitor_off);
+        assert_eq!(
+            coalescer.check(Trigger::LidSwitch, monitor_off + Duration::from_secs(1), window),
+            Some((Trigger::MonitorPower, Duration::from_secs(1)))
+        );
+        assert_eq!(coalescer.check(Trigger::LidSwitch, monitor_off + window, window), None);
+    }
+
+    #[test]
+    fn coalescer_never_drops_explicit_locks() {
+        let window = Duration::from_secs(2);
+        let start = Instant::now();
+        let mut coalescer = Coalescer::default();
+        coalescer.record(Trigger::LidSwitch, start);
+        assert_eq!(coalescer.check(Trigger::Manual, start, window), None);
+        assert_eq!(coalescer.check(Trigger::Scheduled, start, window), None);
+    }
+
+    #[test]
+    fn lid_reopened_within_hold_does_not_lock() {
+        let mut hold = LidHold::default();
+        hold.start(LOCK.to_vec());
+        // Lid opens before the timer fires
+        assert!(hold.cancel());
+        assert!(!hold.elapsed());
+        assert_eq!(hold.take_confirming(), None);
+    }
+
+    #[test]
+    fn lid_closed_through_hold_locks() {
+        let mut hold = LidHold::default();
+        hold.start(LOCK.to_vec());
+        assert!(hold.elapsed());
+        // The fresh reading still says closed
+        assert_eq!(hold.take_confirming(), Some(LOCK.to_vec()));
+        assert!(matches!(hold, LidHold::Idle));
+    }
+
+    #[test]
+    fn lid_reopened_while_confirming_does_not_lock() {
+        let mut hold = LidHold::default();
+        hold.start(LOCK.to_vec());
+        assert!(hold.elapsed());
+        assert!(hold.cancel());
+        assert_eq!(hold.take_confirming(), None);
+    }
+
+    #[test]
+    fn withdraw_only_takes_a_waiting_hold() {
+        let mut hold = LidHold::default();
+        assert_eq!(hold.withdraw(), None);
+        hold.start(LOCK.to_vec());
+        assert_eq!(hold.withdraw(), Some(LOCK.to_vec()));
+        assert!(!hold.cancel());
+
+        hold.start(LOCK.to_vec());
+        hold.elapsed();
+        assert_eq!(hold.withdraw(), None);
+        assert!(hold.cancel());
+    }
+
+    #[test]
+    fn throttle_suppresses_then_recovers() {
+        let mut throttle = Throttle::default();
+        let start = Instant::now();
+        assert_eq!(throttle.check(start, 2), ThrottleCheck::Allowed);
+        assert_eq!(throttle.check(start + Duration::from_secs(1), 2), ThrottleCheck::Allowed);
+        assert_eq!(throttle.check(start + Duration::from_secs(2), 2), ThrottleCheck::Started(2));
+        assert_eq!(throttle.check(start + Duration::from_secs(3), 2), ThrottleCheck::Suppressed);
+        // The first run is a minute old, making room for one more
+        assert_eq!(throttle.check(start + THROTTLE_WINDOW, 2), ThrottleCheck::Recovered);
+        assert_eq!(throttle.check(start + THROTTLE_WINDOW, 2), ThrottleCheck::Started(2));
+    }
+
+    #[test]
+    fn battery_fires_once_until_recharged() {
+        let config = LowBatteryConfig::default();
+        let mut battery = BatteryArm::default();
+        assert_eq!(battery.update(50, &config), BatteryCheck::Unchanged);
+        assert_eq!(battery.update(15, &config), BatteryCheck::Fired);
+        assert_eq!(battery.update(14, &config), BatteryCheck::Unchanged);
+        // Hovering between the thresholds doesn't re-arm
+        assert_eq!(battery.update(17, &config), BatteryCheck::Unchanged);
+        assert_eq!(battery.update(15, &config), BatteryCheck::Unchanged);
+        assert_eq!(battery.update(20, &config), BatteryCheck::Rearmed);
+        assert_eq!(battery.update(10, &config), BatteryCheck::Fired);
+    }
+
+    #[test]
+    fn battery_skipped_crossing_fires_again() {
+        let config = LowBatteryConfig::default();
+        let mut battery = BatteryArm::default();
+        // A first reading already below the threshold, skipped right after startup
+        assert_eq!(battery.update(10, &config), BatteryCheck::Fired);
+        battery.release();
+        assert_eq!(battery.update(9, &config), BatteryCheck::Fired);
+        assert_eq!(battery.update(8, &config), BatteryCheck::Unchanged);
+    }
+
+    #[test]
+    fn presence_absent_while_any_input_is() {
+        let mut presence = PresenceEvaluator::default();
+        assert_eq!(presence.update(PresenceInput::Lid, false), PresenceUpdate::Unchanged);
+        assert_eq!(
+            presence.update(PresenceInput::Lid, true),
+            PresenceUpdate::Changed(UserPresence::Absent)
+        );
+        assert_eq!(
+            presence.update(PresenceInput::Idle, true),
+            PresenceUpdate::Same(UserPresence::Absent)
+        );
+        assert_eq!(presence.update(PresenceInput::Idle, true), PresenceUpdate::Unchanged);
+        assert_eq!(presence.absent_inputs(), [PresenceInput::Lid, PresenceInput::Idle]);
+        assert_eq!(
+            presence.update(PresenceInput::Lid, false),
+            PresenceUpdate::Same(UserPresence::Absent)
+        );
+        assert_eq!(
+            presence.update(PresenceInput::Idle, false),
+            PresenceUpdate::Changed(UserPresence::Present)
+        );
+        assert!(presence.absent_inputs().is_empty());
+    }
+
+    #[test]
+    fn gate_disarmed_stops_everything() {
+        let mut gate = Gate::new(false);
+        assert_eq!(gate.check_armed(), Err(SkipReason::Disarmed));
+        assert!(gate.set_armed(true));
+        assert!(!gate.set_armed(true));
+        assert_eq!(gate.check_armed(), Ok(()));
+    }
+
+    #[test]
+    fn gate_pause_lets_manual_locks_through() {
+        let mut gate = Gate::new(true);
+        assert!(gate.set_paused(true));
+        assert_eq!(gate.check_paused(Trigger::LidSwitch), Err(SkipReason::Paused));
+        assert_eq!(gate.check_paused(Trigger::Manual), Ok(()));
+        // A pause outlives a disarm and re-arm
+        gate.set_armed(false);
+        gate.set_armed(true);
+        assert!(gate.paused());
+        assert!(gate.set_paused(false));
+        assert_eq!(gate.check_paused(Trigger::LidSwitch), Ok(()));
+    }
+
+    fn pipeline(hold: bool, max_locks_per_minute: Option<usize>) -> (Pipeline, Instant) {
+        let config = PipelineConfig {
+            min_uptime: Duration::from_secs(5),
+            coalesce_window: Duration::from_secs(2),
+            lid_hold: hold,
+            max_locks_per_minute,
+            clamshell: false,
+        };
+        let start = Instant::now();
+        (Pipeline::new(config, true, start), start)
+    }
+
+    fn fire(pipeline: &mut Pipeline, trigger: Trigger, state: u32, now: Instant) -> Step {
+        let event = PipelineEvent::Trigger {
+            trigger,
+            state,
+            actions: LOCK.to_vec(),
+        };
+        pipeline.step(event, now, |_| None)
+    }
+
+    fn acts(held: bool) -> Step {
+        Step::Act {
+            actions: LOCK.to_vec(),
+            held,
+            recovered: false,
+        }
+    }
+
+    #[test]
+    fn pipeline_lid_reopened_within_grace_does_not_lock() {
+        let (mut pipeline, start) = pipeline(true, None);
+        let now = start + Duration::from_secs(10);
+        assert_eq!(fire(&mut pipeline, Trigger::LidSwitch, 0, now), Step::StartHold);
+        assert_eq!(pipeline.state(), PipelineState::GracePending);
+        assert_eq!(
+            fire(&mut pipeline, Trigger::LidSwitch, 1, now + Duration::from_secs(3)),
+            Step::Released {
+                lid_opened: true,
+                hold_cancelled: true,
+                unconfirmed: false,
+            }
+        );
+        assert_eq!(pipeline.state(), PipelineState::Idle);
+        // The timer of the cancelled hold may still fire
+        assert_eq!(pipeline.step(PipelineEvent::HoldElapsed, now, |_| None), Step::Ignore);
+    }
+
+    #[test]
+    fn pipeline_lid_closed_through_grace_locks_once_confirmed() {
+        let (mut pipeline, start) = pipeline(true, None);
+        let now = start + Duration::from_secs(10);
+        assert_eq!(fire(&mut pipeline, Trigger::LidSwitch, 0, now), Step::StartHold);
+        // The monitor turning off with the lid doesn't lock past the hold
+        let monitor_off = now + Duration::from_secs(1);
+        assert!(matches!(
+            fire(&mut pipeline, Trigger::MonitorPower, 0, monitor_off),
+            Step::Coalesced { previous: Trigger::LidSwitch, .. }
+        ));
+        assert_eq!(pipeline.step(PipelineEvent::HoldElapsed, now, |_| None), Step::ConfirmLid);
+        assert_eq!(pipeline.state(), PipelineState::Confirming);
+        // The fresh reading reports the lid still closed
+        assert_eq!(fire(&mut pipeline, Trigger::LidSwitch, 0, now), acts(true));
+        assert_eq!(pipeline.state(), PipelineState::Idle);
+    }
+
+    #[test]
+    fn pipeline_lid_found_open_when_confirming_does_not_lock() {
+        let (mut pipeline, start) = pipeline(true, None);
+        let now = start + Duration::from_secs(10);
+        assert_eq!(fire(&mut pipeline, Trigger::LidSwitch, 0, now), Step::StartHold);
+        assert_eq!(pipeline.step(PipelineEvent::HoldElapsed, now, |_| None), Step::ConfirmLid);
+        assert_eq!(
+            fire(&mut pipeline, Trigger::LidSwitch, 1, now),
+            Step::Released {
+                lid_opened: true,
+                hold_cancelled: false,
+                unconfirmed: true,
+            }
+        );
+        assert_eq!(pipeline.step(PipelineEvent::LidUnconfirmed, now, |_| None), Step::Ignore);
+    }
+
+    #[test]
+    fn pipeline_hold_without_a_fresh_reading_locks() {
+        let (mut pipeline, start) = pipeline(true, None);
+        let now = start + Duration::from_secs(10);
+        assert_eq!(fire(&mut pipeline, Trigger::LidSwitch, 0, now), Step::StartHold);
+        assert_eq!(pipeline.held(), Some(LOCK));
+        assert_eq!(pipeline.step(PipelineEvent::HoldElapsed, now, |_| None), Step::ConfirmLid);
+        assert_eq!(pipeline.step(PipelineEvent::LidUnconfirmed, now, |_| None), acts(true));
+        assert_eq!(pipeline.held(), None);
+    }
+
+    #[test]
+    fn pipeline_declined_hold_does_not_lock() {
+        let (mut pipeline, start) = pipeline(true, None);
+        let now = start + Duration::from_secs(10);
+        assert_eq!(fire(&mut pipeline, Trigger::LidSwitch, 0, now), Step::StartHold);
+        assert_eq!(
+            pipeline.step(PipelineEvent::HoldDeclined, now, |_| None),
+            Step::Declined(LOCK.to_vec())
+        );
+        assert_eq!(pipeline.step(PipelineEvent::HoldElapsed, now, |_| None), Step::Ignore);
+    }
+
+    #[test]
+    fn pipeline_lid_only_locks_on_the_close() {
+        let (mut pipeline, start) = pipeline(false, None);
+        let now = start + Duration::from_secs(10);
+        assert_eq!(fire(&mut pipeline, Trigger::LidSwitch, 0, now), acts(false));
+        let later = now + Duration::from_secs(10);
+        assert_eq!(
+            fire(&mut pipeline, Trigger::LidSwitch, 0, later),
+            Step::Skip(SkipReason::LidUnchanged)
+        );
+        // Re-checked after unlock, the same reading counts again
+        pipeline.expect_recheck(true);
+        assert_eq!(fire(&mut pipeline, Trigger::LidSwitch, 0, later), acts(false));
+        assert!(!pipeline.rechecking());
+    }
+
+    #[test]
+    fn pipeline_skipped_trigger_does_not_coalesce_the_next() {
+        let (mut pipeline, start) = pipeline(false, None);
+        let now = start + Duration::from_secs(10);
+        let event = PipelineEvent::Trigger {
+            trigger: Trigger::LidSwitch,
+            state: 0,
+            actions: LOCK.to_vec(),
+        };
+        let step = pipeline.step(event, now, |_| Some(SkipReason::Schedule));
+        assert_eq!(step, Step::Skip(SkipReason::Schedule));
+        assert_eq!(fire(&mut pipeline, Trigger::MonitorPower, 0, now), acts(false));
+        assert!(matches!(
+            fire(&mut pipeline, Trigger::Idle, 0, now),
+            Step::Coalesced { previous: Trigger::MonitorPower, .. }
+        ));
+        assert_eq!(fire(&mut pipeline, Trigger::Manual, 0, now), acts(false));
+    }
+
+    #[test]
+    fn pipeline_checks_run_in_order() {
+        let (mut pipeline, start) = pipeline(false, None);
+        // Inside min_uptime, only a manual lock gets through
+        assert_eq!(fire(&mut pipeline, Trigger::Idle, 0, start), Step::Skip(SkipReason::Startup));
+        let mut checked = false;
+        let event = PipelineEvent::Trigger {
+            trigger: Trigger::Manual,
+            state: 0,
+            actions: Vec::new(),
+        };
+        let step = pipeline.step(event, start, |_| {
+            checked = true;
+            None
+        });
+        assert_eq!(step, Step::Skip(SkipReason::NoActions));
+        assert!(checked);
+
+        let now = start + Duration::from_secs(10);
+        pipeline.gate_mut().set_paused(true);
+        assert_eq!(pipeline.state(), PipelineState::Paused);
+        let step = pipeline.step(
+            PipelineEvent::Trigger {
+                trigger: Trigger::Idle,
+                state: 0,
+                actions: LOCK.to_vec(),
+            },
+            now,
+            |_| unreachable!("checked while paused"),
+        );
+        assert_eq!(step, Step::Skip(SkipReason::Paused));
+        assert_eq!(fire(&mut pipeline, Trigger::Manual, 0, now), acts(false));
+
+        pipeline.gate_mut().set_armed(false);
+        assert_eq!(pipeline.state(), PipelineState::Disarmed);
+        assert_eq!(fire(&mut pipeline, Trigger::Manual, 0, now), Step::Skip(SkipReason::Disarmed));
+    }
+
+    #[test]
+    fn pipeline_throttles_then_recovers() {
+        let (mut pipeline, start) = pipeline(false, Some(1));
+        let now = start + Duration::from_secs(10);
+        assert_eq!(fire(&mut pipeline, Trigger::Manual, 0, now), acts(false));
+        assert_eq!(
+            fire(&mut pipeline, Trigger::Manual, 0, now),
+            Step::Throttled { started: Some(1) }
+        );
+        assert_eq!(pipeline.state(), PipelineState::Throttled);
+        assert_eq!(fire(&mut pipeline, Trigger::Manual, 0, now), Step::Throttled { started: None });
+        assert_eq!(
+            fire(&mut pipeline, Trigger::Manual, 0, now + THROTTLE_WINDOW),
+            Step::Act {
+                actions: LOCK.to_vec(),
+                held: false,
+                recovered: true,
+            }
+        );
+        assert_eq!(pipeline.state(), PipelineState::Idle);
+    }
+}
@@ -1,239 +1,465 @@
 #![windows_subsystem = "windows"]
 
-use std::sync::Mutex;
-use std::fs::OpenOptions;
-use std::io::Write;
-use windows::Win32::Foundation::*;
-use windows::Win32::System::Power::*;
-use windows::Win32::UI::WindowsAndMessaging::*;
-use windows::Win32::System::LibraryLoader::GetModuleHandleW;
-use windows::Win32::System::SystemServices::{GUID_MONITOR_POWER_ON, GUID_LIDSWITCH_STATE_CHANGE};
-use windows::Win32::System::Shutdown::LockWorkStation;
-use windows::Win32::System::Threading::CreateMutexW;
-
-const APP_NAME: &str = "lidlock";
-const SINGLETON_IDENTIFIER: &str = "Global\\{3DA16D16-5F02-4CFD-8C43-11C31127889D}";
-const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
-
-struct Logger {
-    file: Option<Mutex<std::fs::File>>,
-}
+use lidlock::{
+    arm_running_instance, dump_running_instance, overlay_running_instance, protect_secret, recent_stats, self_test,
+    stop_running_instance, tail_running_instance, ClamshellPolicy, Config, ConfigOverlay, Decision, LidLockWindow,
+    LockAction, Logger, SingletonHandle,
+};
+use std::path::PathBuf;
+use std::time::Duration;
+use windows::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
 
-impl Logger {
-    fn new(path: Option<&str>) -> Self {
-        let file = path.and_then(|p| {
-            OpenOptions::new()
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(p)
-                .ok()
-                .map(|f| Mutex::new(f))
-        });
-        Logger { file }
+fn main() -> windows::core::Result<()> {
+    let mut args = std::env::args().skip(1).peekable();
+    let mut debug = false;
+    let mut run_self_test = false;
+    let mut stop = false;
+    let mut arm: Option<bool> = None;
+    let mut overrides = Overrides::default();
+    let mut once = false;
+    let mut print_config = false;
+    let mut foreground = false;
+    let mut allow_multiple = false;
+    let mut stats_days: Option<u32> = None;
+    let mut dump_path: Option<PathBuf> = None;
+    let mut tail_lines: Option<usize> = None;
+    // `Some(None)` drops the running instance's overlay
+    let mut overlay: Option<Option<ConfigOverlay>> = None;
+    let mut protect_path: Option<PathBuf> = None;
+    let mut config_path: Option<PathBuf> = None;
+    let mut custom_log_path: Option<String> = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--debug" => debug = true,
+            "--self-test" => run_self_test = true,
+            "--stop" => stop = true,
+            "--arm" => arm = Some(true),
+            "--disarm" => arm = Some(false),
+            "--disarmed" => overrides.start_disarmed = true,
+            "--dry-run" => overrides.dry_run = true,
+            "--action" => overrides.action = Some(flag_value(&arg, args.next(), parse_action)),
+            "--grace" => overrides.grace_seconds = Some(flag_value(&arg, args.next(), |value| value.parse().ok())),
+            "--skip-if-external-display" => overrides.skip_if_external_display = true,
+            "--trigger" => overrides.triggers.push(flag_value(&arg, args.next(), parse_trigger)),
+            "--once" => once = true,
+            "--print-config" => print_config = true,
+            "--foreground" => foreground = true,
+            "--allow-multiple" => allow_multiple = true,
+            // The day count is optional, so only consume the next argument if it is one
+            "--stats" => {
+                let days = args.next_if(|next| next.parse::<u32>().is_ok());
+                stats_days = Some(days.and_then(|days| days.parse().ok()).unwrap_or(7));
+            }
+            // The path is optional too, anything that isn't another flag is taken as one
+            "--dump" => {
+                let path = args.next_if(|next| !next.starts_with("--")).map(PathBuf::from);
+                // The instance won't overwrite a file, so the default name differs on each run
+                dump_path = Some(path.unwrap_or_else(|| {
+                    let name = format!("lidlock-events-{}.jsonl", chrono::Local::now().format("%Y%m%d-%H%M%S"));
+                    std::env::temp_dir().join(name)
+                }));
+            }
+            "--log-tail" => {
+                let lines = args.next_if(|next| next.parse::<usize>().is_ok());
+                tail_lines = Some(lines.and_then(|lines| lines.parse().ok()).unwrap_or(50));
+            }
+            // `--overlay <minutes> [setting=value ...]`, or `--overlay off`
+            "--overlay" => {
+                if args.next_if_eq("off").is_some() {
+                    overlay = Some(None);
+                } else {
+                    let mut settings = ConfigOverlay {
+                        minutes: flag_value(&arg, args.next(), |value| value.parse().ok().filter(|&minutes| minutes > 0)),
+                        ..ConfigOverlay::default()
+                    };
+                    while let Some(setting) = args.next_if(|next| !next.starts_with("--") && next.contains('=')) {
+                        apply_overlay_setting(&mut settings, &setting);
+                    }
+                    overlay = Some(Some(settings));
+                }
+            }
+            "--protect-secret" => protect_path = args.next().map(PathBuf::from),
+            "--config" => config_path = args.next().map(PathBuf::from),
+            // If a specific path was provided, use that
+            _ => {
+                if custom_log_path.is_none() {
+                    custom_log_path = Some(arg);
+                }
+            }
+        }
+    }
+
+    // Determine log path based on arguments
+    let log_path = if debug {
+        // Get temp dir and append our log file name
+        let temp_path = std::env::temp_dir().join("lidlock.log");
+        temp_path.to_string_lossy().into_owned()
+    } else {
+        custom_log_path.unwrap_or_default()
+    };
+    let log_path = if log_path.is_empty() { None } else { Some(log_path.as_str()) };
+
+    let config_path = Config::resolve_path(config_path);
+
+    if stop {
+        attach_console();
+        let code = match stop_running_instance(Duration::from_secs(5)) {
+            Ok(true) => {
+                println!("Stopped running lidlock instance");
+                0
+            }
+            Ok(false) => {
+                println!("No running lidlock instance found");
+                2
+            }
+            Err(e) => {
+                println!("Failed to stop lidlock: {}", e.message());
+                1
+            }
+        };
+        std::process::exit(code);
     }
 
-    fn log(&self, message: &str) {
-        if let Some(file) = &self.file {
-            if let Ok(mut file_guard) = file.lock() {
-                let now = chrono::Local::now();
-                let timestamp = now.format(TIME_FORMAT);
-                let log_line = format!("[{}] {}\n", timestamp, message);
-                let _ = file_guard.write_all(log_line.as_bytes());
-                let _ = file_guard.flush();
+    if let Some(armed) = arm {
+        attach_console();
+        let code = match arm_running_instance(armed) {
+            Ok(true) => {
+                println!("Asked the running lidlock instance to {}", if armed { "arm" } else { "disarm" });
+                0
+            }
+            Ok(false) => {
+                println!("No running lidlock instance found");
+                2
+            }
+            Err(e) => {
+                println!("Failed to reach lidlock: {}", e.message());
+                1
+            }
+        };
+        std::process::exit(code);
+    }
+
+    if let Some(path) = protect_path {
+        attach_console();
+        std::process::exit(write_protected_secret(&path));
+    }
+
+    if let Some(path) = dump_path {
+        attach_console();
+        // The running instance resolves relative paths against its own directory
+        let path = std::path::absolute(&path).unwrap_or(path);
+        let code = match dump_running_instance(&path, Duration::from_secs(5)) {
+            Ok(Some(count)) => {
+                println!("Wrote {} recent events to {}", count, path.display());
+                0
+            }
+            Ok(None) => {
+                println!("No running lidlock instance found");
+                2
+            }
+            Err(e) => {
+                println!("Failed to dump recent events: {}", e.message());
+                1
+            }
+        };
+        std::process::exit(code);
+    }
+
+    if let Some(overlay) = overlay {
+        attach_console();
+        let code = match overlay_running_instance(overlay.as_ref(), Duration::from_secs(5)) {
+            Ok(true) => {
+                match &overlay {
+                    Some(overlay) => println!("Applied the overlay for {} minutes", overlay.minutes),
+                    None => println!("Dropped the overlay"),
+                }
+                0
+            }
+            Ok(false) => {
+                println!("No running lidlock instance found");
+                2
+            }
+            Err(e) => {
+                println!("Failed to apply the overlay: {}", e.message());
+                1
+            }
+        };
+        std::process::exit(code);
+    }
+
+    if let Some(lines) = tail_lines {
+        attach_console();
+        let code = match tail_running_instance(lines, Duration::from_secs(5)) {
+            Ok(Some(text)) => {
+                print!("{}", text);
+                0
+            }
+            Ok(None) => {
+                println!("No running lidlock instance found");
+                2
+            }
+            Err(e) => {
+                println!("Failed to read the log: {}", e.message());
+                1
+            }
+        };
+        std::process::exit(code);
+    }
+
+    if print_config {
+        attach_console();
+        std::process::exit(print_effective_config(config_path.as_deref(), &overrides));
+    }
+
+    if let Some(days) = stats_days {
+        attach_console();
+        std::process::exit(print_stats(config_path.as_deref(), days));
+    }
+
+    if run_self_test {
+        attach_console();
+        let steps = self_test(log_path, config_path.as_deref());
+        let healthy = steps.iter().all(|step| step.result.is_ok());
+        for step in steps {
+            match step.result {
+                Ok(detail) => println!("[PASS] {}: {}", step.name, detail),
+                Err(error) => println!("[FAIL] {}: {}", step.name, error),
             }
         }
+        std::process::exit(if healthy { 0 } else { 1 });
     }
-}
 
-struct LidLockWindow {
-    hwnd: HWND,
-    logger: Logger,
-}
+    let logger = Logger::new(log_path).with_debug(debug);
+    logger.log("Main started");
 
-impl LidLockWindow {
-    fn new(logger: Logger) -> windows::core::Result<Self> {
-        logger.log("Creating LidLockWindow");
-        
-        unsafe {
-            let instance = GetModuleHandleW(None)?;
-            
-            logger.log("Registering window class");
-            let wc = WNDCLASSEXW {
-                cbSize: std::mem::size_of::<WNDCLASSEXW>() as u32,
-                lpfnWndProc: Some(Self::window_proc),
-                hInstance: instance,
-                lpszClassName: windows::core::PCWSTR(wide_string(APP_NAME).as_ptr()),
-                ..Default::default()
-            };
-
-            if RegisterClassExW(&wc) == 0 {
-                return Err(windows::core::Error::from_win32());
-            }
-
-            logger.log("Creating window");
-            let hwnd = CreateWindowExW(
-                WINDOW_EX_STYLE(0),
-                windows::core::PCWSTR(wide_string(APP_NAME).as_ptr()),
-                None,
-                WINDOW_STYLE(0),
-                CW_USEDEFAULT,
-                CW_USEDEFAULT,
-                CW_USEDEFAULT,
-                CW_USEDEFAULT,
-                HWND_MESSAGE,
-                None,
-                instance,
-                None,
-            );
-
-            if hwnd.0 == 0 {
-                return Err(windows::core::Error::from_win32());
-            }
-
-            let window = LidLockWindow { hwnd, logger };
-            window.register_notifications()?;
-
-            Ok(window)
-        }
-    }
-
-    fn register_notifications(&self) -> windows::core::Result<()> {
-        unsafe {
-            self.logger.log("Registering power notifications");
-            
-            let handle = HANDLE(self.hwnd.0);
-            
-            if RegisterPowerSettingNotification(
-                handle,
-                &GUID_MONITOR_POWER_ON,
-                DEVICE_NOTIFY_WINDOW_HANDLE.0 as u32,
-            ).is_err() {
-                self.logger.log("Failed to register GUID_MONITOR_POWER_ON notification");
-                return Err(windows::core::Error::from_win32());
-            }
-
-            if RegisterPowerSettingNotification(
-                handle,
-                &GUID_LIDSWITCH_STATE_CHANGE,
-                DEVICE_NOTIFY_WINDOW_HANDLE.0 as u32,
-            ).is_err() {
-                self.logger.log("Failed to register GUID_LIDSWITCH_STATE_CHANGE notification");
-                return Err(windows::core::Error::from_win32());
-            }
-
-            Ok(())
-        }
-    }
-
-    fn run(&self) -> windows::core::Result<()> {
-        self.logger.log("Starting message loop");
-        
-        unsafe {
-            let mut msg = MSG::default();
-            while GetMessageW(&mut msg, HWND(0), 0, 0).as_bool() {
-                TranslateMessage(&msg);
-                DispatchMessageW(&msg);
-            }
-            Ok(())
-        }
-    }
-
-    unsafe extern "system" fn window_proc(
-        hwnd: HWND,
-        msg: u32,
-        wparam: WPARAM,
-        lparam: LPARAM,
-    ) -> LRESULT {
-        let logger = Logger::new(None);
-
-        match msg {
-            WM_POWERBROADCAST => {
-                logger.log("Received WM_POWERBROADCAST");
-                
-                if wparam.0 == PBT_POWERSETTINGCHANGE as usize {
-                    logger.log("Received PBT_POWERSETTINGCHANGE");
-                    
-                    let setting = &*(lparam.0 as *const POWERBROADCAST_SETTING);
-                    let state = *(setting.Data.as_ptr() as *const u32);
-                    
-                    logger.log(&format!("Power setting state: {}", state));
-
-                    if state == 0 {
-                        if GetSystemMetrics(SM_REMOTESESSION) == 0 {
-                            logger.log("Attempting to lock workstation");
-                            
-                            if LockWorkStation().as_bool() {
-                                logger.log("Workstation locked successfully");
-                            } else {
-                                logger.log("Failed to lock workstation");
-                            }
-                        } else {
-                            logger.log("Ignoring, session is remote");
-                        }
-                    } else {
-                        logger.log("Ignoring non-zero state");
-                    }
+    let config = match config_path {
+        Some(path) => {
+            logger.log(&format!("Loading config from {}", path.display()));
+            match Config::load(&path) {
+                Ok(config) => config,
+                Err(e) => {
+                    logger.error(&e);
+                    return Err(windows::core::Error::new(
+                        windows::Win32::Foundation::E_INVALIDARG,
+                        e.as_str().into(),
+                    ));
                 }
             }
-            _ => return DefWindowProcW(hwnd, msg, wparam, lparam),
         }
-        LRESULT(0)
+        None => Config::default(),
+    };
+    let config = overrides.apply(config);
+    let mut logger = logger.with_format(config.log_format).with_failure_policy(config.on_log_failure);
+    if !config.log_sinks.is_empty() {
+        logger = logger.with_sinks(&config.log_sinks);
+    }
+    if let Some(template) = &config.log_template {
+        logger = logger.with_template(template);
     }
+    if let Some(batching) = &config.log_batching {
+        logger = logger.with_batching(batching);
+    }
+    if foreground {
+        logger = logger.with_window_sink();
+    }
+
+    // A one-shot run may happen alongside the background instance
+    if once {
+        let window = LidLockWindow::new_once(logger, config)?;
+        let decision = window.run_once()?;
+        // Dropped explicitly since exit() skips destructors and buffered log lines
+        drop(window);
+        std::process::exit(match decision {
+            Decision::Locked => 0,
+            Decision::Skipped(_) => 2,
+            Decision::Failed | Decision::Deferred => 1,
+        });
+    }
+
+    let singleton = if allow_multiple {
+        logger.warn("Single-instance protection is disabled by --allow-multiple; other instances may act on the same events");
+        None
+    } else {
+        Some(SingletonHandle::new()?)
+    };
+
+    let window = LidLockWindow::new(logger, config)?;
+    if let Some(singleton) = singleton {
+        window.hold_singleton(singleton);
+    }
+    if foreground {
+        window.show_diagnostic_window()?;
+    }
+    window.run()
 }
 
-pub struct SingletonHandle {
-    _mutex: Mutex<()>,
+/// Power triggers that can be picked with `--trigger`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PowerTrigger {
+    Lid,
+    Monitor,
 }
 
-impl SingletonHandle {
-    pub fn new() -> windows::core::Result<Self> {
-        unsafe {
-            let _mutex = CreateMutexW(
-                None,
-                false,
-                windows::core::PCWSTR(wide_string(SINGLETON_IDENTIFIER).as_ptr()),
-            )?;
+/// Settings given on the command line; they win over the config file.
+#[derive(Default)]
+struct Overrides {
+    dry_run: bool,
+    action: Option<LockAction>,
+    grace_seconds: Option<u64>,
+    skip_if_external_display: bool,
+    start_disarmed: bool,
+    // Empty keeps the configured triggers
+    triggers: Vec<PowerTrigger>,
+}
 
-            if GetLastError() == ERROR_ALREADY_EXISTS {
-                return Err(windows::core::Error::new(
-                    windows::core::HRESULT(0x800700B7u32 as i32),
-                    "Application instance already exists".into(),
-                ));
-            }
+impl Overrides {
+    fn apply(&self, mut config: Config) -> Config {
+        config.dry_run |= self.dry_run;
+        config.start_disarmed |= self.start_disarmed;
+        if let Some(action) = self.action {
+            config.action = action;
+            config.actions = None;
+        }
+        if let Some(seconds) = self.grace_seconds {
+            config.lid_closed_seconds = seconds;
+        }
+        if self.skip_if_external_display {
+            let clamshell = config.clamshell.get_or_insert_with(ClamshellPolicy::default);
+            clamshell.ac_with_external_display = Some(Vec::new());
+            clamshell.battery_with_external_display = Some(Vec::new());
+        }
+        if !self.triggers.is_empty() {
+            config.triggers.lid_switch = self.triggers.contains(&PowerTrigger::Lid);
+            config.triggers.monitor_power = self.triggers.contains(&PowerTrigger::Monitor);
+        }
+        config
+    }
+}
 
-            Ok(SingletonHandle {
-                _mutex: Mutex::new(()),
-            })
+// Exits on a missing or invalid value rather than run with other settings than asked for
+fn flag_value<T>(flag: &str, value: Option<String>, parse: impl Fn(&str) -> Option<T>) -> T {
+    match value.as_deref().and_then(parse) {
+        Some(value) => value,
+        None => {
+            attach_console();
+            println!("Invalid or missing value for {}: {}", flag, value.unwrap_or_default());
+            std::process::exit(1);
         }
     }
 }
 
-fn wide_string(s: &str) -> Vec<u16> {
-    s.encode_utf16().chain(std::iter::once(0)).collect()
+fn parse_action(value: &str) -> Option<LockAction> {
+    serde_json::from_value(serde_json::Value::String(value.to_string())).ok()
 }
 
-fn main() -> windows::core::Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    
-    // Determine log path based on arguments
-    let log_path = if args.iter().any(|arg| arg == "--debug") {
-        // Get temp dir and append our log file name
-        let temp_path = std::env::temp_dir().join("lidlock.log");
-        temp_path.to_string_lossy().into_owned()
-    } else {
-        // If a specific path was provided as first arg, use that
-        args.get(1)
-            .filter(|arg| *arg != "--debug")
-            .map(|s| s.to_string())
-            .unwrap_or_default()
+fn parse_trigger(value: &str) -> Option<PowerTrigger> {
+    match value {
+        "lid" | "lid_switch" => Some(PowerTrigger::Lid),
+        "monitor" | "monitor_power" => Some(PowerTrigger::Monitor),
+        _ => None,
+    }
+}
+
+// Exits on anything it doesn't understand, like `flag_value`
+fn apply_overlay_setting(overlay: &mut ConfigOverlay, setting: &str) {
+    let (name, value) = setting.split_once('=').unwrap_or((setting, ""));
+    let applied = match name {
+        "ignore" => serde_json::from_value(serde_json::Value::String(value.to_string()))
+            .map(|trigger| overlay.ignore_triggers.push(trigger))
+            .is_ok(),
+        "idle_seconds" => value.parse().map(|seconds| overlay.idle_seconds = Some(seconds)).is_ok(),
+        _ => false,
     };
+    if !applied {
+        attach_console();
+        println!("Invalid overlay setting: {}", setting);
+        std::process::exit(1);
+    }
+}
 
-    let logger = Logger::new(if log_path.is_empty() { None } else { Some(log_path.as_str()) });
-    logger.log("Main started");
+fn print_effective_config(config_path: Option<&std::path::Path>, overrides: &Overrides) -> i32 {
+    let config = match config_path.map(Config::load).transpose() {
+        Ok(config) => overrides.apply(config.unwrap_or_default()),
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    match serde_json::to_string_pretty(&config) {
+        Ok(json) => {
+            println!("{}", json);
+            0
+        }
+        Err(e) => {
+            println!("Failed to serialize config: {}", e);
+            1
+        }
+    }
+}
 
-    let _singleton = SingletonHandle::new()?;
+fn print_stats(config_path: Option<&std::path::Path>, days: u32) -> i32 {
+    let config = match config_path.map(Config::load).transpose() {
+        Ok(config) => config.unwrap_or_default(),
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    let Some(path) = config.stats_file else {
+        println!("No stats_file configured");
+        return 1;
+    };
 
-    let window = LidLockWindow::new(logger)?;
-    window.run()
-}
\ No newline at end of file
+    match recent_stats(&path, days) {
+        Ok(stats) => {
+            for (date, triggers) in stats {
+                let locked: u32 = triggers.values().map(|counts| counts.locked).sum();
+                println!("{}  {} locks", date, locked);
+                for (trigger, counts) in triggers {
+                    println!(
+                        "    {:<14} locked {:>3}  skipped {:>3}  failed {:>3}",
+                        trigger, counts.locked, counts.skipped, counts.failed
+                    );
+                }
+            }
+            0
+        }
+        Err(e) => {
+            println!("Failed to read stats: {}", e);
+            1
+        }
+    }
+}
+
+// Reads the secret from stdin so it never appears on a command line
+fn write_protected_secret(path: &std::path::Path) -> i32 {
+    let mut value = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut value) {
+        println!("Failed to read the secret from stdin: {}", e);
+        return 1;
+    }
+    let value = value.trim_end_matches(['\r', '\n']);
+    if value.is_empty() {
+        println!("No secret given on stdin");
+        return 1;
+    }
+
+    match protect_secret(value).and_then(|blob| std::fs::write(path, blob).map_err(|e| e.to_string())) {
+        Ok(()) => {
+            println!("Wrote protected secret to {}", path.display());
+            0
+        }
+        Err(e) => {
+            println!("Failed to protect secret: {}", e);
+            1
+        }
+    }
+}
+
+// The binary uses the windows subsystem, so console output needs the parent's console
+fn attach_console() {
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use windows::Win32::Devices::Sensors::*;
+use windows::Win32::Foundation::{HWND, LPARAM, WPARAM};
+use windows::Win32::System::Com::{CoCreateInstance, CoInitializeEx, CoUninitialize, CLSCTX_INPROC_SERVER, COINIT_MULTITHREADED};
+use windows::Win32::UI::Shell::PropertiesSystem::{PropVariantToDouble, PROPERTYKEY};
+use windows::Win32::UI::WindowsAndMessaging::{PostMessageW, WM_APP};
+
+use crate::config::MotionConfig;
+use crate::describe_error;
+use crate::logger::Logger;
+
+/// Posted to the owner when the machine moved more than `threshold_g`.
+pub(crate) const WM_MOTION: u32 = WM_APP + 11;
+
+const AXES: [PROPERTYKEY; 3] = [
+    SENSOR_DATA_TYPE_ACCELERATION_X_G,
+    SENSOR_DATA_TYPE_ACCELERATION_Y_G,
+    SENSOR_DATA_TYPE_ACCELERATION_Z_G,
+];
+
+/// Polls the first accelerometer on a thread of its own and posts `WM_MOTION`
+/// when consecutive readings differ by at least `threshold_g`, at most once per
+/// `debounce_ms`.
+pub(crate) struct MotionWatcher {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MotionWatcher {
+    /// Fails if there is no accelerometer, after which nothing is polled.
+    pub(crate) fn start(owner: HWND, config: &MotionConfig, logger: Arc<Logger>) -> Result<Self, String> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (ready, started) = mpsc::channel();
+        let thread_stop = Arc::clone(&stop);
+        let config = config.clone();
+        let owner = owner.0;
+        let thread = std::thread::spawn(move || unsafe {
+            // The sensor objects live in this thread's apartment
+            if let Err(e) = CoInitializeEx(None, COINIT_MULTITHREADED) {
+                let _ = ready.send(Err(describe_error(&e)));
+                return;
+            }
+            match find_accelerometer() {
+                Ok(sensor) => {
+                    let _ = ready.send(Ok(()));
+                    poll(&sensor, HWND(owner), &config, &thread_stop, &logger);
+                }
+                Err(e) => {
+                    let _ = ready.send(Err(e));
+                }
+            }
+            CoUninitialize();
+        });
+
+        match started.recv() {
+            Ok(Ok(())) => Ok(MotionWatcher {
+                stop,
+                thread: Some(thread),
+            }),
+            Ok(Err(e)) => {
+                let _ = thread.join();
+                Err(e)
+            }
+            Err(_) => Err("motion thread exited".to_string()),
+        }
+    }
+}
+
+impl Drop for MotionWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+unsafe fn find_accelerometer() -> Result<ISensor, String> {
+    let manager: ISensorManager =
+        CoCreateInstance(&SensorManager, None, CLSCTX_INPROC_SERVER).map_err(|e| describe_error(&e))?;
+    // Fails with HRESULT_FROM_WIN32(ERROR_NOT_FOUND) when there is none
+    let sensors = manager
+        .GetSensorsByType(&SENSOR_TYPE_ACCELEROMETER_3D)
+        .map_err(|_| "no accelerometer found".to_string())?;
+    if sensors.GetCount().map_err(|e| describe_error(&e))? == 0 {
+        return Err("no accelerometer found".to_string());
+    }
+    sensors.GetAt(0).map_err(|e| describe_error(&e))
+}
+
+unsafe fn read_acceleration(sensor: &ISensor) -> windows::core::Result<[f64; 3]> {
+    let report = sensor.GetData()?;
+    let mut reading = [0.0; 3];
+    for (value, axis) in reading.iter_mut().zip(&AXES) {
+        *value = PropVariantToDouble(&report.GetSensorValue(axis)?)?;
+    }
+    Ok(reading)
+}
+
+unsafe fn poll(sensor: &ISensor, owner: HWND, config: &MotionConfig, stop: &AtomicBool, logger: &Logger) {
+    let interval = Duration::from_millis(config.poll_ms.max(1));
+    let debounce = Duration::from_millis(config.debounce_ms);
+    let mut previous: Option<[f64; 3]> = None;
+    let mut last_posted: Option<Instant> = None;
+    let mut failing = false;
+
+    while !stop.load(Ordering::Relaxed) {
+        std::thread::sleep(interval);
+        let reading = match read_acceleration(sensor) {
+            Ok(reading) => {
+                failing = false;
+                reading
+            }
+            Err(e) => {
+                // Once per run of failures, e.g. while the sensor is disabled
+                if !std::mem::replace(&mut failing, true) {
+                    logger.warn(&format!("Failed to read the accelerometer: {}", describe_error(&e)));
+                }
+                previous = None;
+                continue;
+            }
+        };
+
+        let Some(last) = previous.replace(reading) else {
+            continue;
+        };
+        let change = reading.iter().zip(&last).map(|(now, then)| (now - then).powi(2)).sum::<f64>().sqrt();
+        if change < config.threshold_g {
+            continue;
+        }
+        logger.debug(&format!("Motion detected: {:.2}g change", change));
+        if last_posted.is_some_and(|posted| posted.elapsed() < debounce) {
+            continue;
+        }
+        last_posted = Some(Instant::now());
+        PostMessageW(owner, WM_MOTION, WPARAM(0), LPARAM(0));
+    }
+}
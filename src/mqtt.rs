@@ -0,0 +1,129 @@
+use rumqttc::{Client, Event, MqttOptions, Outgoing, Packet, QoS};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::MqttConfig;
+use crate::logger::Logger;
+use crate::{hostname, APP_NAME};
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Publishes lock state transitions to a retained MQTT topic: `idle` while the
+/// user is away from an unlocked session, `locked` and `unlocked`.
+///
+/// The connection is driven on a background thread that reconnects with backoff,
+/// so publishing never blocks the message loop or waits for the broker.
+pub(crate) struct MqttPublisher {
+    client: Client,
+    topic: String,
+    state: Arc<Mutex<&'static str>>,
+    stopping: Arc<AtomicBool>,
+    logger: Arc<Logger>,
+}
+
+impl MqttPublisher {
+    pub(crate) fn start(config: &MqttConfig, logger: Arc<Logger>) -> Self {
+        let client_id = config
+            .client_id
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}", APP_NAME, hostname()));
+        let mut options = MqttOptions::new(client_id, config.host.clone(), config.port);
+        options.set_keep_alive(KEEP_ALIVE);
+        if let Some(username) = &config.username {
+            let password = config
+                .password
+                .as_ref()
+                .and_then(|password| password.resolve_logged("mqtt.password", &logger));
+            options.set_credentials(username.clone(), password.unwrap_or_default());
+        }
+
+        let (client, mut connection) = Client::new(options, 10);
+        let state = Arc::new(Mutex::new("idle"));
+
+        logger.log(&format!("Connecting to MQTT broker {}:{}", config.host, config.port));
+
+        let thread_client = client.clone();
+        let thread_topic = config.topic.clone();
+        let thread_state = Arc::clone(&state);
+        let thread_logger = Arc::clone(&logger);
+        let stopping = Arc::new(AtomicBool::new(false));
+        let thread_stopping = Arc::clone(&stopping);
+        std::thread::spawn(move || {
+            let mut delay = Duration::from_secs(1);
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        thread_logger.log("Connected to MQTT broker");
+                        delay = Duration::from_secs(1);
+                        // Republish so the retained topic is correct after a broker outage
+                        let current = *thread_state.lock().unwrap();
+                        if let Err(e) = thread_client.try_publish(
+                            thread_topic.as_str(),
+                            QoS::AtLeastOnce,
+                            true,
+                            current,
+                        ) {
+                            thread_logger.log(&format!("Failed to publish MQTT state: {}", e));
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::PubAck(ack))) => {
+                        thread_logger.log(&format!("MQTT publish {} acknowledged", ack.pkid));
+                    }
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                    Ok(_) => {}
+                    // The disconnect from stop() ends the connection, don't reconnect
+                    Err(_) if thread_stopping.load(Ordering::SeqCst) => break,
+                    Err(e) => {
+                        thread_logger.log(&format!(
+                            "MQTT connection error: {}, reconnecting in {}s",
+                            e,
+                            delay.as_secs()
+                        ));
+                        std::thread::sleep(delay);
+                        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                    }
+                }
+            }
+        });
+
+        MqttPublisher {
+            client,
+            topic: config.topic.clone(),
+            state,
+            stopping,
+            logger,
+        }
+    }
+
+    /// Disconnects from the broker and ends the connection thread.
+    pub(crate) fn stop(&self) {
+        if self.stopping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        match self.client.try_disconnect() {
+            Ok(()) => self.logger.log("Disconnecting from MQTT broker"),
+            Err(e) => self.logger.log(&format!("Failed to disconnect from MQTT broker: {}", e)),
+        }
+    }
+
+    /// Publishes `state` unless it is already the current one.
+    pub(crate) fn publish(&self, state: &'static str) {
+        if std::mem::replace(&mut *self.state.lock().unwrap(), state) == state {
+            return;
+        }
+        match self.client.try_publish(self.topic.as_str(), QoS::AtLeastOnce, true, state) {
+            Ok(()) => self.logger.log(&format!("Queued MQTT state '{}' on {}", state, self.topic)),
+            Err(e) => self.logger.log(&format!("Failed to publish MQTT state '{}': {}", state, e)),
+        }
+    }
+
+    /// Goes back to `unlocked` once the user is back, unless the session was locked meanwhile.
+    pub(crate) fn leave_idle(&self) {
+        let idle = *self.state.lock().unwrap() == "idle";
+        if idle {
+            self.publish("unlocked");
+        }
+    }
+}
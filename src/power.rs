@@ -0,0 +1,109 @@
+use windows::Win32::Devices::Display::*;
+use windows::Win32::Foundation::ERROR_SUCCESS;
+use windows::Win32::System::Power::{GetSystemPowerStatus, SYSTEM_POWER_STATUS};
+use windows::Win32::UI::WindowsAndMessaging::{GetSystemMetrics, SM_CONVERTIBLESLATEMODE, SM_TABLETPC};
+
+/// Where the machine is drawing power from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PowerSource {
+    Ac,
+    Battery,
+    Unknown,
+}
+
+impl PowerSource {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            PowerSource::Ac => "ac",
+            PowerSource::Battery => "battery",
+            PowerSource::Unknown => "unknown",
+        }
+    }
+}
+
+pub(crate) fn power_source() -> PowerSource {
+    let mut status = SYSTEM_POWER_STATUS::default();
+    if !unsafe { GetSystemPowerStatus(&mut status) }.as_bool() {
+        return PowerSource::Unknown;
+    }
+    match status.ACLineStatus {
+        0 => PowerSource::Battery,
+        1 => PowerSource::Ac,
+        _ => PowerSource::Unknown,
+    }
+}
+
+/// Whether a convertible is folded into tablet mode. Devices that aren't
+/// convertibles report laptop mode.
+pub(crate) fn in_tablet_mode() -> bool {
+    let (slate_mode, tablet_pc) = unsafe {
+        (GetSystemMetrics(SM_CONVERTIBLESLATEMODE), GetSystemMetrics(SM_TABLETPC))
+    };
+    // SM_CONVERTIBLESLATEMODE is 0 in slate mode, but can also be 0 on devices
+    // that can't be folded at all
+    slate_mode == 0 && tablet_pc != 0
+}
+
+/// Counts active display paths that don't end at a built-in panel.
+pub(crate) fn external_display_count() -> Result<usize, String> {
+    Ok(active_display_paths()?.iter().filter(|path| is_external(path)).count())
+}
+
+/// GDI device names (e.g. `\\.\DISPLAY2`) of the sources driving external displays,
+/// matching `MONITORINFOEXW::szDevice`.
+pub(crate) fn external_display_devices() -> Result<Vec<String>, String> {
+    let mut devices = Vec::new();
+    for path in active_display_paths()?.iter().filter(|path| is_external(path)) {
+        let mut request = DISPLAYCONFIG_SOURCE_DEVICE_NAME {
+            header: DISPLAYCONFIG_DEVICE_INFO_HEADER {
+                r#type: DISPLAYCONFIG_DEVICE_INFO_GET_SOURCE_NAME,
+                size: std::mem::size_of::<DISPLAYCONFIG_SOURCE_DEVICE_NAME>() as u32,
+                adapterId: path.sourceInfo.adapterId,
+                id: path.sourceInfo.id,
+            },
+            ..Default::default()
+        };
+        if unsafe { DisplayConfigGetDeviceInfo(&mut request.header) } == 0 {
+            let name = &request.viewGdiDeviceName;
+            let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+            devices.push(String::from_utf16_lossy(&name[..len]));
+        }
+    }
+    Ok(devices)
+}
+
+fn is_external(path: &DISPLAYCONFIG_PATH_INFO) -> bool {
+    !matches!(
+        path.targetInfo.outputTechnology,
+        DISPLAYCONFIG_OUTPUT_TECHNOLOGY_INTERNAL
+            | DISPLAYCONFIG_OUTPUT_TECHNOLOGY_DISPLAYPORT_EMBEDDED
+            | DISPLAYCONFIG_OUTPUT_TECHNOLOGY_UDI_EMBEDDED
+    )
+}
+
+fn active_display_paths() -> Result<Vec<DISPLAYCONFIG_PATH_INFO>, String> {
+    unsafe {
+        let mut path_count = 0u32;
+        let mut mode_count = 0u32;
+        let error = GetDisplayConfigBufferSizes(QDC_ONLY_ACTIVE_PATHS, &mut path_count, &mut mode_count);
+        if error != ERROR_SUCCESS {
+            return Err(format!("GetDisplayConfigBufferSizes failed: {}", error.0));
+        }
+
+        let mut paths = vec![DISPLAYCONFIG_PATH_INFO::default(); path_count as usize];
+        let mut modes = vec![DISPLAYCONFIG_MODE_INFO::default(); mode_count as usize];
+        let error = QueryDisplayConfig(
+            QDC_ONLY_ACTIVE_PATHS,
+            &mut path_count,
+            paths.as_mut_ptr(),
+            &mut mode_count,
+            modes.as_mut_ptr(),
+            None,
+        );
+        if error != ERROR_SUCCESS {
+            return Err(format!("QueryDisplayConfig failed: {}", error.0));
+        }
+        paths.truncate(path_count as usize);
+        Ok(paths)
+    }
+}
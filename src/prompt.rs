@@ -0,0 +1,249 @@
+use std::cell::Cell;
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::*;
+use windows::Win32::Graphics::Gdi::*;
+use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+use windows::Win32::UI::Input::KeyboardAndMouse::{VK_ESCAPE, VK_N, VK_RETURN, VK_Y};
+use windows::Win32::UI::WindowsAndMessaging::*;
+
+use crate::config::{PromptConfig, PromptDefault};
+use crate::power::external_display_devices;
+use crate::{wide_string, APP_NAME};
+
+pub(crate) const PROMPT_TIMER_ID: usize = 4;
+/// Posted to the owner with wparam 1 to lock or 0 to keep working.
+pub(crate) const WM_PROMPT_RESULT: u32 = WM_APP + 4;
+
+const PROMPT_CLASS_NAME: &str = "lidlock_prompt";
+const ID_LOCK: usize = 1;
+const ID_KEEP: usize = 2;
+const WIDTH: i32 = 380;
+const HEIGHT: i32 = 150;
+
+/// Topmost "Lock anyway?" window shown on an external display when the lid closes.
+///
+/// The answer is posted to the owner as `WM_PROMPT_RESULT`; the owner's
+/// `PROMPT_TIMER_ID` timer fires if nobody answers in time.
+pub(crate) struct ConfirmPrompt {
+    config: PromptConfig,
+    owner: HWND,
+    window: Cell<HWND>,
+}
+
+impl ConfirmPrompt {
+    pub(crate) fn new(config: PromptConfig, owner: HWND) -> windows::core::Result<Self> {
+        unsafe {
+            let class_name = wide_string(PROMPT_CLASS_NAME);
+            let wc = WNDCLASSEXW {
+                cbSize: std::mem::size_of::<WNDCLASSEXW>() as u32,
+                lpfnWndProc: Some(prompt_proc),
+                hInstance: GetModuleHandleW(None)?,
+                hCursor: LoadCursorW(None, IDC_ARROW)?,
+                hbrBackground: HBRUSH((COLOR_BTNFACE.0 + 1) as isize),
+                lpszClassName: PCWSTR(class_name.as_ptr()),
+                ..Default::default()
+            };
+            if RegisterClassExW(&wc) == 0 {
+                return Err(windows::core::Error::from_win32());
+            }
+        }
+
+        Ok(ConfirmPrompt {
+            config,
+            owner,
+            window: Cell::new(HWND(0)),
+        })
+    }
+
+    pub(crate) fn default_locks(&self) -> bool {
+        self.config.default == PromptDefault::Lock
+    }
+
+    pub(crate) fn show(&self) -> windows::core::Result<()> {
+        self.close();
+        unsafe {
+            let instance = GetModuleHandleW(None)?;
+            let area = target_work_area();
+            let class_name = wide_string(PROMPT_CLASS_NAME);
+            let title = wide_string(APP_NAME);
+            let window = CreateWindowExW(
+                WS_EX_TOPMOST | WS_EX_TOOLWINDOW,
+                PCWSTR(class_name.as_ptr()),
+                PCWSTR(title.as_ptr()),
+                WS_POPUP | WS_CAPTION | WS_VISIBLE,
+                area.left + (area.right - area.left - WIDTH) / 2,
+                area.top + (area.bottom - area.top - HEIGHT) / 2,
+                WIDTH,
+                HEIGHT,
+                None,
+                None,
+                instance,
+                None,
+            );
+            if window.0 == 0 {
+                return Err(windows::core::Error::from_win32());
+            }
+            SetWindowLongPtrW(window, GWLP_USERDATA, self.owner.0);
+            self.window.set(window);
+
+            let fallback = match self.config.default {
+                PromptDefault::Lock => "Locking",
+                PromptDefault::KeepWorking => "Not locking",
+            };
+            let text = format!(
+                "The lid was closed. Lock anyway?\n{} in {} seconds if there is no answer.",
+                fallback, self.config.timeout_seconds
+            );
+            create_child(window, "STATIC", &text, WINDOW_STYLE(0), 15, 12, WIDTH - 30, 40, 0)?;
+            create_child(window, "BUTTON", "&Yes, lock", WINDOW_STYLE(BS_DEFPUSHBUTTON as u32), 15, 65, 160, 30, ID_LOCK)?;
+            create_child(window, "BUTTON", "&No, keep working", WINDOW_STYLE(BS_PUSHBUTTON as u32), 190, 65, 160, 30, ID_KEEP)?;
+
+            // Keyboard answers only work once the prompt has focus
+            SetForegroundWindow(window);
+
+            if SetTimer(self.owner, PROMPT_TIMER_ID, self.config.timeout_seconds.saturating_mul(1000), None) == 0 {
+                return Err(windows::core::Error::from_win32());
+            }
+            Ok(())
+        }
+    }
+
+    /// Removes the prompt if it is shown; returns whether it was.
+    pub(crate) fn close(&self) -> bool {
+        let window = self.window.replace(HWND(0));
+        unsafe {
+            KillTimer(self.owner, PROMPT_TIMER_ID);
+            if window.0 != 0 {
+                DestroyWindow(window);
+            }
+        }
+        window.0 != 0
+    }
+}
+
+impl Drop for ConfirmPrompt {
+    fn drop(&mut self) {
+        self.close();
+        unsafe {
+            if let Ok(instance) = GetModuleHandleW(None) {
+                let class_name = wide_string(PROMPT_CLASS_NAME);
+                UnregisterClassW(PCWSTR(class_name.as_ptr()), instance);
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) unsafe fn create_child(
+    parent: HWND,
+    class: &str,
+    text: &str,
+    style: WINDOW_STYLE,
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+    id: usize,
+) -> windows::core::Result<HWND> {
+    let class = wide_string(class);
+    let text = wide_string(text);
+    let child = CreateWindowExW(
+        WINDOW_EX_STYLE(0),
+        PCWSTR(class.as_ptr()),
+        PCWSTR(text.as_ptr()),
+        WS_CHILD | WS_VISIBLE | style,
+        x,
+        y,
+        width,
+        height,
+        parent,
+        HMENU(id as isize),
+        GetModuleHandleW(None)?,
+        None,
+    );
+    if child.0 == 0 {
+        return Err(windows::core::Error::from_win32());
+    }
+    Ok(child)
+}
+
+// Prefers the work area of a monitor driven by an external display and falls
+// back to the primary monitor
+fn target_work_area() -> RECT {
+    let external = external_display_devices().unwrap_or_default();
+    let mut monitors: Vec<MONITORINFOEXW> = Vec::new();
+    unsafe {
+        EnumDisplayMonitors(
+            None,
+            None,
+            Some(collect_monitor),
+            LPARAM(&mut monitors as *mut Vec<MONITORINFOEXW> as isize),
+        );
+    }
+
+    let device_name = |info: &MONITORINFOEXW| {
+        let len = info.szDevice.iter().position(|&c| c == 0).unwrap_or(info.szDevice.len());
+        String::from_utf16_lossy(&info.szDevice[..len])
+    };
+    monitors
+        .iter()
+        .find(|info| external.contains(&device_name(info)))
+        .or_else(|| {
+            monitors
+                .iter()
+                .find(|info| info.monitorInfo.dwFlags & MONITORINFOF_PRIMARY != 0)
+        })
+        .map(|info| info.monitorInfo.rcWork)
+        .unwrap_or(RECT {
+            left: 0,
+            top: 0,
+            right: unsafe { GetSystemMetrics(SM_CXSCREEN) },
+            bottom: unsafe { GetSystemMetrics(SM_CYSCREEN) },
+        })
+}
+
+unsafe extern "system" fn collect_monitor(
+    monitor: HMONITOR,
+    _hdc: HDC,
+    _rect: *mut RECT,
+    data: LPARAM,
+) -> BOOL {
+    let monitors = &mut *(data.0 as *mut Vec<MONITORINFOEXW>);
+    let mut info = MONITORINFOEXW::default();
+    info.monitorInfo.cbSize = std::mem::size_of::<MONITORINFOEXW>() as u32;
+    if GetMonitorInfoW(monitor, &mut info.monitorInfo).as_bool() {
+        monitors.push(info);
+    }
+    TRUE
+}
+
+// Posts the answer once; later clicks or keys find no owner and do nothing
+unsafe fn answer(hwnd: HWND, lock: bool) {
+    let owner = SetWindowLongPtrW(hwnd, GWLP_USERDATA, 0);
+    if owner != 0 {
+        PostMessageW(HWND(owner), WM_PROMPT_RESULT, WPARAM(lock as usize), LPARAM(0));
+    }
+}
+
+unsafe extern "system" fn prompt_proc(
+    hwnd: HWND,
+    msg: u32,
+    wparam: WPARAM,
+    lparam: LPARAM,
+) -> LRESULT {
+    match msg {
+        WM_COMMAND => match wparam.0 & 0xffff {
+            ID_LOCK => answer(hwnd, true),
+            ID_KEEP => answer(hwnd, false),
+            _ => {}
+        },
+        WM_KEYDOWN => match wparam.0 as u16 {
+            key if key == VK_Y.0 || key == VK_RETURN.0 => answer(hwnd, true),
+            key if key == VK_N.0 || key == VK_ESCAPE.0 => answer(hwnd, false),
+            _ => {}
+        },
+        WM_CLOSE => answer(hwnd, false),
+        _ => return DefWindowProcW(hwnd, msg, wparam, lparam),
+    }
+    LRESULT(0)
+}
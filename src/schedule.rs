@@ -0,0 +1,214 @@
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+
+use crate::event::Trigger;
+
+/// Daily time range written as `HH:MM-HH:MM`; the end may be earlier than the
+/// start for ranges that cross midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl From<TimeWindow> for String {
+    fn from(window: TimeWindow) -> Self {
+        format!("{}-{}", window.start.format("%H:%M"), window.end.format("%H:%M"))
+    }
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (start, end) = value
+            .split_once('-')
+            .ok_or_else(|| format!("expected HH:MM-HH:MM, got '{}'", value))?;
+        Ok(TimeWindow {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        })
+    }
+}
+
+/// Time of day written as `HH:MM`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(pub NaiveTime);
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        time.0.format("%H:%M").to_string()
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        parse_time(&value).map(TimeOfDay)
+    }
+}
+
+/// Lock at a fixed time of day, on the given weekdays or every day if none are listed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ScheduledLock {
+    pub at: TimeOfDay,
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+}
+
+impl ScheduledLock {
+    /// Whether the lock's most recent time up to `to` falls after `from`.
+    pub fn due(&self, from: NaiveDateTime, to: NaiveDateTime) -> bool {
+        let mut at = to.date().and_time(self.at.0);
+        if at > to {
+            at -= chrono::Duration::days(1);
+        }
+        at > from && (self.days.is_empty() || self.days.contains(&at.weekday()))
+    }
+}
+
+/// Rules for one day of the week, replacing the top-level ones on that day.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DaySchedule {
+    /// Never lock while the local time is inside one of these windows.
+    pub disable_between: Vec<TimeWindow>,
+    /// Only these triggers act on this day; all of them if unset.
+    pub triggers: Option<Vec<Trigger>>,
+}
+
+impl DaySchedule {
+    pub fn allows(&self, trigger: Trigger) -> bool {
+        self.triggers.as_ref().is_none_or(|triggers| triggers.contains(&trigger))
+    }
+}
+
+/// Per-weekday rules; days without an entry use the top-level `disable_between`.
+/// Each entry covers its day from midnight to midnight.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WeeklySchedule {
+    #[serde(rename = "Mon", skip_serializing_if = "Option::is_none")]
+    pub mon: Option<DaySchedule>,
+    #[serde(rename = "Tue", skip_serializing_if = "Option::is_none")]
+    pub tue: Option<DaySchedule>,
+    #[serde(rename = "Wed", skip_serializing_if = "Option::is_none")]
+    pub wed: Option<DaySchedule>,
+    #[serde(rename = "Thu", skip_serializing_if = "Option::is_none")]
+    pub thu: Option<DaySchedule>,
+    #[serde(rename = "Fri", skip_serializing_if = "Option::is_none")]
+    pub fri: Option<DaySchedule>,
+    #[serde(rename = "Sat", skip_serializing_if = "Option::is_none")]
+    pub sat: Option<DaySchedule>,
+    #[serde(rename = "Sun", skip_serializing_if = "Option::is_none")]
+    pub sun: Option<DaySchedule>,
+}
+
+impl WeeklySchedule {
+    pub fn day(&self, weekday: Weekday) -> Option<&DaySchedule> {
+        match weekday {
+            Weekday::Mon => self.mon.as_ref(),
+            Weekday::Tue => self.tue.as_ref(),
+            Weekday::Wed => self.wed.as_ref(),
+            Weekday::Thu => self.thu.as_ref(),
+            Weekday::Fri => self.fri.as_ref(),
+            Weekday::Sat => self.sat.as_ref(),
+            Weekday::Sun => self.sun.as_ref(),
+        }
+    }
+}
+
+fn parse_time(s: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(s.trim(), "%H:%M").map_err(|e| format!("invalid time '{}': {}", s.trim(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    // 2024-01-01 was a Monday
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap().and_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn lock_at(time: &str, days: Vec<Weekday>) -> ScheduledLock {
+        ScheduledLock {
+            at: TimeOfDay::try_from(time.to_string()).unwrap(),
+            days,
+        }
+    }
+
+    #[test]
+    fn scheduled_lock_due_once_between_polls() {
+        let lock = lock_at("22:00", Vec::new());
+        assert!(!lock.due(at(1, 21, 58), at(1, 21, 59)));
+        assert!(lock.due(at(1, 21, 59), at(1, 22, 0)));
+        assert!(!lock.due(at(1, 22, 0), at(1, 22, 1)));
+        // Polls further apart still see a time between them
+        assert!(lock.due(at(1, 21, 0), at(2, 8, 0)));
+    }
+
+    #[test]
+    fn scheduled_lock_only_on_listed_days() {
+        let lock = lock_at("00:30", vec![Weekday::Tue]);
+        // Crossing midnight into Tuesday
+        assert!(lock.due(at(1, 23, 59), at(2, 0, 30)));
+        assert!(!lock.due(at(2, 23, 59), at(3, 0, 30)));
+    }
+
+    #[test]
+    fn day_schedule_allows_listed_triggers() {
+        let day = DaySchedule::default();
+        assert!(day.allows(Trigger::Idle));
+        let day = DaySchedule {
+            triggers: Some(vec![Trigger::LidSwitch]),
+            ..DaySchedule::default()
+        };
+        assert!(day.allows(Trigger::LidSwitch));
+        assert!(!day.allows(Trigger::Idle));
+    }
+
+    #[test]
+    fn weekly_schedule_reads_day_tables() {
+        let weekly: WeeklySchedule = toml::from_str(
+            r#"
+            [Sat]
+            triggers = ["lid_switch"]
+
+            [Sun]
+            disable_between = ["00:00-12:00"]
+            "#,
+        )
+        .unwrap();
+        assert!(weekly.day(Weekday::Mon).is_none());
+        let saturday = weekly.day(Weekday::Sat).unwrap();
+        assert!(saturday.allows(Trigger::LidSwitch));
+        assert!(!saturday.allows(Trigger::Idle));
+        let sunday = weekly.day(Weekday::Sun).unwrap();
+        assert!(sunday.allows(Trigger::Idle));
+        assert!(sunday.disable_between[0].contains(at(7, 11, 59).time()));
+    }
+
+    #[test]
+    fn time_window_across_midnight() {
+        let window = TimeWindow::try_from("22:00-06:00".to_string()).unwrap();
+        assert!(window.contains(at(1, 23, 0).time()));
+        assert!(window.contains(at(1, 5, 59).time()));
+        assert!(!window.contains(at(1, 6, 0).time()));
+        assert!(TimeWindow::try_from("22:00".to_string()).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize, Serializer};
+use std::path::PathBuf;
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::HLOCAL;
+use windows::Win32::Security::Cryptography::*;
+use windows::Win32::System::Memory::LocalFree;
+
+use crate::logger::Logger;
+
+/// A credential in the config. Written as a plain string it is used as is;
+/// `{ file = "..." }` reads it from a file that can have a tighter ACL than the
+/// config, and `{ dpapi_file = "..." }` decrypts a file written by
+/// `lidlock --protect-secret`, which only the same Windows user can read.
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Secret {
+    Inline(String),
+    File { file: PathBuf },
+    Dpapi { dpapi_file: PathBuf },
+}
+
+// Config is logged and printed with {:?}, which must never show the value
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Secret::Inline(_) => f.write_str("Inline(..)"),
+            Secret::File { file } => f.debug_struct("File").field("file", file).finish(),
+            Secret::Dpapi { dpapi_file } => f.debug_struct("Dpapi").field("dpapi_file", dpapi_file).finish(),
+        }
+    }
+}
+
+// Printing the config shows references but never an inline value
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        #[serde(untagged)]
+        enum Redacted<'a> {
+            Inline(&'static str),
+            File { file: &'a PathBuf },
+            Dpapi { dpapi_file: &'a PathBuf },
+        }
+        match self {
+            Secret::Inline(_) => Redacted::Inline("<redacted>"),
+            Secret::File { file } => Redacted::File { file },
+            Secret::Dpapi { dpapi_file } => Redacted::Dpapi { dpapi_file },
+        }
+        .serialize(serializer)
+    }
+}
+
+impl Secret {
+    pub(crate) fn resolve(&self) -> Result<String, String> {
+        match self {
+            Secret::Inline(value) => Ok(value.clone()),
+            Secret::File { file } => std::fs::read_to_string(file)
+                .map(|value| value.trim_end_matches(['\r', '\n']).to_string())
+                .map_err(|e| format!("{}: {}", file.display(), e)),
+            Secret::Dpapi { dpapi_file } => std::fs::read(dpapi_file)
+                .map_err(|e| e.to_string())
+                .and_then(|blob| unprotect(&blob))
+                .map_err(|e| format!("{}: {}", dpapi_file.display(), e)),
+        }
+    }
+
+    /// Resolves the secret for the `name` setting, logging where it came from
+    /// but never the value. Returns None, after logging why, if it can't be read.
+    pub(crate) fn resolve_logged(&self, name: &str, logger: &Logger) -> Option<String> {
+        let source = match self {
+            Secret::Inline(_) => None,
+            Secret::File { file } => Some(format!("file {}", file.display())),
+            Secret::Dpapi { dpapi_file } => Some(format!("DPAPI file {}", dpapi_file.display())),
+        };
+        match self.resolve() {
+            Ok(value) => {
+                if let Some(source) = source {
+                    logger.log(&format!("Resolved {} from {}", name, source));
+                }
+                Some(value)
+            }
+            Err(e) => {
+                logger.error(&format!("Failed to resolve {}: {}", name, e));
+                None
+            }
+        }
+    }
+}
+
+/// Encrypts `value` for the current Windows user, for use with `dpapi_file`.
+pub fn protect_secret(value: &str) -> Result<Vec<u8>, String> {
+    let description: Vec<u16> = "lidlock secret".encode_utf16().chain(std::iter::once(0)).collect();
+    let bytes = value.as_bytes();
+    let input = CRYPT_INTEGER_BLOB {
+        cbData: bytes.len() as u32,
+        pbData: bytes.as_ptr() as *mut u8,
+    };
+    let mut output = CRYPT_INTEGER_BLOB::default();
+    unsafe {
+        CryptProtectData(
+            &input,
+            PCWSTR(description.as_ptr()),
+            None,
+            None,
+            None,
+            CRYPTPROTECT_UI_FORBIDDEN,
+            &mut output,
+        )
+        .ok()
+        .map_err(|e| e.message().to_string())?;
+        Ok(take_blob(output))
+    }
+}
+
+fn unprotect(blob: &[u8]) -> Result<String, String> {
+    let input = CRYPT_INTEGER_BLOB {
+        cbData: blob.len() as u32,
+        pbData: blob.as_ptr() as *mut u8,
+    };
+    let mut output = CRYPT_INTEGER_BLOB::default();
+    let bytes = unsafe {
+        CryptUnprotectData(&input, None, None, None, None, CRYPTPROTECT_UI_FORBIDDEN, &mut output)
+            .ok()
+            .map_err(|e| e.message().to_string())?;
+        take_blob(output)
+    };
+    String::from_utf8(bytes).map_err(|_| "decrypted secret is not UTF-8".to_string())
+}
+
+// Copies out a blob allocated by DPAPI and frees it
+unsafe fn take_blob(blob: CRYPT_INTEGER_BLOB) -> Vec<u8> {
+    let bytes = std::slice::from_raw_parts(blob.pbData, blob.cbData as usize).to_vec();
+    let _ = LocalFree(HLOCAL(blob.pbData as isize));
+    bytes
+}
@@ -0,0 +1,90 @@
+use std::fs::OpenOptions;
+use std::path::Path;
+use windows::Win32::System::Power::UnregisterPowerSettingNotification;
+use windows::Win32::UI::WindowsAndMessaging::{DestroyWindow, GetSystemMetrics, SM_REMOTESESSION};
+
+use crate::config::Config;
+use crate::logger::Logger;
+use crate::window::LidLockWindow;
+
+/// Outcome of one `--self-test` check.
+pub struct SelfTestStep {
+    pub name: &'static str,
+    pub result: Result<String, String>,
+}
+
+/// Checks everything lidlock needs at startup without entering the message loop.
+pub fn self_test(log_path: Option<&str>, config_path: Option<&Path>) -> Vec<SelfTestStep> {
+    let mut steps = Vec::new();
+    let logger = Logger::new(None);
+
+    steps.push(SelfTestStep {
+        name: "Log file",
+        result: match log_path {
+            Some(path) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map(|_| path.to_string())
+                .map_err(|e| format!("{}: {}", path, e)),
+            None => Ok("no log file configured".to_string()),
+        },
+    });
+
+    let config = config_path.map(Config::load).transpose();
+    steps.push(SelfTestStep {
+        name: "Config",
+        result: match (&config, config_path) {
+            (Ok(_), Some(path)) => Ok(path.display().to_string()),
+            (Ok(_), None) => Ok("no config file, using defaults".to_string()),
+            (Err(e), _) => Err(e.clone()),
+        },
+    });
+    let triggers = config.ok().flatten().unwrap_or_default().triggers;
+
+    let class_name = LidLockWindow::register_class(&logger);
+    steps.push(SelfTestStep {
+        name: "Window class",
+        result: class_name
+            .as_ref()
+            .map(|name| String::from_utf16_lossy(&name[..name.len() - 1]))
+            .map_err(|e| e.message().to_string()),
+    });
+
+    if let Ok(class_name) = class_name {
+        steps.push(SelfTestStep {
+            name: "Power notifications",
+            result: LidLockWindow::create_window(&class_name, &logger)
+                .and_then(|hwnd| {
+                    let result = LidLockWindow::register_power_notifications(hwnd, &triggers, &logger)
+                        .map(|handles| {
+                            // Only a check, so nothing may stay registered
+                            handles
+                                .into_iter()
+                                .map(|(trigger, handle)| {
+                                    unsafe {
+                                        UnregisterPowerSettingNotification(handle);
+                                    }
+                                    trigger.as_str()
+                                })
+                                .collect::<Vec<_>>()
+                        });
+                    unsafe {
+                        DestroyWindow(hwnd);
+                    }
+                    result
+                })
+                .map(|triggers| format!("registered {}", triggers.join(", ")))
+                .map_err(|e| e.message().to_string()),
+        });
+        LidLockWindow::unregister_class(&class_name);
+    }
+
+    let remote = unsafe { GetSystemMetrics(SM_REMOTESESSION) } != 0;
+    steps.push(SelfTestStep {
+        name: "Session type",
+        result: Ok(if remote { "remote" } else { "local" }.to_string()),
+    });
+
+    steps
+}
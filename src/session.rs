@@ -0,0 +1,44 @@
+use windows::core::PWSTR;
+use windows::Win32::System::RemoteDesktop::*;
+
+// Copies out the fixed-size value returned for `class`; None if it is too short
+unsafe fn query<T: Copy>(class: WTS_INFO_CLASS) -> windows::core::Result<Option<T>> {
+    let mut buffer = PWSTR::null();
+    let mut bytes = 0u32;
+    WTSQuerySessionInformationW(WTS_CURRENT_SERVER_HANDLE, WTS_CURRENT_SESSION, class, &mut buffer, &mut bytes)
+        .ok()?;
+
+    let value = if bytes as usize >= std::mem::size_of::<T>() {
+        Some(*(buffer.as_ptr() as *const T))
+    } else {
+        None
+    };
+    WTSFreeMemory(buffer.as_ptr() as *mut _);
+    Ok(value)
+}
+
+/// Connection state of the session lidlock runs in, e.g. `WTSActive` or `WTSDisconnected`.
+pub(crate) fn connect_state() -> windows::core::Result<WTS_CONNECTSTATE_CLASS> {
+    Ok(unsafe { query(WTSConnectState) }?.unwrap_or(WTSActive))
+}
+
+/// Whether the session lidlock runs in is locked, or None if Windows doesn't say.
+pub(crate) fn is_locked() -> windows::core::Result<Option<bool>> {
+    let info = unsafe { query::<WTSINFOEXW>(WTSSessionInfoEx) }?;
+    let Some(info) = info else {
+        return Ok(None);
+    };
+    if info.Level != 1 {
+        return Ok(None);
+    }
+    Ok(match unsafe { info.Data.WTSInfoExLevel1 }.SessionFlags as u32 {
+        WTS_SESSIONSTATE_LOCK => Some(true),
+        WTS_SESSIONSTATE_UNLOCK => Some(false),
+        _ => None,
+    })
+}
+
+/// Whether nobody can be attached to the session, so locking it would be meaningless.
+pub(crate) fn is_detached(state: WTS_CONNECTSTATE_CLASS) -> bool {
+    [WTSDisconnected, WTSListen, WTSReset, WTSDown, WTSInit].contains(&state)
+}
@@ -0,0 +1,42 @@
+use windows::Win32::Foundation::*;
+use windows::Win32::System::Threading::CreateMutexW;
+
+use crate::wide_string;
+
+const SINGLETON_IDENTIFIER: &str = "Global\\{3DA16D16-5F02-4CFD-8C43-11C31127889D}";
+
+/// Holds the named mutex that keeps a second instance from starting; closing
+/// it, by dropping this, lets the next instance start.
+pub struct SingletonHandle {
+    mutex: HANDLE,
+}
+
+impl SingletonHandle {
+    pub fn new() -> windows::core::Result<Self> {
+        unsafe {
+            let mutex = CreateMutexW(
+                None,
+                false,
+                windows::core::PCWSTR(wide_string(SINGLETON_IDENTIFIER).as_ptr()),
+            )?;
+
+            if GetLastError() == ERROR_ALREADY_EXISTS {
+                CloseHandle(mutex);
+                return Err(windows::core::Error::new(
+                    windows::core::HRESULT(0x800700B7u32 as i32),
+                    "Application instance already exists".into(),
+                ));
+            }
+
+            Ok(SingletonHandle { mutex })
+        }
+    }
+}
+
+impl Drop for SingletonHandle {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.mutex);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+use crate::config::Config;
+use crate::logger::Logger;
+use crate::tray::TrayIcon;
+use crate::APP_NAME;
+
+const TITLE: &str = "lidlock is active";
+const MESSAGE: &str = "Closing the lid will lock your PC.";
+const MARKER_FILE_NAME: &str = "startup_notification_shown";
+
+/// Confirms lidlock is running, through the tray icon when there is one and
+/// otherwise only in the log.
+pub(crate) fn show_startup_notification(config: &Config, tray: Option<&TrayIcon>, logger: &Logger) {
+    if !config.show_startup_notification {
+        return;
+    }
+    let marker = marker_path();
+    if !config.startup_notification_every_launch && marker.as_ref().is_some_and(|path| path.exists()) {
+        return;
+    }
+
+    let Some(tray) = tray else {
+        logger.log(&format!("{}: {}", TITLE, MESSAGE));
+        return;
+    };
+    if let Err(e) = tray.notify(TITLE, MESSAGE) {
+        logger.log(&format!("Failed to show startup notification: {}", e.message()));
+        return;
+    }
+    logger.log("Startup notification shown");
+
+    // Best effort; at worst the notification shows again next launch
+    if let Some(path) = marker {
+        if let Some(parent) = path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        let _ = std::fs::write(path, chrono::Local::now().to_rfc3339());
+    }
+}
+
+fn marker_path() -> Option<PathBuf> {
+    let local_app_data = std::env::var_os("LOCALAPPDATA")?;
+    Some(PathBuf::from(local_app_data).join(APP_NAME).join(MARKER_FILE_NAME))
+}
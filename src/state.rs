@@ -0,0 +1,16 @@
+use std::path::Path;
+
+use crate::event::LastDecision;
+
+/// Rewrites the state file with the latest decision.
+///
+/// The record goes to a sibling temp file first and is then renamed over the
+/// target, so readers never see a partially written file.
+pub(crate) fn write_state_file(path: &Path, last: &LastDecision) -> std::io::Result<()> {
+    let record = last.to_json();
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    std::fs::write(&temp_path, record.to_string())?;
+    std::fs::rename(&temp_path, path)
+}
@@ -0,0 +1,89 @@
+use chrono::{Days, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::event::{Decision, LockEvent};
+use crate::logger::Logger;
+use crate::writer::BackgroundWriter;
+
+/// Outcome counts for one trigger on one day.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DecisionCounts {
+    pub locked: u32,
+    pub skipped: u32,
+    pub failed: u32,
+}
+
+/// Counts for one day, keyed by trigger.
+pub type DayStats = BTreeMap<String, DecisionCounts>;
+
+// Keyed by ISO date, then trigger, so the file stays readable and sorted
+type Stats = BTreeMap<NaiveDate, DayStats>;
+
+// One decision to count, sent to the recorder thread
+type Record = (NaiveDate, &'static str, Decision);
+
+/// Counts decisions per day and trigger in a JSON file, which is read and
+/// rewritten for every decision.
+pub(crate) struct StatsRecorder {
+    writer: BackgroundWriter<Record>,
+}
+
+impl StatsRecorder {
+    pub(crate) fn start(path: PathBuf, logger: Arc<Logger>) -> Self {
+        StatsRecorder {
+            writer: BackgroundWriter::start(path, logger, "Failed to update stats file", record),
+        }
+    }
+
+    /// Writes out what is queued and ends the thread.
+    pub(crate) fn stop(&self) {
+        self.writer.stop();
+    }
+
+    pub(crate) fn record(&self, event: &LockEvent) {
+        let today = chrono::Local::now().date_naive();
+        self.writer.send((today, event.trigger.as_str(), event.decision));
+    }
+}
+
+fn record(path: &Path, (date, trigger, decision): Record) -> Result<(), String> {
+    let mut stats = load(path)?;
+    let counts = stats.entry(date).or_default().entry(trigger.to_string()).or_default();
+    match decision {
+        Decision::Locked => counts.locked += 1,
+        Decision::Skipped(_) => counts.skipped += 1,
+        Decision::Failed => counts.failed += 1,
+        // Counted once the prompt is answered
+        Decision::Deferred => return Ok(()),
+    }
+
+    let json = serde_json::to_string_pretty(&stats).map_err(|e| e.to_string())?;
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    std::fs::write(&temp_path, json).map_err(|e| e.to_string())?;
+    std::fs::rename(&temp_path, path).map_err(|e| e.to_string())
+}
+
+fn load(path: &Path) -> Result<Stats, String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Stats::new()),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+/// Per-trigger counts for each of the last `days` days, oldest first.
+/// Days without any recorded decision are included with no triggers.
+pub fn recent_stats(path: &Path, days: u32) -> Result<Vec<(NaiveDate, DayStats)>, String> {
+    let stats = load(path)?;
+    let today = chrono::Local::now().date_naive();
+    Ok((0..days)
+        .rev()
+        .filter_map(|offset| today.checked_sub_days(Days::new(offset.into())))
+        .map(|date| (date, stats.get(&date).cloned().unwrap_or_default()))
+        .collect())
+}
@@ -0,0 +1,313 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use windows::Win32::Foundation::{HWND, LPARAM, WPARAM};
+use windows::Win32::Security::Cryptography::{BCryptGenRandom, BCRYPT_ALG_HANDLE, BCRYPT_USE_SYSTEM_PREFERRED_RNG};
+use windows::Win32::UI::WindowsAndMessaging::{PostMessageW, WM_APP};
+
+use crate::control::WM_ARM;
+use crate::event::{LastDecision, SharedLastDecision};
+use crate::history::EventHistory;
+use crate::hostname;
+use crate::logger::Logger;
+use crate::APP_NAME;
+
+/// Posted to the main window for `POST /lock`.
+pub(crate) const WM_REMOTE_LOCK: u32 = WM_APP + 2;
+/// Posted to the main window for `POST /pause` (wparam 1) and `POST /resume` (wparam 0).
+pub(crate) const WM_REMOTE_PAUSE: u32 = WM_APP + 3;
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_HEADER_LINES: usize = 100;
+/// Header every request has to carry with the contents of the token file.
+pub(crate) const TOKEN_HEADER: &str = "x-lidlock-token";
+const TOKEN_FILE_NAME: &str = "status_token";
+const TOKEN_BYTES: usize = 32;
+
+#[derive(Default)]
+struct Status {
+    paused: bool,
+    armed: bool,
+}
+
+/// Loopback-only HTTP server answering `GET /status`, `GET /events`, `POST /lock`,
+/// `POST /pause`, `POST /resume`, `POST /arm` and `POST /disarm`.
+///
+/// Requests are served on a worker thread; anything that has to act is posted to
+/// the main window so it runs on the message loop thread like every other trigger.
+///
+/// Being on loopback doesn't keep browsers out, so every request has to name
+/// `127.0.0.1:<port>` as its `Host` (against DNS rebinding), must not carry an
+/// `Origin` or `Referer` (against cross-site requests) and has to send the
+/// per-install token from [`token_path`] in [`TOKEN_HEADER`].
+pub(crate) struct StatusServer {
+    status: Arc<Mutex<Status>>,
+    port: u16,
+    stopping: Arc<AtomicBool>,
+}
+
+impl StatusServer {
+    pub(crate) fn start(
+        port: u16,
+        hwnd: HWND,
+        last_decision: SharedLastDecision,
+        history: EventHistory,
+        armed: bool,
+        logger: Arc<Logger>,
+    ) -> std::io::Result<Self> {
+        let token_path = token_path().ok_or_else(|| std::io::Error::other("LOCALAPPDATA is not set"))?;
+        let token = load_or_create_token(&token_path)?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        logger.log(&format!(
+            "Status server listening on 127.0.0.1:{}, token in {}",
+            port,
+            token_path.display()
+        ));
+
+        let status = Arc::new(Mutex::new(Status {
+            armed,
+            ..Status::default()
+        }));
+        let shared = Arc::clone(&status);
+        let stopping = Arc::new(AtomicBool::new(false));
+        let thread_stopping = Arc::clone(&stopping);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // Dropping the listener closes the port
+                if thread_stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Err(e) = handle(stream, port, &token, hwnd, &shared, &last_decision, &history, &logger) {
+                    logger.log(&format!("Status server request failed: {}", e));
+                }
+            }
+        });
+
+        Ok(StatusServer { status, port, stopping })
+    }
+
+    /// Stops accepting requests and closes the port.
+    pub(crate) fn stop(&self) {
+        if !self.stopping.swap(true, Ordering::SeqCst) {
+            // Wakes the accept loop so it sees the flag
+            let _ = TcpStream::connect((Ipv4Addr::LOCALHOST, self.port));
+        }
+    }
+
+    pub(crate) fn set_paused(&self, paused: bool) {
+        if let Ok(mut status) = self.status.lock() {
+            status.paused = paused;
+        }
+    }
+
+    pub(crate) fn set_armed(&self, armed: bool) {
+        if let Ok(mut status) = self.status.lock() {
+            status.armed = armed;
+        }
+    }
+}
+
+/// Where the per-install token lives: `%LOCALAPPDATA%\lidlock\status_token`.
+pub(crate) fn token_path() -> Option<PathBuf> {
+    let local_app_data = std::env::var_os("LOCALAPPDATA")?;
+    Some(PathBuf::from(local_app_data).join(APP_NAME).join(TOKEN_FILE_NAME))
+}
+
+/// Reads the token, generating and saving a random one on first use.
+fn load_or_create_token(path: &Path) -> std::io::Result<String> {
+    if let Ok(token) = std::fs::read_to_string(path) {
+        let token = token.trim();
+        if !token.is_empty() {
+            return Ok(token.to_string());
+        }
+    }
+
+    let mut bytes = [0u8; TOKEN_BYTES];
+    unsafe { BCryptGenRandom(BCRYPT_ALG_HANDLE::default(), &mut bytes, BCRYPT_USE_SYSTEM_PREFERRED_RNG) }
+        .map_err(|e| std::io::Error::other(e.message().to_string()))?;
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, &token)?;
+    Ok(token)
+}
+
+/// Checks the request headers (names lowercased) before anything is served.
+fn authorize(headers: &[(String, String)], port: u16, token: &str) -> Result<(), &'static str> {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    };
+    if header("origin").is_some() || header("referer").is_some() {
+        return Err("cross-origin requests are not accepted");
+    }
+    if header("host") != Some(format!("127.0.0.1:{}", port).as_str()) {
+        return Err("unexpected host");
+    }
+    match header(TOKEN_HEADER) {
+        Some(sent) if constant_time_eq(sent.as_bytes(), token.as_bytes()) => Ok(()),
+        _ => Err("missing or wrong token"),
+    }
+}
+
+// Doesn't stop at the first mismatch, so timing doesn't reveal the token
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle(
+    mut stream: TcpStream,
+    port: u16,
+    token: &str,
+    hwnd: HWND,
+    status: &Mutex<Status>,
+    last_decision: &SharedLastDecision,
+    history: &EventHistory,
+    logger: &Logger,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Bodies are never needed, only the headers
+    let mut headers = Vec::new();
+    for _ in 0..MAX_HEADER_LINES {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default();
+    logger.log(&format!("Status server request: {} {}", method, path));
+
+    let (code, body) = match authorize(&headers, port, token) {
+        Ok(()) => route(method, path, hwnd, status, last_decision, history)?,
+        Err(reason) => {
+            logger.warn(&format!("Status server rejected {} {}: {}", method, path, reason));
+            ("403 Forbidden", serde_json::json!({ "error": reason }))
+        }
+    };
+
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code,
+        body.len(),
+        body
+    )
+}
+
+fn route(
+    method: &str,
+    path: &str,
+    hwnd: HWND,
+    status: &Mutex<Status>,
+    last_decision: &SharedLastDecision,
+    history: &EventHistory,
+) -> std::io::Result<(&'static str, serde_json::Value)> {
+    Ok(match (method, path) {
+        ("GET", "/status") => {
+            let status = status
+                .lock()
+                .map_err(|_| std::io::Error::other("status lock poisoned"))?;
+            let last_event = last_decision
+                .read()
+                .map_err(|_| std::io::Error::other("last decision lock poisoned"))?
+                .as_ref()
+                .map(LastDecision::to_json);
+            (
+                "200 OK",
+                serde_json::json!({
+                    "host": hostname(),
+                    "paused": status.paused,
+                    "armed": status.armed,
+                    "last_event": last_event,
+                }),
+            )
+        }
+        ("GET", "/events") => (
+            "200 OK",
+            serde_json::Value::Array(history.snapshot().iter().map(LastDecision::to_json).collect()),
+        ),
+        ("POST", "/lock") => post(hwnd, WM_REMOTE_LOCK, 0),
+        ("POST", "/pause") => post(hwnd, WM_REMOTE_PAUSE, 1),
+        ("POST", "/resume") => post(hwnd, WM_REMOTE_PAUSE, 0),
+        ("POST", "/arm") => post(hwnd, WM_ARM, 1),
+        ("POST", "/disarm") => post(hwnd, WM_ARM, 0),
+        (_, "/status" | "/events" | "/lock" | "/pause" | "/resume" | "/arm" | "/disarm") => (
+            "405 Method Not Allowed",
+            serde_json::json!({ "error": "method not allowed" }),
+        ),
+        _ => ("404 Not Found", serde_json::json!({ "error": "not found" })),
+    })
+}
+
+fn post(hwnd: HWND, msg: u32, wparam: usize) -> (&'static str, serde_json::Value) {
+    match unsafe { PostMessageW(hwnd, msg, WPARAM(wparam), LPARAM(0)) }.ok() {
+        Ok(()) => ("202 Accepted", serde_json::json!({ "accepted": true })),
+        Err(e) => (
+            "500 Internal Server Error",
+            serde_json::json!({ "error": e.message().to_string() }),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "0123abcd";
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn accepts_loopback_host_with_token() {
+        let request = headers(&[("host", "127.0.0.1:8420"), (TOKEN_HEADER, TOKEN)]);
+        assert_eq!(authorize(&request, 8420, TOKEN), Ok(()));
+    }
+
+    #[test]
+    fn rejects_missing_or_wrong_token() {
+        assert!(authorize(&headers(&[("host", "127.0.0.1:8420")]), 8420, TOKEN).is_err());
+        let request = headers(&[("host", "127.0.0.1:8420"), (TOKEN_HEADER, "0123abce")]);
+        assert!(authorize(&request, 8420, TOKEN).is_err());
+    }
+
+    #[test]
+    fn rejects_rebound_host() {
+        for host in ["evil.example:8420", "localhost:8420", "127.0.0.1:8421", "127.0.0.1"] {
+            let request = headers(&[("host", host), (TOKEN_HEADER, TOKEN)]);
+            assert!(authorize(&request, 8420, TOKEN).is_err(), "{}", host);
+        }
+    }
+
+    #[test]
+    fn rejects_browser_requests() {
+        for header in ["origin", "referer"] {
+            let request = headers(&[
+                ("host", "127.0.0.1:8420"),
+                (TOKEN_HEADER, TOKEN),
+                (header, "https://evil.example"),
+            ]);
+            assert!(authorize(&request, 8420, TOKEN).is_err(), "{}", header);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::cell::Cell;
+use windows::Win32::Foundation::HWND;
+use windows::Win32::UI::Shell::*;
+use windows::Win32::UI::WindowsAndMessaging::{LoadIconW, IDI_APPLICATION, WM_APP};
+
+use crate::APP_NAME;
+
+/// Callback message for notification area events.
+pub(crate) const WM_TRAY: u32 = WM_APP + 6;
+
+const TRAY_ICON_ID: u32 = 1;
+
+/// Notification area icon owned by the main window.
+pub(crate) struct TrayIcon {
+    owner: HWND,
+    removed: Cell<bool>,
+}
+
+impl TrayIcon {
+    pub(crate) fn add(owner: HWND) -> windows::core::Result<Self> {
+        let mut data = notify_data(owner);
+        data.uFlags = NIF_ICON | NIF_TIP | NIF_MESSAGE;
+        data.uCallbackMessage = WM_TRAY;
+        data.hIcon = unsafe { LoadIconW(None, IDI_APPLICATION)? };
+        copy_wide(&mut data.szTip, APP_NAME);
+
+        unsafe {
+            Shell_NotifyIconW(NIM_ADD, &data).ok()?;
+            data.Anonymous.uVersion = NOTIFYICON_VERSION_4;
+            Shell_NotifyIconW(NIM_SETVERSION, &data);
+        }
+        Ok(TrayIcon {
+            owner,
+            removed: Cell::new(false),
+        })
+    }
+
+    /// Replaces the text shown when hovering over the icon.
+    pub(crate) fn set_tip(&self, text: &str) -> windows::core::Result<()> {
+        let mut data = notify_data(self.owner);
+        data.uFlags = NIF_TIP;
+        copy_wide(&mut data.szTip, text);
+        unsafe { Shell_NotifyIconW(NIM_MODIFY, &data) }.ok()
+    }
+
+    /// Restores the default tooltip.
+    pub(crate) fn reset_tip(&self) -> windows::core::Result<()> {
+        self.set_tip(APP_NAME)
+    }
+
+    /// Shows a balloon, or a toast on Windows 10 and later, next to the icon.
+    pub(crate) fn notify(&self, title: &str, text: &str) -> windows::core::Result<()> {
+        let mut data = notify_data(self.owner);
+        data.uFlags = NIF_INFO;
+        data.dwInfoFlags = NIIF_INFO;
+        copy_wide(&mut data.szInfoTitle, title);
+        copy_wide(&mut data.szInfo, text);
+        unsafe { Shell_NotifyIconW(NIM_MODIFY, &data) }.ok()
+    }
+
+    /// Takes the icon out of the notification area ahead of drop.
+    pub(crate) fn remove(&self) {
+        if self.removed.replace(true) {
+            return;
+        }
+        let data = notify_data(self.owner);
+        unsafe {
+            Shell_NotifyIconW(NIM_DELETE, &data);
+        }
+    }
+}
+
+impl Drop for TrayIcon {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+fn notify_data(owner: HWND) -> NOTIFYICONDATAW {
+    NOTIFYICONDATAW {
+        cbSize: std::mem::size_of::<NOTIFYICONDATAW>() as u32,
+        hWnd: owner,
+        uID: TRAY_ICON_ID,
+        ..Default::default()
+    }
+}
+
+// Copies as much of `text` as fits, always leaving room for the terminator
+fn copy_wide(buffer: &mut [u16], text: &str) {
+    let len = buffer.len() - 1;
+    for (slot, unit) in buffer[..len].iter_mut().zip(text.encode_utf16().chain(std::iter::repeat(0))) {
+        *slot = unit;
+    }
+    buffer[len] = 0;
+}
@@ -0,0 +1,152 @@
+use windows::Win32::Foundation::{HANDLE, HWND, LPARAM, WPARAM};
+use windows::Win32::NetworkManagement::WiFi::*;
+use windows::Win32::UI::WindowsAndMessaging::{PostMessageW, WM_APP};
+
+use crate::describe_win32_error;
+
+/// Posted when a wireless interface connects or disconnects; wparam is
+/// [`WIFI_CONNECTED`] or [`WIFI_DISCONNECTED`] and lparam a `Box<String>` with
+/// the SSID, which the receiver takes ownership of.
+pub(crate) const WM_WIFI_CHANGED: u32 = WM_APP + 9;
+pub(crate) const WIFI_DISCONNECTED: usize = 0;
+pub(crate) const WIFI_CONNECTED: usize = 1;
+
+// Version 2 is the WLAN API since Vista
+const WLAN_CLIENT_VERSION: u32 = 2;
+
+/// Open handle to the WLAN service. Fails to open on machines without a
+/// wireless adapter, where the service isn't running.
+struct Client(HANDLE);
+
+impl Client {
+    fn open() -> Result<Self, String> {
+        let mut version = 0;
+        let mut handle = HANDLE::default();
+        match unsafe { WlanOpenHandle(WLAN_CLIENT_VERSION, None, &mut version, &mut handle) } {
+            0 => Ok(Client(handle)),
+            code => Err(describe_win32_error(code)),
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Also waits for a notification callback that is still running
+        unsafe {
+            WlanCloseHandle(self.0, None);
+        }
+    }
+}
+
+/// Forwards connect and disconnect notifications to the owner window as
+/// `WM_WIFI_CHANGED`.
+pub(crate) struct WifiWatcher {
+    client: Client,
+}
+
+impl WifiWatcher {
+    pub(crate) fn start(owner: HWND) -> Result<Self, String> {
+        let client = Client::open()?;
+        let status = unsafe {
+            WlanRegisterNotification(
+                client.0,
+                WLAN_NOTIFICATION_SOURCE_ACM,
+                true,
+                Some(notification_callback),
+                Some(owner.0 as *const _),
+                None,
+                None,
+            )
+        };
+        match status {
+            0 => Ok(WifiWatcher { client }),
+            code => Err(describe_win32_error(code)),
+        }
+    }
+}
+
+impl Drop for WifiWatcher {
+    fn drop(&mut self) {
+        unsafe {
+            WlanRegisterNotification(self.client.0, WLAN_NOTIFICATION_SOURCE_NONE, true, None, None, None, None);
+        }
+    }
+}
+
+/// SSID of the first connected wireless interface, or `None` if none is connected.
+pub(crate) fn current_ssid() -> Result<Option<String>, String> {
+    let client = Client::open()?;
+    unsafe {
+        let mut list: *mut WLAN_INTERFACE_INFO_LIST = std::ptr::null_mut();
+        let status = WlanEnumInterfaces(client.0, None, &mut list);
+        if status != 0 {
+            return Err(describe_win32_error(status));
+        }
+        let count = (*list).dwNumberOfItems as usize;
+        let interfaces = std::slice::from_raw_parts((*list).InterfaceInfo.as_ptr(), count);
+
+        let mut ssid = None;
+        for interface in interfaces.iter().filter(|interface| interface.isState == wlan_interface_state_connected) {
+            let mut size = 0;
+            let mut data = std::ptr::null_mut();
+            let status = WlanQueryInterface(
+                client.0,
+                &interface.InterfaceGuid,
+                wlan_intf_opcode_current_connection,
+                None,
+                &mut size,
+                &mut data,
+                None,
+            );
+            if status != 0 {
+                continue;
+            }
+            if size as usize >= std::mem::size_of::<WLAN_CONNECTION_ATTRIBUTES>() {
+                let attributes = std::ptr::read_unaligned(data as *const WLAN_CONNECTION_ATTRIBUTES);
+                ssid = Some(ssid_string(&attributes.wlanAssociationAttributes.dot11Ssid));
+            }
+            WlanFreeMemory(data);
+            if ssid.is_some() {
+                break;
+            }
+        }
+        WlanFreeMemory(list as *const _);
+        Ok(ssid)
+    }
+}
+
+fn ssid_string(ssid: &DOT11_SSID) -> String {
+    let len = (ssid.uSSIDLength as usize).min(ssid.ucSSID.len());
+    String::from_utf8_lossy(&ssid.ucSSID[..len]).into_owned()
+}
+
+// Runs on a WLAN service thread, so it only posts to the owner
+unsafe extern "system" fn notification_callback(data: *mut L2_NOTIFICATION_DATA, context: *mut std::ffi::c_void) {
+    let Some(data) = data.as_ref() else {
+        return;
+    };
+    if data.NotificationSource != WLAN_NOTIFICATION_SOURCE_ACM {
+        return;
+    }
+    let code = WLAN_NOTIFICATION_ACM(data.NotificationCode as i32);
+    let state = if code == wlan_notification_acm_disconnected {
+        WIFI_DISCONNECTED
+    } else if code == wlan_notification_acm_connection_complete {
+        WIFI_CONNECTED
+    } else {
+        return;
+    };
+    // Only the fields up to the SSID are needed
+    let needed = std::mem::offset_of!(WLAN_CONNECTION_NOTIFICATION_DATA, dot11BssType);
+    if data.pData.is_null() || (data.dwDataSize as usize) < needed {
+        return;
+    }
+    let ssid_offset = std::mem::offset_of!(WLAN_CONNECTION_NOTIFICATION_DATA, dot11Ssid);
+    let ssid = std::ptr::read_unaligned(data.pData.cast::<u8>().add(ssid_offset).cast::<DOT11_SSID>());
+
+    let owner = HWND(context as isize);
+    let ssid = Box::into_raw(Box::new(ssid_string(&ssid)));
+    if !PostMessageW(owner, WM_WIFI_CHANGED, WPARAM(state), LPARAM(ssid as isize)).as_bool() {
+        drop(Box::from_raw(ssid));
+    }
+}
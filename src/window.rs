@@ -0,0 +1,2728 @@
+use chrono::Datelike;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use windows::Win32::Foundation::*;
+use windows::Win32::System::Console::{SetConsoleCtrlHandler, CTRL_BREAK_EVENT, CTRL_C_EVENT};
+use windows::Win32::System::DataExchange::COPYDATASTRUCT;
+use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+use windows::Win32::System::Power::*;
+use windows::Win32::System::RemoteDesktop::{
+    WTSRegisterSessionNotification, WTSUnRegisterSessionNotification, NOTIFY_FOR_THIS_SESSION,
+};
+use windows::Win32::UI::Input::KeyboardAndMouse::{RegisterHotKey, UnregisterHotKey, MOD_NOREPEAT};
+use windows::Win32::System::SystemServices::{
+    GUID_BATTERY_PERCENTAGE_REMAINING, GUID_LIDSWITCH_STATE_CHANGE, GUID_MONITOR_POWER_ON,
+};
+use windows::Win32::UI::Shell::NIN_BALLOONUSERCLICK;
+use windows::Win32::UI::WindowsAndMessaging::*;
+
+use crate::action::{self, CommandOutcome, LID_OPEN_COMMAND, PANIC_COMMAND, SEQUENCE_COMMAND, WM_COMMAND_DONE};
+use crate::alert::AlertSender;
+use crate::config::{Config, ConfigOverlay, InputDeferConfig, PresenceInput, TriggerConfig};
+use crate::control::{DUMP_EVENTS_REQUEST, LOG_TAIL_REPLY, LOG_TAIL_REQUEST, OVERLAY_REQUEST, WM_ARM};
+use crate::decision_log::{DecisionInputs, DecisionLog};
+use crate::diagnostic::DiagnosticWindow;
+use crate::broadcast::{BroadcastWindow, WM_DOCK_CHANGED, WM_SESSION_ENDING};
+use crate::dock;
+use crate::event::{Decision, LastDecision, LockAction, LockEvent, SharedLastDecision, SkipReason, Trigger};
+use crate::history::EventHistory;
+use crate::idle::{idle_duration_ms, IdleMonitor, BLANK_TIMER_ID, IDLE_TIMER_ID, WM_IDLE_INPUT};
+use crate::lockscreen::LockScreenMessage;
+use crate::machine::{
+    BatteryArm, BatteryCheck, Pipeline, PipelineConfig, PipelineEvent, PresenceEvaluator, PresenceUpdate, Step,
+    UserPresence,
+};
+use crate::logger::{with_trigger, LogFailurePolicy, Logger, LOG_FAILURE_EXIT_CODE, WM_LOG_FAILED};
+use crate::power::{external_display_count, in_tablet_mode, power_source};
+use crate::prompt::{ConfirmPrompt, PROMPT_TIMER_ID, WM_PROMPT_RESULT};
+use crate::session;
+use crate::singleton::SingletonHandle;
+#[cfg(feature = "motion")]
+use crate::motion::{MotionWatcher, WM_MOTION};
+#[cfg(feature = "mqtt")]
+use crate::mqtt::MqttPublisher;
+use crate::startup::show_startup_notification;
+use crate::state::write_state_file;
+use crate::stats::StatsRecorder;
+use crate::wifi::{self, WifiWatcher, WIFI_DISCONNECTED, WM_WIFI_CHANGED};
+#[cfg(feature = "http-status")]
+use crate::status_server::{StatusServer, WM_REMOTE_LOCK, WM_REMOTE_PAUSE};
+use crate::tray::{TrayIcon, WM_TRAY};
+use crate::{describe_error, hostname, unique_class_name, wide_string, APP_NAME};
+
+const SLEEP_TIMER_ID: usize = 3;
+const HOLD_TIMER_ID: usize = 5;
+const LOCK_CONFIRM_TIMER_ID: usize = 6;
+const COUNTDOWN_TIMER_ID: usize = 7;
+const SCHEDULE_TIMER_ID: usize = 8;
+const SCHEDULE_POLL_MS: u32 = 15_000;
+const ESCALATION_TIMER_ID: usize = 9;
+const PRESENCE_TIMER_ID: usize = 10;
+const PRESENCE_POLL_MS: u32 = 1_000;
+const INPUT_DEFER_TIMER_ID: usize = 11;
+const OVERLAY_TIMER_ID: usize = 12;
+// Shutdown reason for a session ending by signing out rather than shutting down
+const SIGNING_OUT: &str = "user signing out";
+const LOCK_DISABLED_ERROR: &str = "locking is disabled by the DisableLockWorkstation policy";
+// Doubled after every failed registration attempt
+const REGISTRATION_RETRY_DELAY: Duration = Duration::from_secs(1);
+// Registered settings carry a DWORD; anything far larger is malformed
+const MAX_POWER_SETTING_DATA: usize = 64;
+const PANIC_HOTKEY_ID: i32 = 1;
+const ARM_HOTKEY_ID: i32 = 2;
+// The `--log-tail` process is waiting on this reply
+const LOG_TAIL_TIMEOUT_MS: u32 = 2_000;
+// Sequences waiting behind a busy one; locking never waits for this
+const MAX_QUEUED_SEQUENCES: usize = 4;
+
+// Window closed on Ctrl+C when lidlock runs in a console
+static CTRL_C_TARGET: AtomicIsize = AtomicIsize::new(0);
+
+type EventCallback = Box<dyn Fn(&LockEvent)>;
+
+/// Actions for one decision, kept between steps while a command runs.
+struct ActionSequence {
+    trigger: Trigger,
+    state: u32,
+    actions: Vec<LockAction>,
+    next: usize,
+    errors: Vec<String>,
+}
+
+impl ActionSequence {
+    fn new(trigger: Trigger, state: u32, actions: Vec<LockAction>) -> Self {
+        ActionSequence {
+            trigger,
+            state,
+            actions,
+            next: 0,
+            errors: Vec::new(),
+        }
+    }
+}
+
+/// One step of [`LidLockWindow::shutdown`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShutdownStep {
+    UnregisterNotifications,
+    StopServers,
+    LockOnStop,
+    RestoreLockScreen,
+    RemoveTray,
+    CloseDiagnostics,
+    StopWorkers,
+    ReleaseSingleton,
+    FlushLog,
+}
+
+/// The order shutdown runs in. Nothing new comes in once notifications and
+/// servers are gone, the lock on stop can still alert, and the single-instance
+/// mutex is only released once nothing acts anymore. The log is flushed last so
+/// every line above reaches it.
+const SHUTDOWN_STEPS: [ShutdownStep; 9] = [
+    ShutdownStep::UnregisterNotifications,
+    ShutdownStep::StopServers,
+    ShutdownStep::LockOnStop,
+    ShutdownStep::RestoreLockScreen,
+    ShutdownStep::RemoveTray,
+    ShutdownStep::CloseDiagnostics,
+    ShutdownStep::StopWorkers,
+    ShutdownStep::ReleaseSingleton,
+    ShutdownStep::FlushLog,
+];
+
+impl ShutdownStep {
+    fn describe(&self) -> &'static str {
+        match self {
+            ShutdownStep::UnregisterNotifications => "unregistering notifications",
+            ShutdownStep::StopServers => "stopping status server and MQTT",
+            ShutdownStep::LockOnStop => "locking on stop",
+            ShutdownStep::RestoreLockScreen => "restoring lock screen message",
+            ShutdownStep::RemoveTray => "removing tray icon",
+            ShutdownStep::CloseDiagnostics => "closing diagnostic window",
+            ShutdownStep::StopWorkers => "stopping alert, stats and decision log threads",
+            ShutdownStep::ReleaseSingleton => "releasing single-instance mutex",
+            ShutdownStep::FlushLog => "flushing log",
+        }
+    }
+}
+
+pub struct LidLockWindow {
+    hwnd: HWND,
+    class_name: Vec<u16>,
+    logger: Arc<Logger>,
+    config: Config,
+    started: Instant,
+    notifications: RefCell<Vec<(Trigger, HPOWERNOTIFY)>>,
+    sleep_pending: Cell<bool>,
+    // Suspend attempts made for the pending sleep
+    sleep_attempts: Cell<u32>,
+    // Set once the DisableLockWorkstation policy was seen, until it is lifted
+    lock_disabled: Cell<bool>,
+    // A lock held back by `defer_on_input`, and since when
+    input_defer: RefCell<Option<(ActionSequence, Instant)>>,
+    // Trigger of a display_off_then_lock waiting for the display to stay off
+    escalation: Cell<Option<Trigger>>,
+    // Armed unless `start_disarmed`, and paused through the status server
+    pipeline: RefCell<Pipeline>,
+    battery: RefCell<BatteryArm>,
+    presence: RefCell<PresenceEvaluator>,
+    // Local time of the last scheduled lock check
+    schedule_checked: Cell<chrono::NaiveDateTime>,
+    // When the hold period ends, while the tray tooltip counts down to it
+    countdown_deadline: Cell<Option<Instant>>,
+    // Whether the countdown notification is the one currently shown
+    countdown_notified: Cell<bool>,
+    // Retries left while waiting for a lock to be confirmed
+    lock_confirm: Cell<Option<u32>>,
+    // Applied through `--overlay` until its timer expires
+    overlay: RefCell<Option<ConfigOverlay>>,
+    last_decision: SharedLastDecision,
+    history: EventHistory,
+    callbacks: Vec<EventCallback>,
+    alert: Option<AlertSender>,
+    stats: Option<StatsRecorder>,
+    decision_log: Option<DecisionLog>,
+    // Inputs noted for `decision_log` while a decision is being made
+    decision_inputs: RefCell<Option<DecisionInputs>>,
+    idle: Option<IdleMonitor>,
+    prompt: Option<ConfirmPrompt>,
+    lock_screen: Option<LockScreenMessage>,
+    tray: Option<TrayIcon>,
+    // Only held so its window keeps receiving broadcasts
+    _broadcasts: Option<BroadcastWindow>,
+    // Only held so notifications keep arriving
+    _wifi: Option<WifiWatcher>,
+    docked: Cell<Option<bool>>,
+    diagnostics: RefCell<Option<DiagnosticWindow>>,
+    shut_down: Cell<bool>,
+    // Handed over by `hold_singleton` so shutdown releases it in order
+    singleton: RefCell<Option<SingletonHandle>>,
+    prompt_actions: RefCell<Option<Vec<LockAction>>>,
+    running: RefCell<Option<ActionSequence>>,
+    // Triggered while `running` or `input_defer` was busy; run in order once it is done
+    queued: RefCell<VecDeque<ActionSequence>>,
+    #[cfg(feature = "motion")]
+    _motion: Option<MotionWatcher>,
+    #[cfg(feature = "mqtt")]
+    mqtt: Option<MqttPublisher>,
+    #[cfg(feature = "http-status")]
+    status_server: Option<StatusServer>,
+}
+
+impl LidLockWindow {
+    pub fn new(logger: Logger, config: Config) -> windows::core::Result<Box<Self>> {
+        Self::build(logger, config, false)
+    }
+
+    /// Creates a window for [`run_once`](Self::run_once) only. It registers no
+    /// power, session or hotkey notifications and starts none of the watchers,
+    /// servers or publishers, so nothing but the manual trigger can reach it.
+    pub fn new_once(logger: Logger, config: Config) -> windows::core::Result<Box<Self>> {
+        Self::build(logger, config, true)
+    }
+
+    fn build(logger: Logger, config: Config, once: bool) -> windows::core::Result<Box<Self>> {
+        let logger = Arc::new(logger);
+        logger.log(if once { "Creating LidLockWindow for a single run" } else { "Creating LidLockWindow" });
+        let last_decision: SharedLastDecision = Arc::new(RwLock::new(None));
+        let history = EventHistory::new(config.event_history_size);
+
+        unsafe {
+            let class_name = Self::register_class(&logger)?;
+            let hwnd = Self::create_window(&class_name, &logger).inspect_err(|_| {
+                Self::unregister_class(&class_name);
+            })?;
+
+            if config.on_log_failure != LogFailurePolicy::Continue {
+                logger.report_failures_to(hwnd);
+            }
+
+            let alert = config
+                .alert_webhook_url
+                .as_ref()
+                .and_then(|url| url.resolve_logged("alert_webhook_url", &logger))
+                .map(|url| AlertSender::start(url, config.alert_delivery.clone(), Arc::clone(&logger)));
+
+            let stats = config
+                .stats_file
+                .clone()
+                .map(|path| StatsRecorder::start(path, Arc::clone(&logger)));
+            let decision_log = config
+                .decision_log
+                .clone()
+                .map(|path| DecisionLog::start(path, Arc::clone(&logger)));
+
+            // Idle lock is an extra, so failing to start it leaves lid locking running
+            let idle = match config.idle_lock.clone() {
+                Some(idle_config) if !once => {
+                    logger.log(&format!("Starting idle lock after {}s", idle_config.seconds));
+                    IdleMonitor::start(idle_config, hwnd)
+                        .inspect_err(|e| logger.error(&format!("Failed to start idle lock: {}", describe_error(e))))
+                        .ok()
+                }
+                _ => None,
+            };
+
+            let prompt = match config.external_display_prompt.clone() {
+                Some(prompt_config) => Some(ConfirmPrompt::new(prompt_config, hwnd)?),
+                None => None,
+            };
+            let lock_screen = config
+                .lock_screen_message
+                .clone()
+                .map(|message| LockScreenMessage::new(message, &logger));
+
+            // The icon is cosmetic, so lidlock keeps running without it
+            // Dry runs report through the tray, so they need the icon
+            let tray = if (config.tray_icon && !once) || config.dry_run {
+                TrayIcon::add(hwnd)
+                    .inspect_err(|e| logger.log(&format!("Failed to add tray icon: {}", describe_error(e))))
+                    .ok()
+            } else {
+                None
+            };
+            if config.tray_countdown && tray.is_none() && !once {
+                logger.warn("tray_countdown needs the tray icon, set tray_icon = true");
+            }
+            if config.countdown_click_cancels && !config.countdown_notification {
+                logger.warn("countdown_click_cancels needs the countdown notification, set countdown_notification = true");
+            }
+            // Undock detection and cleanup at session end are best effort;
+            // lid handling works without them
+            let broadcasts = if once {
+                None
+            } else {
+                BroadcastWindow::start(hwnd)
+                    .inspect_err(|e| {
+                        logger.error(&format!(
+                            "Failed to create broadcast window, undocking and session end won't be seen: {}",
+                            describe_error(e)
+                        ))
+                    })
+                    .ok()
+            };
+            // Without a wireless adapter there is simply nothing to watch
+            let wifi = match &config.wifi {
+                Some(wifi) if !wifi.lock_on_disconnect.is_empty() && !once => WifiWatcher::start(hwnd)
+                    .inspect(|_| {
+                        logger.log(&format!("Watching Wi-Fi for disconnects from {}", wifi.lock_on_disconnect.join(", ")))
+                    })
+                    .inspect_err(|e| logger.warn(&format!("Failed to watch Wi-Fi, no wireless adapter? {}", e)))
+                    .ok(),
+                _ => None,
+            };
+            let docked = dock::is_docked();
+            if config.lock_on_undock {
+                logger.log(&format!("Dock state at startup docked={}", describe_flag(docked)));
+            }
+
+            // Most desktops and many laptops have no accelerometer
+            #[cfg(feature = "motion")]
+            let motion = config.triggers.motion.as_ref().filter(|_| !once).and_then(|motion| {
+                MotionWatcher::start(hwnd, motion, Arc::clone(&logger))
+                    .inspect(|_| {
+                        logger.log(&format!("Watching for motion above {}g", motion.threshold_g))
+                    })
+                    .inspect_err(|e| logger.warn(&format!("Motion trigger disabled: {}", e)))
+                    .ok()
+            });
+            #[cfg(not(feature = "motion"))]
+            if config.triggers.motion.is_some() {
+                logger.warn("The motion trigger is configured but lidlock was built without the motion feature");
+            }
+
+            #[cfg(feature = "mqtt")]
+            let mqtt = config
+                .mqtt
+                .as_ref()
+                .filter(|_| !once)
+                .map(|mqtt| MqttPublisher::start(mqtt, Arc::clone(&logger)));
+            #[cfg(not(feature = "mqtt"))]
+            if config.mqtt.is_some() {
+                logger.log("MQTT is configured but lidlock was built without the mqtt feature");
+            }
+
+            let armed = !config.start_disarmed;
+            // Status reporting is optional, so a port already in use is not fatal
+            #[cfg(feature = "http-status")]
+            let status_server = config.status_port.filter(|_| !once).and_then(|port| {
+                StatusServer::start(
+                    port,
+                    hwnd,
+                    Arc::clone(&last_decision),
+                    history.clone(),
+                    armed,
+                    Arc::clone(&logger),
+                )
+                    .inspect_err(|e| logger.log(&format!("Failed to start status server: {}", e)))
+                    .ok()
+            });
+            #[cfg(not(feature = "http-status"))]
+            if config.status_port.is_some() {
+                logger.log("status_port is set but lidlock was built without the http-status feature");
+            }
+
+            let pipeline = Pipeline::new(PipelineConfig::new(&config), armed, Instant::now());
+            // Boxed so the pointer handed to window_proc stays valid for the window's lifetime
+            let window = Box::new(LidLockWindow {
+                hwnd,
+                class_name,
+                logger,
+                config,
+                started: Instant::now(),
+                notifications: RefCell::new(Vec::new()),
+                sleep_pending: Cell::new(false),
+                sleep_attempts: Cell::new(0),
+                escalation: Cell::new(None),
+                input_defer: RefCell::new(None),
+                lock_disabled: Cell::new(false),
+                pipeline: RefCell::new(pipeline),
+                battery: RefCell::new(BatteryArm::default()),
+                presence: RefCell::new(PresenceEvaluator::default()),
+                schedule_checked: Cell::new(chrono::Local::now().naive_local()),
+                countdown_deadline: Cell::new(None),
+                countdown_notified: Cell::new(false),
+                lock_confirm: Cell::new(None),
+                overlay: RefCell::new(None),
+                last_decision,
+                history,
+                callbacks: Vec::new(),
+                alert,
+                stats,
+                decision_log,
+                decision_inputs: RefCell::new(None),
+                idle,
+                prompt,
+                lock_screen,
+                tray,
+                _broadcasts: broadcasts,
+                _wifi: wifi,
+                docked: Cell::new(docked),
+                diagnostics: RefCell::new(None),
+                shut_down: Cell::new(false),
+                singleton: RefCell::new(None),
+                prompt_actions: RefCell::new(None),
+                running: RefCell::new(None),
+                queued: RefCell::new(VecDeque::new()),
+                #[cfg(feature = "motion")]
+                _motion: motion,
+                #[cfg(feature = "mqtt")]
+                mqtt,
+                #[cfg(feature = "http-status")]
+                status_server,
+            });
+            SetWindowLongPtrW(hwnd, GWLP_USERDATA, &*window as *const Self as isize);
+            if once {
+                return Ok(window);
+            }
+            window.register_notifications()?;
+            if action::lock_disabled_by_policy() {
+                window.report_lock_disabled();
+            }
+            if window.config.start_disarmed {
+                window.logger.log("Starting disarmed, events are logged but not acted on until armed");
+            }
+            window.logger.log(&format!(
+                "Remote session policy: {}",
+                if window.config.lock_in_remote_session { "act" } else { "skip" }
+            ));
+            // Only once the lid is actually being watched
+            show_startup_notification(&window.config, window.tray.as_ref(), &window.logger);
+
+            Ok(window)
+        }
+    }
+
+    /// Registers a window class unique to this window and returns its name.
+    pub(crate) fn register_class(logger: &Logger) -> windows::core::Result<Vec<u16>> {
+        unsafe {
+            let instance = GetModuleHandleW(None)?;
+
+            let name = unique_class_name(APP_NAME);
+            logger.log(&format!("Registering window class {}", name));
+            let class_name = wide_string(&name);
+            let wc = WNDCLASSEXW {
+                cbSize: std::mem::size_of::<WNDCLASSEXW>() as u32,
+                lpfnWndProc: Some(Self::window_proc),
+                hInstance: instance,
+                lpszClassName: windows::core::PCWSTR(class_name.as_ptr()),
+                ..Default::default()
+            };
+
+            if RegisterClassExW(&wc) == 0 {
+                return Err(logger.last_win32_error(&format!("RegisterClassExW for {}", name)));
+            }
+
+            Ok(class_name)
+        }
+    }
+
+    pub(crate) fn unregister_class(class_name: &[u16]) {
+        unsafe {
+            if let Ok(instance) = GetModuleHandleW(None) {
+                UnregisterClassW(windows::core::PCWSTR(class_name.as_ptr()), instance);
+            }
+        }
+    }
+
+    pub(crate) fn create_window(class_name: &[u16], logger: &Logger) -> windows::core::Result<HWND> {
+        unsafe {
+            let instance = GetModuleHandleW(None)?;
+
+            logger.log("Creating window");
+            // The fixed title lets `--stop` find the running instance
+            let title = wide_string(APP_NAME);
+            let hwnd = CreateWindowExW(
+                WINDOW_EX_STYLE(0),
+                windows::core::PCWSTR(class_name.as_ptr()),
+                windows::core::PCWSTR(title.as_ptr()),
+                WINDOW_STYLE(0),
+                CW_USEDEFAULT,
+                CW_USEDEFAULT,
+                CW_USEDEFAULT,
+                CW_USEDEFAULT,
+                HWND_MESSAGE,
+                None,
+                instance,
+                None,
+            );
+
+            if hwnd.0 == 0 {
+                return Err(logger.last_win32_error("CreateWindowExW"));
+            }
+
+            Ok(hwnd)
+        }
+    }
+
+    /// Registers a callback invoked with every decision made on a power event.
+    /// Callbacks run on the message loop thread, in registration order.
+    pub fn on_event<F>(&mut self, callback: F)
+    where
+        F: Fn(&LockEvent) + 'static,
+    {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Registers the power notifications for the enabled triggers. A trigger
+    /// the hardware doesn't support is logged and skipped; only failing to
+    /// register any enabled trigger is an error.
+    pub(crate) fn register_power_notifications(
+        hwnd: HWND,
+        triggers: &TriggerConfig,
+        logger: &Logger,
+    ) -> windows::core::Result<Vec<(Trigger, HPOWERNOTIFY)>> {
+        let settings = [
+            (triggers.monitor_power, Trigger::MonitorPower, &GUID_MONITOR_POWER_ON, "GUID_MONITOR_POWER_ON"),
+            (triggers.lid_switch, Trigger::LidSwitch, &GUID_LIDSWITCH_STATE_CHANGE, "GUID_LIDSWITCH_STATE_CHANGE"),
+            (
+                triggers.low_battery.is_some(),
+                Trigger::LowBattery,
+                &GUID_BATTERY_PERCENTAGE_REMAINING,
+                "GUID_BATTERY_PERCENTAGE_REMAINING",
+            ),
+        ];
+
+        let mut handles = Vec::new();
+        let mut last_error = None;
+        for (enabled, trigger, guid, name) in settings {
+            if !enabled {
+                logger.log(&format!("Not registering {} notification, {} trigger is disabled", name, trigger));
+                continue;
+            }
+            match unsafe { RegisterPowerSettingNotification(HANDLE(hwnd.0), guid, DEVICE_NOTIFY_WINDOW_HANDLE.0) } {
+                Ok(handle) => {
+                    logger.log(&format!("Registered {} notification for {} trigger", name, trigger));
+                    handles.push((trigger, handle));
+                }
+                Err(e) => {
+                    logger.warn(&format!(
+                        "Failed to register {} notification, {} trigger won't fire: {}",
+                        name,
+                        trigger,
+                        describe_error(&e)
+                    ));
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        match last_error {
+            Some(e) if handles.is_empty() => {
+                logger.error("Failed to register any power notification");
+                Err(e)
+            }
+            None if handles.is_empty() => {
+                logger.warn("No power triggers are enabled");
+                Ok(handles)
+            }
+            _ => Ok(handles),
+        }
+    }
+
+    // The power service may not be ready yet right after login
+    fn register_power_notifications_with_retry(&self) -> windows::core::Result<Vec<(Trigger, HPOWERNOTIFY)>> {
+        let attempts = self.config.registration_retries.saturating_add(1);
+        let mut delay = REGISTRATION_RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            self.logger.log(&format!(
+                "Registering power notifications, attempt {}/{}",
+                attempt, attempts
+            ));
+            match Self::register_power_notifications(self.hwnd, &self.config.triggers, &self.logger) {
+                Ok(handles) => return Ok(handles),
+                Err(e) if attempt < attempts => {
+                    self.logger.warn(&format!(
+                        "Registering power notifications failed, retrying in {}ms: {}",
+                        delay.as_millis(),
+                        describe_error(&e)
+                    ));
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Registers a power setting whose trigger is disabled, so its changes are
+    // only used for `purpose` and never decided on
+    fn watch_power_setting(&self, trigger: Trigger, guid: &windows::core::GUID, name: &str, purpose: &str) {
+        match unsafe { RegisterPowerSettingNotification(HANDLE(self.hwnd.0), guid, DEVICE_NOTIFY_WINDOW_HANDLE.0) } {
+            Ok(handle) => {
+                self.logger.log(&format!("Registered {} notification for {}", name, purpose));
+                self.notifications.borrow_mut().push((trigger, handle));
+            }
+            Err(e) => self.logger.warn(&format!(
+                "Failed to register {} notification, {} won't see it: {}",
+                name,
+                purpose,
+                describe_error(&e)
+            )),
+        }
+    }
+
+    fn power_trigger_enabled(&self, trigger: Trigger) -> bool {
+        match trigger {
+            Trigger::LidSwitch => self.config.triggers.lid_switch,
+            Trigger::MonitorPower => self.config.triggers.monitor_power,
+            _ => true,
+        }
+    }
+
+    /// Publishes `state` over MQTT, if configured.
+    fn publish_state(&self, state: &'static str) {
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.publish(state);
+        }
+        #[cfg(not(feature = "mqtt"))]
+        let _ = state;
+    }
+
+    fn leave_idle_state(&self) {
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.leave_idle();
+        }
+    }
+
+    fn update_presence(&self, input: PresenceInput, absent: bool) {
+        if !self.config.presence_inputs().contains(&input) {
+            return;
+        }
+        let update = self.presence.borrow_mut().update(input, absent);
+        let presence = match update {
+            PresenceUpdate::Unchanged => return,
+            PresenceUpdate::Same(presence) | PresenceUpdate::Changed(presence) => presence,
+        };
+        let absent_inputs = self.presence.borrow().absent_inputs().iter().map(PresenceInput::as_str).collect::<Vec<_>>();
+        self.logger.log(&format!(
+            "Presence input {}={} presence={} absent_inputs=[{}]",
+            input.as_str(),
+            if absent { "absent" } else { "present" },
+            presence.as_str(),
+            absent_inputs.join(",")
+        ));
+        match update {
+            PresenceUpdate::Changed(UserPresence::Absent) => {
+                self.publish_state("idle");
+                self.decide_and_act(Trigger::Presence, 0);
+            }
+            PresenceUpdate::Changed(UserPresence::Present) => {
+                self.logger.log("User present again");
+                self.leave_idle_state();
+            }
+            _ => {}
+        }
+    }
+
+    fn register_notifications(&self) -> windows::core::Result<()> {
+        if self.config.startup_delay_ms > 0 {
+            self.logger.log(&format!(
+                "Waiting {}ms before registering notifications",
+                self.config.startup_delay_ms
+            ));
+            std::thread::sleep(Duration::from_millis(self.config.startup_delay_ms));
+        }
+        let handles = self.register_power_notifications_with_retry()?;
+        self.notifications.borrow_mut().extend(handles);
+
+        // display_off_then_lock and presence need the lid and display state
+        // even without their triggers
+        let presence_inputs = self.config.presence_inputs();
+        let presence_uses = |input| presence_inputs.contains(&input);
+        if let Some(presence) = &self.config.presence {
+            let owned = presence.inputs.iter().filter(|input| !presence_inputs.contains(input));
+            for input in owned {
+                self.logger.log(&format!("Presence input {} is left to its own trigger", input.as_str()));
+            }
+        }
+        let mut display_users = Vec::new();
+        if self.config.uses_action(LockAction::DisplayOffThenLock) {
+            display_users.push("display_off_then_lock");
+        }
+        if presence_uses(PresenceInput::Display) {
+            display_users.push("presence");
+        }
+        if !self.config.triggers.monitor_power && !display_users.is_empty() {
+            self.watch_power_setting(Trigger::MonitorPower, &GUID_MONITOR_POWER_ON, "GUID_MONITOR_POWER_ON", &display_users.join(" and "));
+        }
+        if presence_uses(PresenceInput::Lid) {
+            self.watch_power_setting(Trigger::LidSwitch, &GUID_LIDSWITCH_STATE_CHANGE, "GUID_LIDSWITCH_STATE_CHANGE", "presence");
+        }
+        if presence_uses(PresenceInput::Idle) && unsafe { SetTimer(self.hwnd, PRESENCE_TIMER_ID, PRESENCE_POLL_MS, None) } == 0 {
+            self.logger.error("Failed to start the presence idle timer, idle won't count towards presence");
+        }
+
+        // The hotkey is an extra, so a combination taken by another program isn't fatal
+        if let Some(panic) = &self.config.panic {
+            let modifiers = panic.hotkey.modifiers | MOD_NOREPEAT;
+            match unsafe { RegisterHotKey(self.hwnd, PANIC_HOTKEY_ID, modifiers, panic.hotkey.key) }.ok() {
+                Ok(()) => self.logger.log(&format!("Registered panic hotkey {}", panic.hotkey)),
+                Err(e) => self.logger.error(&format!(
+                    "Failed to register panic hotkey {}: {}",
+                    panic.hotkey,
+                    describe_error(&e)
+                )),
+            }
+        }
+        if let Some(hotkey) = &self.config.arm_hotkey {
+            match unsafe { RegisterHotKey(self.hwnd, ARM_HOTKEY_ID, hotkey.modifiers | MOD_NOREPEAT, hotkey.key) }.ok() {
+                Ok(()) => self.logger.log(&format!("Registered arm hotkey {}", hotkey)),
+                Err(e) => self.logger.error(&format!("Failed to register arm hotkey {}: {}", hotkey, describe_error(&e))),
+            }
+        }
+
+        if !self.config.scheduled_locks.is_empty() {
+            if unsafe { SetTimer(self.hwnd, SCHEDULE_TIMER_ID, SCHEDULE_POLL_MS, None) } == 0 {
+                self.logger.error("Failed to start the scheduled lock timer, scheduled locks won't run");
+            } else {
+                let times: Vec<String> = self.config.scheduled_locks.iter().map(|lock| lock.at.into()).collect();
+                self.logger.log(&format!("Scheduled locks at {}", times.join(", ")));
+            }
+        }
+
+        // Lock/unlock tracking is best effort, lid handling works without it
+        self.logger.log("Registering session notifications");
+        if let Err(e) = unsafe { WTSRegisterSessionNotification(self.hwnd, NOTIFY_FOR_THIS_SESSION) }.ok() {
+            self.logger.log(&format!("Failed to register session notifications: {}", describe_error(&e)));
+        }
+
+        Ok(())
+    }
+
+    fn on_session_change(&self, event: u32) {
+        match event {
+            WTS_SESSION_LOCK => {
+                self.logger.log("Session locked");
+                // Also covers locks lidlock didn't start, such as Win+L
+                self.publish_state("locked");
+                if self.lock_confirm.take().is_some() {
+                    unsafe {
+                        KillTimer(self.hwnd, LOCK_CONFIRM_TIMER_ID);
+                    }
+                    self.logger.log("Lock confirmed by session notification");
+                }
+            }
+            WTS_SESSION_UNLOCK => {
+                self.logger.log("Session unlocked");
+                if let Some(lock_screen) = &self.lock_screen {
+                    lock_screen.restore(&self.logger);
+                }
+                self.publish_state("unlocked");
+                if self.config.recheck_on_unlock {
+                    self.recheck_lid("after unlock");
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn reconcile_after_resume(&self) {
+        let displays = match external_display_count() {
+            Ok(count) => count.to_string(),
+            Err(e) => format!("unknown ({})", e),
+        };
+        self.logger.log(&format!(
+            "Resumed from sleep lid_closed={} external_displays={}",
+            describe_flag(self.pipeline.borrow().lid_closed()),
+            displays
+        ));
+        self.recheck_lid("after resume");
+    }
+
+    fn recheck_lid(&self, when: &str) {
+        self.logger.log(&format!("Re-checking lid state {}", when));
+        self.pipeline.borrow_mut().expect_recheck(true);
+        if !self.requery_lid() {
+            self.pipeline.borrow_mut().expect_recheck(false);
+        }
+    }
+
+    // Registering for a power setting delivers its current value right away,
+    // so re-registering the lid notification reports whether it is closed now.
+    // Returns false if the notification could not be re-registered.
+    fn requery_lid(&self) -> bool {
+        let mut notifications = self.notifications.borrow_mut();
+        // Nothing to re-query while the lid trigger is disabled
+        let Some(index) = notifications.iter().position(|(trigger, _)| *trigger == Trigger::LidSwitch) else {
+            return false;
+        };
+        let (_, lid_switch) = notifications.remove(index);
+
+        unsafe {
+            UnregisterPowerSettingNotification(lid_switch);
+            match RegisterPowerSettingNotification(
+                HANDLE(self.hwnd.0),
+                &GUID_LIDSWITCH_STATE_CHANGE,
+                DEVICE_NOTIFY_WINDOW_HANDLE.0,
+            ) {
+                Ok(handle) => {
+                    notifications.push((Trigger::LidSwitch, handle));
+                    true
+                }
+                Err(e) => {
+                    self.logger.error(&format!(
+                        "Failed to re-register GUID_LIDSWITCH_STATE_CHANGE notification: {}",
+                        describe_error(&e)
+                    ));
+                    false
+                }
+            }
+        }
+    }
+
+    /// Shows a visible window with the live log and the latest decision, fed
+    /// by a logger created with [`Logger::with_window_sink`]. Closing it ends
+    /// [`LidLockWindow::run`].
+    pub fn show_diagnostic_window(&self) -> windows::core::Result<()> {
+        let diagnostics = DiagnosticWindow::show()?;
+        self.logger.attach_window(diagnostics.hwnd());
+        *self.diagnostics.borrow_mut() = Some(diagnostics);
+        Ok(())
+    }
+
+    pub fn run(&self) -> windows::core::Result<()> {
+        self.logger.log("Starting message loop");
+
+        unsafe {
+            CTRL_C_TARGET.store(self.hwnd.0, Ordering::Relaxed);
+            SetConsoleCtrlHandler(Some(console_ctrl_handler), true);
+
+            let mut msg = MSG::default();
+            while GetMessageW(&mut msg, HWND(0), 0, 0).as_bool() {
+                TranslateMessage(&msg);
+                DispatchMessageW(&msg);
+            }
+        }
+        self.shutdown("message loop ended");
+        Ok(())
+    }
+
+    /// Undoes everything that outlives the process, in order, logging each
+    /// step. Runs once, when the message loop ends, when the session ends or
+    /// on drop, whichever comes first.
+    fn shutdown(&self, reason: &str) {
+        if self.shut_down.replace(true) {
+            return;
+        }
+        self.logger.log(&format!("Shutting down: {}", reason));
+
+        for (index, step) in SHUTDOWN_STEPS.iter().enumerate() {
+            self.logger.log(&format!(
+                "Shutdown {}/{}: {}",
+                index + 1,
+                SHUTDOWN_STEPS.len(),
+                step.describe()
+            ));
+            self.shutdown_step(*step, reason);
+        }
+    }
+
+    fn shutdown_step(&self, step: ShutdownStep, reason: &str) {
+        match step {
+            ShutdownStep::UnregisterNotifications => unsafe {
+                for (_, handle) in self.notifications.borrow_mut().drain(..) {
+                    UnregisterPowerSettingNotification(handle);
+                }
+                WTSUnRegisterSessionNotification(self.hwnd);
+                if self.config.panic.is_some() {
+                    UnregisterHotKey(self.hwnd, PANIC_HOTKEY_ID);
+                }
+                if self.config.arm_hotkey.is_some() {
+                    UnregisterHotKey(self.hwnd, ARM_HOTKEY_ID);
+                }
+            },
+            ShutdownStep::StopServers => {
+                #[cfg(feature = "http-status")]
+                if let Some(server) = &self.status_server {
+                    server.stop();
+                }
+                #[cfg(feature = "mqtt")]
+                if let Some(mqtt) = &self.mqtt {
+                    mqtt.stop();
+                }
+            }
+            ShutdownStep::LockOnStop => {
+                if !self.config.lock_on_stop {
+                    self.logger.log("lock_on_stop is not set, not locking");
+                } else if reason == SIGNING_OUT {
+                    self.logger.log("The session is ending, not locking");
+                } else if self.config.dry_run {
+                    self.logger.log("Dry run: would lock on stop");
+                } else {
+                    match self.lock_workstation() {
+                        Ok(()) => self.logger.log("Locked on stop"),
+                        Err(e) => self.logger.error(&format!("Failed to lock on stop: {}", e)),
+                    }
+                }
+            }
+            ShutdownStep::RestoreLockScreen => {
+                if let Some(lock_screen) = &self.lock_screen {
+                    lock_screen.close(&self.logger);
+                }
+            }
+            ShutdownStep::RemoveTray => {
+                if let Some(tray) = &self.tray {
+                    tray.remove();
+                }
+            }
+            ShutdownStep::CloseDiagnostics => {
+                if self.diagnostics.borrow_mut().take().is_some() {
+                    self.logger.attach_window(HWND(0));
+                }
+            }
+            ShutdownStep::StopWorkers => {
+                if let Some(alert) = &self.alert {
+                    alert.stop();
+                }
+                if let Some(stats) = &self.stats {
+                    stats.stop();
+                }
+                if let Some(log) = &self.decision_log {
+                    log.stop();
+                }
+            }
+            ShutdownStep::ReleaseSingleton => {
+                self.singleton.borrow_mut().take();
+            }
+            // The log's flush thread ends on its own once the logger is dropped
+            ShutdownStep::FlushLog => {
+                self.logger.log(&format!("Stopped: {}", reason));
+                self.logger.flush();
+            }
+        }
+    }
+
+    /// Keeps the single-instance mutex until shutdown has stopped everything
+    /// that acts, rather than until `main` returns.
+    pub fn hold_singleton(&self, singleton: SingletonHandle) {
+        *self.singleton.borrow_mut() = Some(singleton);
+    }
+
+    /// Runs the actions once as if triggered manually and returns the final
+    /// decision. Meant for a window from [`new_once`](Self::new_once). Messages are only processed until a started command, any
+    /// scheduled sleep, deferred lock and display-off lock have finished.
+    pub fn run_once(&self) -> windows::core::Result<Decision> {
+        self.logger.log("Running actions once");
+        self.decide_and_act(Trigger::Manual, 0);
+
+        unsafe {
+            let mut msg = MSG::default();
+            while (self.running.borrow().is_some()
+                || self.input_defer.borrow().is_some()
+                || !self.queued.borrow().is_empty()
+                || self.sleep_pending.get()
+                || self.escalation.get().is_some())
+                && GetMessageW(&mut msg, HWND(0), 0, 0).as_bool()
+            {
+                TranslateMessage(&msg);
+                DispatchMessageW(&msg);
+            }
+        }
+        // A command finishing reports its decision from the message loop
+        Ok(self.last_decision().map_or(Decision::Deferred, |last| last.event.decision))
+    }
+
+    /// The most recently processed event, if any.
+    pub fn last_decision(&self) -> Option<LastDecision> {
+        self.last_decision.read().ok().and_then(|last| last.clone())
+    }
+
+    fn decide_and_act(&self, trigger: Trigger, state: u32) -> Decision {
+        with_trigger(trigger, || {
+            if self.decision_log.is_some() {
+                self.start_decision_inputs();
+            }
+            let actions = self.select_actions(trigger, state);
+            let decision = self.decide(trigger, state, &actions);
+            self.emit(LockEvent {
+                trigger,
+                state,
+                actions,
+                decision,
+            });
+            decision
+        })
+    }
+
+    fn emit(&self, event: LockEvent) {
+        let last = LastDecision {
+            timestamp: chrono::Local::now(),
+            event: event.clone(),
+        };
+        // Everything reporting state reads this, so it is updated first
+        if let Ok(mut shared) = self.last_decision.write() {
+            *shared = Some(last.clone());
+        }
+        self.history.record(&last);
+        if let Some(diagnostics) = &*self.diagnostics.borrow() {
+            diagnostics.set_status(&format!(
+                "{}  {}",
+                last.timestamp.format("%H:%M:%S"),
+                event.summary()
+            ));
+        }
+
+        if let Some(path) = &self.config.state_file {
+            if let Err(e) = write_state_file(path, &last) {
+                self.logger.log(&format!("Failed to write state file {}: {}", path.display(), e));
+            }
+        }
+
+        if let Some(stats) = &self.stats {
+            stats.record(&event);
+        }
+        // Outcomes of deferred decisions are recorded too, without inputs of their own
+        let inputs = self.decision_inputs.take();
+        if let Some(log) = &self.decision_log {
+            log.record(&last, inputs.unwrap_or_default());
+        }
+
+        // Opening the lid or waking the display would only add noise
+        if self.config.dry_run && event.state == 0 && event.decision != Decision::Deferred {
+            let summary = event.dry_run_summary();
+            self.logger.log(&format!("Dry run: {}", summary));
+            if let Some(tray) = &self.tray {
+                if let Err(e) = tray.notify("lidlock dry run", &summary) {
+                    self.logger.log(&format!("Failed to show dry run notification: {}", e.message()));
+                }
+            }
+        }
+
+        for callback in &self.callbacks {
+            callback(&event);
+        }
+    }
+
+    /// Snapshot of the machine state for the decision log. Taken up front, so
+    /// it covers inputs the pipeline would otherwise only read for some triggers.
+    fn start_decision_inputs(&self) {
+        let mut inputs = DecisionInputs::new();
+        inputs.insert("paused".into(), self.pipeline.borrow().gate().paused().into());
+        inputs.insert("armed".into(), self.pipeline.borrow().gate().armed().into());
+        inputs.insert("pipeline_state".into(), self.pipeline.borrow().state().as_str().into());
+        let overlay = self.overlay.borrow().as_ref().map(ConfigOverlay::summary);
+        inputs.insert("overlay".into(), overlay.into());
+        inputs.insert("lid_closed".into(), self.pipeline.borrow().lid_closed().into());
+        inputs.insert("remote_session".into(), (unsafe { GetSystemMetrics(SM_REMOTESESSION) } != 0).into());
+        let session_state = match session::connect_state() {
+            Ok(state) => state.0.into(),
+            Err(e) => e.message().to_string().into(),
+        };
+        inputs.insert("session_connect_state".into(), session_state);
+        inputs.insert("power_source".into(), power_source().as_str().into());
+        let displays = match external_display_count() {
+            Ok(count) => count.into(),
+            Err(e) => e.into(),
+        };
+        inputs.insert("external_displays".into(), displays);
+        inputs.insert("idle_ms".into(), idle_duration_ms().into());
+        inputs.insert("tablet_mode".into(), in_tablet_mode().into());
+        inputs.insert("uptime_ms".into(), (self.started.elapsed().as_millis() as u64).into());
+        *self.decision_inputs.borrow_mut() = Some(inputs);
+    }
+
+    /// Adds an input to the decision log record, if one is being collected.
+    fn note(&self, name: &str, value: impl Into<serde_json::Value>) {
+        if let Some(inputs) = self.decision_inputs.borrow_mut().as_mut() {
+            inputs.insert(name.to_string(), value.into());
+        }
+    }
+
+    fn select_actions(&self, trigger: Trigger, state: u32) -> Vec<LockAction> {
+        let actions = self.config.actions_for(trigger);
+        let Some(policy) = &self.config.clamshell else {
+            return actions;
+        };
+        if trigger != Trigger::LidSwitch || state != 0 {
+            return actions;
+        }
+
+        let source = power_source();
+        // Without a display count, assume none so the lid still locks
+        let displays = external_display_count().unwrap_or_else(|e| {
+            self.logger.log(&format!("Failed to count external displays: {}", e));
+            0
+        });
+        let (rule, selected) = policy.select(source, displays);
+        self.note("clamshell_rule", rule.to_string());
+        let actions = selected.map(<[_]>::to_vec).unwrap_or(actions);
+        self.logger.log(&format!(
+            "Clamshell policy power_source={} external_displays={} rule={} actions=[{}]",
+            source.as_str(),
+            displays,
+            rule,
+            actions.iter().map(LockAction::as_str).collect::<Vec<_>>().join(",")
+        ));
+        actions
+    }
+
+    fn decide(&self, trigger: Trigger, state: u32, actions: &[LockAction]) -> Decision {
+        if trigger == Trigger::LidSwitch && state == 0 {
+            let pipeline = self.pipeline.borrow();
+            if pipeline.rechecking() && pipeline.lid_closed() == Some(true) {
+                self.logger.log("Lid is closed on re-check, locking again");
+            }
+        }
+        let event = PipelineEvent::Trigger {
+            trigger,
+            state,
+            actions: actions.to_vec(),
+        };
+        let step = self.pipeline.borrow_mut().step(event, Instant::now(), |trigger| self.system_check(trigger));
+        // Only events about the lid hold itself come back with nothing to do
+        self.follow(trigger, state, step).unwrap_or(Decision::Deferred)
+    }
+
+    // The checks the pipeline leaves to the window, in order; returns the first
+    // one that rules the trigger out
+    fn system_check(&self, trigger: Trigger) -> Option<SkipReason> {
+        if self.overlay.borrow().as_ref().is_some_and(|overlay| overlay.ignore_triggers.contains(&trigger)) {
+            return Some(SkipReason::Overlay);
+        }
+        if self.within_no_lock_schedule(trigger) {
+            return Some(SkipReason::Schedule);
+        }
+        if unsafe { GetSystemMetrics(SM_REMOTESESSION) } != 0 {
+            if !self.config.lock_in_remote_session {
+                return Some(SkipReason::RemoteSession);
+            }
+            self.logger.log("Remote session, acting anyway because lock_in_remote_session is set");
+        }
+        match session::connect_state() {
+            Ok(state) if session::is_detached(state) => {
+                self.logger.log(&format!("Session connect state: {}", state.0));
+                return Some(SkipReason::DisconnectedSession);
+            }
+            Ok(_) => {}
+            // Fall through and lock rather than let a failed query disable locking
+            Err(e) => self.logger.log(&format!("Failed to query session state: {}", e.message())),
+        }
+        // Folding a convertible can look just like closing the lid
+        if self.config.skip_in_tablet_mode && matches!(trigger, Trigger::LidSwitch | Trigger::MonitorPower) {
+            let tablet = in_tablet_mode();
+            self.logger.log(&format!("Device mode: {}", if tablet { "tablet" } else { "laptop" }));
+            if tablet {
+                return Some(SkipReason::TabletMode);
+            }
+        }
+        if trigger != Trigger::Manual && self.on_trusted_network() {
+            return Some(SkipReason::TrustedNetwork);
+        }
+        // A lid closed mid-typing is more likely a bump than the user walking away
+        if let (Trigger::LidSwitch, Some(required)) = (trigger, self.config.lid_requires_idle_seconds) {
+            let idle_seconds = u64::from(idle_duration_ms() / 1000);
+            self.logger.log(&format!("Lid closed after {}s without input, {}s required", idle_seconds, required));
+            if idle_seconds < required {
+                return Some(SkipReason::RecentInput);
+            }
+        }
+        None
+    }
+
+    // Carries out a step of the pipeline. Returns the decision, or None if the
+    // step decided nothing yet.
+    fn follow(&self, trigger: Trigger, state: u32, step: Step) -> Option<Decision> {
+        let decision = match step {
+            Step::Ignore => return None,
+            Step::Skip(SkipReason::Startup) => {
+                self.logger.log(&format!(
+                    "Ignoring {} event {}ms after startup",
+                    trigger,
+                    self.started.elapsed().as_millis()
+                ));
+                self.skip(SkipReason::Startup)
+            }
+            Step::Skip(reason) => self.skip(reason),
+            Step::Released {
+                lid_opened,
+                hold_cancelled,
+                unconfirmed,
+            } => {
+                if unconfirmed {
+                    self.logger.log("Lid found open when confirming it stayed closed, not locking");
+                }
+                if trigger == Trigger::LidSwitch {
+                    self.cancel_sleep();
+                    if hold_cancelled {
+                        self.hold_cancelled();
+                    }
+                    self.drop_queued_lid_closes();
+                    self.cancel_input_defer();
+                    self.dismiss_prompt();
+                }
+                if lid_opened {
+                    self.lid_opened();
+                }
+                self.skip(SkipReason::NonZeroState)
+            }
+            Step::Coalesced { previous, ago } => {
+                self.note("coalesced_with", previous.as_str());
+                self.logger.log(&format!(
+                    "Coalescing {} event with {} event {}ms ago",
+                    trigger,
+                    previous,
+                    ago.as_millis()
+                ));
+                self.skip(SkipReason::Coalesced)
+            }
+            Step::StartHold => self.start_hold(),
+            Step::ConfirmLid => {
+                self.confirm_lid();
+                return None;
+            }
+            Step::Act {
+                actions,
+                held,
+                recovered,
+            } => {
+                if recovered {
+                    self.logger.log("Lock rate is back below max_locks_per_minute");
+                }
+                if held {
+                    self.logger.log(&format!(
+                        "Lid still closed after {}s, locking",
+                        self.config.lid_closed_seconds
+                    ));
+                }
+                self.act(trigger, state, &actions)
+            }
+            Step::Throttled { started } => {
+                // Warn once per storm rather than for every suppressed lock
+                if let Some(runs) = started {
+                    self.throttle_started(runs);
+                }
+                self.skip(SkipReason::Throttled)
+            }
+            Step::Declined(_) => {
+                unsafe {
+                    KillTimer(self.hwnd, HOLD_TIMER_ID);
+                }
+                self.stop_countdown();
+                self.logger.log("Countdown notification clicked, not locking");
+                self.skip(SkipReason::Declined)
+            }
+        };
+        Some(decision)
+    }
+
+    fn act(&self, trigger: Trigger, state: u32, actions: &[LockAction]) -> Decision {
+        // Sequences that do more than lock still run the rest
+        if actions.iter().all(LockAction::locks) && self.locking_disabled() {
+            return self.skip(SkipReason::LockDisabled);
+        }
+        if self.running.borrow().is_some() || self.input_defer.borrow().is_some() {
+            return self.queue_behind_busy(trigger, state, actions);
+        }
+        if trigger == Trigger::LidSwitch {
+            if let Some(prompt) = &self.prompt {
+                if external_display_count().unwrap_or(0) > 0 {
+                    return self.start_prompt(prompt, actions);
+                }
+            }
+        }
+
+        let sequence = ActionSequence::new(trigger, state, actions.to_vec());
+        // A manual lock comes from elsewhere, input here says nothing about it
+        if let Some(defer) = &self.config.defer_on_input {
+            let idle = u64::from(idle_duration_ms());
+            if trigger != Trigger::Manual && actions.iter().any(LockAction::locks) && idle < defer.recent_input_ms {
+                return self.start_input_defer(sequence, idle, defer);
+            }
+        }
+        self.run_actions(sequence)
+    }
+
+    // An earlier sequence may wait up to action_timeout_seconds for its command or
+    // max_defer_ms for input to stop. The lock can't wait that long, so it happens
+    // right away and the rest of the sequence runs once the earlier one is done.
+    // The decision reports the lock; the queued rest is reported when it runs.
+    fn queue_behind_busy(&self, trigger: Trigger, state: u32, actions: &[LockAction]) -> Decision {
+        if self.config.dry_run {
+            return self.skip(SkipReason::DryRun);
+        }
+        let mut decision = Decision::Deferred;
+        if actions.iter().any(LockAction::locks) {
+            self.logger.log("An earlier action sequence is still running, locking now");
+            decision = match self.run_action(LockAction::Lock, trigger) {
+                Ok(()) => Decision::Locked,
+                Err(e) => {
+                    self.logger.error(&format!("Failed to lock while busy: {}", e));
+                    Decision::Failed
+                }
+            };
+        }
+        // The lock already happened, so the queued copy doesn't lock a second time
+        let rest: Vec<_> = actions.iter().copied().filter(|action| *action != LockAction::Lock).collect();
+        if rest.is_empty() {
+            return decision;
+        }
+        if self.queued.borrow().len() >= MAX_QUEUED_SEQUENCES {
+            if decision == Decision::Deferred {
+                return self.skip(SkipReason::Busy);
+            }
+            self.logger.warn("Too many action sequences queued, dropping the rest of this one");
+            return decision;
+        }
+        self.queued.borrow_mut().push_back(ActionSequence::new(trigger, state, rest));
+        self.note("defer", "busy");
+        self.logger.log("Queued the actions behind the running sequence");
+        decision
+    }
+
+    // Runs the next queued sequence once nothing is running or deferred anymore
+    fn start_queued(&self) {
+        if self.running.borrow().is_some() || self.input_defer.borrow().is_some() {
+            return;
+        }
+        let Some(sequence) = self.queued.borrow_mut().pop_front() else {
+            return;
+        };
+        with_trigger(sequence.trigger, || {
+            self.logger.log("Running queued actions");
+            self.resume_actions(sequence);
+        });
+    }
+
+    // Opening the lid cancels what a lid close started, queued or not
+    fn drop_queued_lid_closes(&self) {
+        let mut queued = self.queued.borrow_mut();
+        let before = queued.len();
+        queued.retain(|sequence| sequence.trigger != Trigger::LidSwitch);
+        if queued.len() < before {
+            self.logger.log("Lid opened, queued lid close actions dropped");
+        }
+    }
+
+    fn start_input_defer(&self, sequence: ActionSequence, idle: u64, defer: &InputDeferConfig) -> Decision {
+        let interval = defer.recent_input_ms.clamp(100, u64::from(u32::MAX)) as u32;
+        if unsafe { SetTimer(self.hwnd, INPUT_DEFER_TIMER_ID, interval, None) } == 0 {
+            self.logger.error("Failed to start the input defer timer, locking now");
+            return self.run_actions(sequence);
+        }
+        self.logger.log(&format!(
+            "Input {}ms ago, deferring the lock for up to {}ms",
+            idle, defer.max_defer_ms
+        ));
+        *self.input_defer.borrow_mut() = Some((sequence, Instant::now()));
+        self.note("defer", "input");
+        Decision::Deferred
+    }
+
+    fn check_input_defer(&self) {
+        let Some(defer) = &self.config.defer_on_input else {
+            return;
+        };
+        let Some((sequence, since)) = self.input_defer.borrow_mut().take() else {
+            unsafe {
+                KillTimer(self.hwnd, INPUT_DEFER_TIMER_ID);
+            }
+            return;
+        };
+        let idle = u64::from(idle_duration_ms());
+        let waited = since.elapsed();
+        if idle < defer.recent_input_ms && waited < Duration::from_millis(defer.max_defer_ms) {
+            *self.input_defer.borrow_mut() = Some((sequence, since));
+            return;
+        }
+
+        unsafe {
+            KillTimer(self.hwnd, INPUT_DEFER_TIMER_ID);
+        }
+        with_trigger(sequence.trigger, || {
+            if idle >= defer.recent_input_ms {
+                self.logger.log(&format!("Input stopped, locking after deferring {}ms", waited.as_millis()));
+            } else {
+                self.logger.log(&format!(
+                    "Still seeing input after deferring {}ms, locking anyway",
+                    waited.as_millis()
+                ));
+            }
+            self.resume_actions(sequence);
+        });
+    }
+
+    fn cancel_input_defer(&self) {
+        if self.input_defer.borrow_mut().take().is_some() {
+            unsafe {
+                KillTimer(self.hwnd, INPUT_DEFER_TIMER_ID);
+            }
+            self.logger.log("Lid opened, deferred lock cancelled");
+            self.start_queued();
+        }
+    }
+
+    fn throttle_started(&self, runs: usize) {
+        self.logger.warn(&format!(
+            "Actions ran {} times in the last minute, suppressing further locks",
+            runs
+        ));
+        if let (true, Some(alert)) = (self.config.alert_on_throttle, &self.alert) {
+            alert.send(format!(
+                "lidlock on {} is suppressing locks after {} in the last minute",
+                hostname(),
+                runs
+            ));
+        }
+    }
+
+    fn battery_crossed_threshold(&self, percent: u32) -> bool {
+        let Some(low_battery) = &self.config.triggers.low_battery else {
+            return false;
+        };
+        let check = self.battery.borrow_mut().update(percent, low_battery);
+        match check {
+            BatteryCheck::Fired => {
+                self.logger.log(&format!(
+                    "Battery at {}%, at or below {}%; disarmed until it is back at {}%",
+                    percent, low_battery.percent, low_battery.rearm_percent
+                ));
+                true
+            }
+            BatteryCheck::Rearmed => {
+                self.logger.log(&format!(
+                    "Battery back at {}%, low battery trigger re-armed at {}%",
+                    percent, low_battery.percent
+                ));
+                false
+            }
+            BatteryCheck::Unchanged => false,
+        }
+    }
+
+    fn start_hold(&self) -> Decision {
+        let delay = self.config.lid_closed_seconds;
+        let millis = u32::try_from(delay.saturating_mul(1000)).unwrap_or(u32::MAX);
+        if unsafe { SetTimer(self.hwnd, HOLD_TIMER_ID, millis, None) } == 0 {
+            self.logger.error("Failed to start lid hold timer, locking now");
+            let step = self.pipeline.borrow_mut().step(PipelineEvent::HoldFailed, Instant::now(), |_| None);
+            return self.follow(Trigger::LidSwitch, 0, step).unwrap_or(Decision::Deferred);
+        }
+        self.note("defer", "lid_hold");
+        self.logger.log(&format!("Waiting for the lid to stay closed for {}s", delay));
+        self.start_countdown(Duration::from_secs(delay));
+        Decision::Deferred
+    }
+
+    fn start_countdown(&self, delay: Duration) {
+        let Some(tray) = self.tray.as_ref().filter(|_| self.config.tray_countdown) else {
+            return;
+        };
+        if unsafe { SetTimer(self.hwnd, COUNTDOWN_TIMER_ID, 1000, None) } == 0 {
+            self.logger.warn("Failed to start tray countdown timer");
+            return;
+        }
+        self.countdown_deadline.set(Some(Instant::now() + delay));
+        self.update_countdown();
+        if self.config.countdown_notification {
+            let text = if self.config.countdown_click_cancels {
+                format!("Locking in {}s, click here if you're still here", delay.as_secs())
+            } else {
+                format!("Locking in {}s unless the lid is opened", delay.as_secs())
+            };
+            match tray.notify(APP_NAME, &text) {
+                Ok(()) => self.countdown_notified.set(true),
+                Err(e) => self.logger.log(&format!("Failed to show countdown notification: {}", e.message())),
+            }
+        }
+    }
+
+    fn update_countdown(&self) {
+        let (Some(tray), Some(deadline)) = (&self.tray, self.countdown_deadline.get()) else {
+            return;
+        };
+        // Rounded up so the tooltip never shows 0s while the lock is still pending
+        let remaining = deadline.saturating_duration_since(Instant::now()).as_millis().div_ceil(1000);
+        if let Err(e) = tray.set_tip(&format!("{}: locking in {}s", APP_NAME, remaining)) {
+            self.logger.debug(&format!("Failed to update tray countdown: {}", e.message()));
+        }
+    }
+
+    fn stop_countdown(&self) {
+        self.countdown_notified.set(false);
+        if self.countdown_deadline.take().is_none() {
+            return;
+        }
+        unsafe {
+            KillTimer(self.hwnd, COUNTDOWN_TIMER_ID);
+        }
+        if let Some(tray) = &self.tray {
+            let _ = tray.reset_tip();
+        }
+    }
+
+    fn hold_elapsed(&self) {
+        unsafe {
+            KillTimer(self.hwnd, HOLD_TIMER_ID);
+        }
+        self.stop_countdown();
+        self.step_hold(PipelineEvent::HoldElapsed);
+    }
+
+    fn confirm_lid(&self) {
+        self.logger.log("Confirming the lid is still closed");
+        if !self.requery_lid() {
+            // Without a fresh reading, trust the last one; the lid never reported opening
+            self.step_hold(PipelineEvent::LidUnconfirmed);
+        }
+    }
+
+    fn hold_cancelled(&self) {
+        unsafe {
+            KillTimer(self.hwnd, HOLD_TIMER_ID);
+        }
+        self.stop_countdown();
+        self.logger.log("Lid opened before the hold period ended, not locking");
+    }
+
+    fn on_tray(&self, event: u32) {
+        if event != NIN_BALLOONUSERCLICK || !self.config.countdown_click_cancels || !self.countdown_notified.get() {
+            return;
+        }
+        self.step_hold(PipelineEvent::HoldDeclined);
+    }
+
+    // Feeds the pipeline an event about the lid hold, which comes without a
+    // trigger of its own, and emits the decision for the held actions if any
+    fn step_hold(&self, event: PipelineEvent) {
+        with_trigger(Trigger::LidSwitch, || {
+            let actions = self.pipeline.borrow().held().map(<[_]>::to_vec).unwrap_or_default();
+            let step = self.pipeline.borrow_mut().step(event, Instant::now(), |_| None);
+            if let Some(decision) = self.follow(Trigger::LidSwitch, 0, step) {
+                self.emit(LockEvent {
+                    trigger: Trigger::LidSwitch,
+                    state: 0,
+                    actions,
+                    decision,
+                });
+            }
+        });
+    }
+
+    fn start_prompt(&self, prompt: &ConfirmPrompt, actions: &[LockAction]) -> Decision {
+        if let Err(e) = prompt.show() {
+            // A prompt that can't be shown can't be answered, so act as if it timed out
+            self.logger.log(&format!("Failed to show confirmation prompt: {}", e.message()));
+            prompt.close();
+            return if prompt.default_locks() {
+                self.run_actions(ActionSequence::new(Trigger::LidSwitch, 0, actions.to_vec()))
+            } else {
+                self.skip(SkipReason::Declined)
+            };
+        }
+        self.logger.log("External display attached, asking before locking");
+        self.note("defer", "prompt");
+        *self.prompt_actions.borrow_mut() = Some(actions.to_vec());
+        Decision::Deferred
+    }
+
+    fn finish_prompt(&self, lock: bool, answer: &str) {
+        with_trigger(Trigger::LidSwitch, || self.answer_prompt(lock, answer));
+    }
+
+    fn answer_prompt(&self, lock: bool, answer: &str) {
+        let Some(prompt) = &self.prompt else {
+            return;
+        };
+        prompt.close();
+        let Some(actions) = self.prompt_actions.borrow_mut().take() else {
+            return;
+        };
+
+        self.logger.log(&format!(
+            "Confirmation prompt {}, {}",
+            answer,
+            if lock { "locking" } else { "not locking" }
+        ));
+        if lock {
+            self.resume_actions(ActionSequence::new(Trigger::LidSwitch, 0, actions));
+        } else {
+            let decision = self.skip(SkipReason::Declined);
+            self.emit(LockEvent {
+                trigger: Trigger::LidSwitch,
+                state: 0,
+                actions,
+                decision,
+            });
+        }
+    }
+
+    fn dismiss_prompt(&self) {
+        if let Some(prompt) = &self.prompt {
+            if prompt.close() {
+                self.prompt_actions.borrow_mut().take();
+                self.logger.log("Lid opened, confirmation prompt dismissed");
+            }
+        }
+    }
+
+    fn check_scheduled_locks(&self) {
+        let now = chrono::Local::now().naive_local();
+        // Times passed while asleep are dropped rather than all firing on resume
+        let from = self.schedule_checked.replace(now).max(now - chrono::Duration::minutes(1));
+        let due = self.config.scheduled_locks.iter().find(|lock| lock.due(from, now));
+        if let Some(lock) = due {
+            self.logger.log(&format!("Scheduled lock at {} is due", String::from(lock.at)));
+            self.decide_and_act(Trigger::Scheduled, 0);
+        }
+    }
+
+    // Fails closed: an SSID that can't be read never counts as trusted
+    fn on_trusted_network(&self) -> bool {
+        let Some(trusted) = self.config.wifi.as_ref().map(|wifi| &wifi.no_lock).filter(|ssids| !ssids.is_empty()) else {
+            return false;
+        };
+        match wifi::current_ssid() {
+            Ok(Some(ssid)) => {
+                let is_trusted = trusted.contains(&ssid);
+                self.note("wifi_ssid", ssid.as_str());
+                self.logger.log(&format!("Connected to Wi-Fi {} trusted={}", ssid, is_trusted));
+                is_trusted
+            }
+            Ok(None) => {
+                self.logger.log("Not connected to Wi-Fi, not a trusted network");
+                false
+            }
+            Err(e) => {
+                self.logger.warn(&format!("Failed to read the Wi-Fi network, treating it as untrusted: {}", e));
+                false
+            }
+        }
+    }
+
+    fn within_no_lock_schedule(&self, trigger: Trigger) -> bool {
+        let now = chrono::Local::now();
+        let Some(weekly) = &self.config.weekly_schedule else {
+            let window = self.config.disable_between.filter(|window| window.contains(now.time()));
+            self.note("schedule_window", window.map(String::from));
+            return window.is_some();
+        };
+
+        let weekday = now.weekday();
+        let day = weekly.day(weekday);
+        let windows = match day {
+            Some(day) => day.disable_between.as_slice(),
+            None => self.config.disable_between.as_slice(),
+        };
+        let window = windows.iter().find(|window| window.contains(now.time()));
+        let allowed = day.is_none_or(|day| day.allows(trigger));
+        self.note(
+            "schedule",
+            serde_json::json!({
+                "day": weekday.to_string(),
+                "rules": if day.is_some() { "weekly" } else { "default" },
+                "window": window.map(|window| String::from(*window)),
+                "trigger_active": allowed,
+            }),
+        );
+        self.logger.log(&format!(
+            "Schedule slot day={} rules={} window={} trigger_active={}",
+            weekday,
+            if day.is_some() { "weekly" } else { "default" },
+            window.map_or_else(|| "none".to_string(), |window| String::from(*window)),
+            allowed
+        ));
+        window.is_some() || !allowed
+    }
+
+    /// Runs the remaining actions in order. Returns `Deferred` when a command was
+    /// started; the sequence then continues from `command_finished`.
+    fn run_actions(&self, mut sequence: ActionSequence) -> Decision {
+        if self.config.dry_run {
+            return self.skip(SkipReason::DryRun);
+        }
+        while let Some(&action) = sequence.actions.get(sequence.next) {
+            sequence.next += 1;
+            match self.run_action(action, sequence.trigger) {
+                Ok(()) if action == LockAction::RunCommand => {
+                    *self.running.borrow_mut() = Some(sequence);
+                    return Decision::Deferred;
+                }
+                Ok(()) => self.action_succeeded(&sequence),
+                Err(e) => self.action_failed(&mut sequence, e),
+            }
+        }
+
+        if sequence.errors.is_empty() {
+            return Decision::Locked;
+        }
+
+        if let Some(alert) = &self.alert {
+            alert.send(format!("lidlock failed on {}: {}", hostname(), sequence.errors.join("; ")));
+        }
+        Decision::Failed
+    }
+
+    // Continues a sequence outside the original decision, reporting it once it completes
+    fn resume_actions(&self, sequence: ActionSequence) {
+        let (trigger, state, actions) = (sequence.trigger, sequence.state, sequence.actions.clone());
+        let decision = self.run_actions(sequence);
+        if decision != Decision::Deferred {
+            self.emit(LockEvent {
+                trigger,
+                state,
+                actions,
+                decision,
+            });
+            self.start_queued();
+        }
+    }
+
+    fn command_finished(&self, outcome: CommandOutcome) {
+        let Some(sequence) = self.running.borrow_mut().take() else {
+            return;
+        };
+        with_trigger(sequence.trigger, || self.continue_after_command(sequence, outcome));
+    }
+
+    fn continue_after_command(&self, mut sequence: ActionSequence, outcome: CommandOutcome) {
+        match outcome {
+            CommandOutcome::TimedOut(timeout) => self.logger.log(&format!(
+                "Command still running after {}s, terminated it",
+                timeout.as_secs()
+            )),
+            CommandOutcome::Exited(status) => self.logger.log(&format!("Command exited with {}", status)),
+            CommandOutcome::Failed(_) => {}
+        }
+        match outcome.into_result() {
+            Ok(()) => self.action_succeeded(&sequence),
+            Err(e) => self.action_failed(&mut sequence, e),
+        }
+        self.resume_actions(sequence);
+    }
+
+    fn action_succeeded(&self, sequence: &ActionSequence) {
+        self.logger.log(&format!(
+            "Action {}/{} {} succeeded",
+            sequence.next,
+            sequence.actions.len(),
+            sequence.actions[sequence.next - 1]
+        ));
+    }
+
+    fn action_failed(&self, sequence: &mut ActionSequence, error: String) {
+        let action = sequence.actions[sequence.next - 1];
+        self.logger.error(&format!(
+            "Action {}/{} {} failed: {}",
+            sequence.next,
+            sequence.actions.len(),
+            action,
+            error
+        ));
+        sequence.errors.push(format!("{}: {}", action, error));
+        if !self.config.continue_on_failure {
+            self.logger.log("Stopping action sequence after failure");
+            sequence.next = sequence.actions.len();
+        }
+    }
+
+    fn run_action(&self, action: LockAction, trigger: Trigger) -> Result<(), String> {
+        match action {
+            LockAction::Lock | LockAction::LockThenSleep => {
+                if let Some(lock_screen) = &self.lock_screen {
+                    lock_screen.set(trigger, &self.logger);
+                }
+                self.logger.log("Attempting to lock workstation");
+                self.lock_workstation()?;
+                self.logger.log("Workstation locked successfully");
+                if let Some(confirm) = &self.config.confirm_lock {
+                    self.await_lock_confirmation(confirm.retries);
+                }
+                if action == LockAction::LockThenSleep {
+                    self.schedule_sleep();
+                }
+                Ok(())
+            }
+            // Only starts the command; run_actions waits for WM_COMMAND_DONE
+            LockAction::RunCommand => match &self.config.command {
+                Some(command) => {
+                    let timeout = self
+                        .config
+                        .command_timeout_seconds
+                        .unwrap_or(self.config.action_timeout_seconds);
+                    self.logger.log(&format!("Running command: {} (timeout {}s)", command, timeout));
+                    action::spawn_command(command, Duration::from_secs(timeout), self.hwnd, SEQUENCE_COMMAND)
+                }
+                None => Err("no command configured".to_string()),
+            },
+            LockAction::DisplayOff => action::display_off(),
+            LockAction::DisplayOffThenLock => {
+                action::display_off()?;
+                if self.start_escalation(trigger) {
+                    Ok(())
+                } else {
+                    // Fail closed rather than leave the machine unlocked
+                    self.run_action(LockAction::Lock, trigger)
+                }
+            }
+        }
+    }
+
+    fn start_escalation(&self, trigger: Trigger) -> bool {
+        let delay = self.config.display_off_lock_seconds;
+        if unsafe { SetTimer(self.hwnd, ESCALATION_TIMER_ID, delay.saturating_mul(1000), None) } == 0 {
+            self.logger.error("Failed to start the display off timer, locking now");
+            return false;
+        }
+        self.escalation.set(Some(trigger));
+        self.logger.log(&format!("Display off, locking in {}s unless it turns back on", delay));
+        true
+    }
+
+    fn cancel_escalation(&self) {
+        if self.escalation.take().is_some() {
+            unsafe {
+                KillTimer(self.hwnd, ESCALATION_TIMER_ID);
+            }
+            self.logger.log("Display turned back on, lock after display off cancelled");
+        }
+    }
+
+    fn escalate(&self) {
+        unsafe {
+            KillTimer(self.hwnd, ESCALATION_TIMER_ID);
+        }
+        let Some(trigger) = self.escalation.take() else {
+            return;
+        };
+        with_trigger(trigger, || {
+            self.logger.log(&format!(
+                "Display stayed off for {}s, locking",
+                self.config.display_off_lock_seconds
+            ));
+            if let Err(e) = self.run_action(LockAction::Lock, trigger) {
+                self.logger.error(&format!("Lock after display off failed: {}", e));
+                if let Some(alert) = &self.alert {
+                    alert.send(format!("lidlock failed on {}: lock: {}", hostname(), e));
+                }
+            }
+        });
+    }
+
+    // Locks and starts the panic command straight away, skipping the decision
+    // pipeline so nothing can pause, defer or coalesce it
+    fn panic_lock(&self) {
+        let Some(panic) = &self.config.panic else {
+            return;
+        };
+        with_trigger(Trigger::PanicHotkey, || {
+            self.logger.error("Panic hotkey pressed, locking immediately");
+            let mut actions = vec![LockAction::Lock];
+            if panic.command.is_some() {
+                actions.push(LockAction::RunCommand);
+            }
+
+            let decision = if self.config.dry_run {
+                self.skip(SkipReason::DryRun)
+            } else {
+                let mut errors = Vec::new();
+                match self.lock_workstation() {
+                    Ok(()) => {
+                        self.logger.log("Workstation locked successfully");
+                        if let Some(confirm) = &self.config.confirm_lock {
+                            self.await_lock_confirmation(confirm.retries);
+                        }
+                    }
+                    Err(e) => errors.push(format!("lock: {}", e)),
+                }
+                if let Some(command) = &panic.command {
+                    let timeout = Duration::from_secs(self.config.action_timeout_seconds);
+                    self.logger.log(&format!("Running panic command: {}", command));
+                    if let Err(e) = action::spawn_command(command, timeout, self.hwnd, PANIC_COMMAND) {
+                        errors.push(format!("panic command: {}", e));
+                    }
+                }
+
+                if errors.is_empty() {
+                    Decision::Locked
+                } else {
+                    self.logger.error(&format!("Panic lock failed: {}", errors.join("; ")));
+                    if let Some(alert) = &self.alert {
+                        alert.send(format!("lidlock panic lock failed on {}: {}", hostname(), errors.join("; ")));
+                    }
+                    Decision::Failed
+                }
+            };
+            self.emit(LockEvent {
+                trigger: Trigger::PanicHotkey,
+                state: 0,
+                actions,
+                decision,
+            });
+        });
+    }
+
+    // LockWorkStation fails on every call under the policy, so it is only
+    // reported once and not called again until the policy is lifted
+    fn lock_workstation(&self) -> Result<(), String> {
+        if self.locking_disabled() {
+            return Err(LOCK_DISABLED_ERROR.to_string());
+        }
+        action::lock_workstation().map_err(|e| {
+            if action::lock_disabled_by_policy() {
+                self.report_lock_disabled();
+                LOCK_DISABLED_ERROR.to_string()
+            } else {
+                e
+            }
+        })
+    }
+
+    fn locking_disabled(&self) -> bool {
+        if !self.lock_disabled.get() {
+            return false;
+        }
+        if action::lock_disabled_by_policy() {
+            return true;
+        }
+        self.lock_disabled.set(false);
+        self.logger.log("The DisableLockWorkstation policy was lifted, locking again");
+        false
+    }
+
+    fn report_lock_disabled(&self) {
+        if self.lock_disabled.replace(true) {
+            return;
+        }
+        self.logger.error(
+            "Windows won't lock: the DisableLockWorkstation policy (\"Remove Lock Computer\") is set for this user. \
+             Lock actions are skipped with reason=lock_disabled until it is removed",
+        );
+        if let Some(alert) = &self.alert {
+            alert.send(format!("lidlock on {} can't lock: locking is disabled by policy", hostname()));
+        }
+    }
+
+    // Only a real closed -> open transition, not the state reported at startup
+    fn lid_opened(&self) {
+        self.logger.log("Lid opened after being closed");
+        let Some(command) = &self.config.on_lid_open_command else {
+            return;
+        };
+        if self.config.dry_run {
+            self.logger.log(&format!("Dry run: would run lid open command: {}", command));
+            return;
+        }
+        let timeout = Duration::from_secs(self.config.action_timeout_seconds);
+        self.logger.log(&format!("Running lid open command: {}", command));
+        if let Err(e) = action::spawn_command(command, timeout, self.hwnd, LID_OPEN_COMMAND) {
+            self.logger.error(&format!("Failed to start lid open command: {}", e));
+        }
+    }
+
+    fn lid_open_command_finished(&self, outcome: CommandOutcome) {
+        match outcome.into_result() {
+            Ok(()) => self.logger.log("Lid open command finished"),
+            Err(e) => self.logger.error(&format!("Lid open command failed: {}", e)),
+        }
+    }
+
+    fn panic_command_finished(&self, outcome: CommandOutcome) {
+        with_trigger(Trigger::PanicHotkey, || match outcome.into_result() {
+            Ok(()) => self.logger.log("Panic command finished"),
+            Err(e) => {
+                self.logger.error(&format!("Panic command failed: {}", e));
+                if let Some(alert) = &self.alert {
+                    alert.send(format!("lidlock panic command failed on {}: {}", hostname(), e));
+                }
+            }
+        });
+    }
+
+    // LockWorkStation only says the request was accepted, so wait for the
+    // session to actually report locked
+    fn await_lock_confirmation(&self, retries: u32) {
+        let Some(confirm) = &self.config.confirm_lock else {
+            return;
+        };
+        self.lock_confirm.set(Some(retries));
+        unsafe {
+            if SetTimer(self.hwnd, LOCK_CONFIRM_TIMER_ID, confirm.timeout_seconds.saturating_mul(1000), None) == 0 {
+                self.logger.error("Failed to start lock confirmation timer");
+                self.lock_confirm.set(None);
+                return;
+            }
+        }
+        self.logger.log(&format!(
+            "Waiting up to {}s for the session to lock",
+            confirm.timeout_seconds
+        ));
+    }
+
+    fn lock_confirmation_elapsed(&self) {
+        unsafe {
+            KillTimer(self.hwnd, LOCK_CONFIRM_TIMER_ID);
+        }
+        let (Some(retries), Some(confirm)) = (self.lock_confirm.take(), &self.config.confirm_lock) else {
+            return;
+        };
+
+        // The notification can be missed, so ask for the session state before giving up
+        match session::is_locked() {
+            Ok(Some(true)) => {
+                self.logger.log("Lock confirmed by session state query");
+                return;
+            }
+            Ok(Some(false)) => {}
+            Ok(None) => self.logger.log("Session lock state is unknown"),
+            Err(e) => self.logger.log(&format!("Failed to query session lock state: {}", e.message())),
+        }
+
+        if retries > 0 {
+            self.logger.warn(&format!(
+                "Lock not confirmed after {}s, retrying ({} left)",
+                confirm.timeout_seconds,
+                retries - 1
+            ));
+            match self.lock_workstation() {
+                Ok(()) => return self.await_lock_confirmation(retries - 1),
+                Err(e) => self.logger.error(&format!("Retrying lock failed: {}", e)),
+            }
+        }
+
+        self.logger.error(&format!("Lock not confirmed after {}s", confirm.timeout_seconds));
+        if let Some(alert) = &self.alert {
+            alert.send(format!("lidlock failed on {}: session did not lock", hostname()));
+        }
+    }
+
+    #[cfg(feature = "http-status")]
+    fn set_paused(&self, paused: bool) {
+        self.pipeline.borrow_mut().gate_mut().set_paused(paused);
+        if let Some(server) = &self.status_server {
+            server.set_paused(paused);
+        }
+        self.logger.log(if paused { "Locking paused" } else { "Locking resumed" });
+    }
+
+    fn set_armed(&self, armed: bool) {
+        let changed = self.pipeline.borrow_mut().gate_mut().set_armed(armed);
+        if !changed {
+            self.logger.log(if armed { "Already armed" } else { "Already disarmed" });
+            return;
+        }
+        #[cfg(feature = "http-status")]
+        if let Some(server) = &self.status_server {
+            server.set_armed(armed);
+        }
+        if armed {
+            self.logger.log("Armed, acting on events");
+        } else {
+            // Warned so a disarm nobody expected stands out in the log
+            self.logger.warn("Disarmed, no longer acting on events");
+        }
+    }
+
+    /// Handles an `OVERLAY_REQUEST`; returns the reply for the sender.
+    fn apply_overlay(&self, json: &str) -> isize {
+        let overlay = match serde_json::from_str::<Option<ConfigOverlay>>(json) {
+            Ok(overlay) => overlay,
+            Err(e) => {
+                self.logger.warn(&format!("Ignoring an invalid overlay: {}", e));
+                return -1;
+            }
+        };
+        let Some(overlay) = overlay else {
+            match self.overlay.borrow().as_ref() {
+                Some(_) => self.logger.log("Temporary overlay dropped, back to the configured settings"),
+                None => self.logger.log("No temporary overlay to drop"),
+            }
+            self.clear_overlay();
+            return 1;
+        };
+
+        let millis = u32::try_from(overlay.minutes.saturating_mul(60_000)).unwrap_or(u32::MAX);
+        if overlay.minutes == 0 || unsafe { SetTimer(self.hwnd, OVERLAY_TIMER_ID, millis, None) } == 0 {
+            self.logger.warn("Ignoring an overlay without a working expiry timer");
+            return -1;
+        }
+        // Also undoes the idle time of an overlay this one replaces
+        match &self.idle {
+            Some(idle) => idle.set_seconds(overlay.idle_seconds),
+            None if overlay.idle_seconds.is_some() => {
+                self.logger.warn("idle_seconds in an overlay needs [idle_lock], ignoring it")
+            }
+            None => {}
+        }
+        self.logger.log(&format!(
+            "Applied temporary overlay for {} minutes: {}",
+            overlay.minutes,
+            overlay.summary()
+        ));
+        *self.overlay.borrow_mut() = Some(overlay);
+        1
+    }
+
+    fn overlay_expired(&self) {
+        if let Some(overlay) = self.overlay.borrow().as_ref() {
+            self.logger.log(&format!(
+                "Temporary overlay expired after {} minutes, back to the configured settings",
+                overlay.minutes
+            ));
+        }
+        self.clear_overlay();
+    }
+
+    fn clear_overlay(&self) {
+        unsafe {
+            KillTimer(self.hwnd, OVERLAY_TIMER_ID);
+        }
+        if self.overlay.borrow_mut().take().is_some() {
+            if let Some(idle) = &self.idle {
+                idle.set_seconds(None);
+            }
+        }
+    }
+
+    fn schedule_sleep(&self) {
+        let delay = self.config.sleep_delay_seconds;
+        if unsafe { SetTimer(self.hwnd, SLEEP_TIMER_ID, delay.saturating_mul(1000), None) } == 0 {
+            self.logger.error("Failed to schedule sleep");
+            return;
+        }
+        self.sleep_pending.set(true);
+        self.sleep_attempts.set(0);
+        self.logger.log(&format!("Sleep scheduled in {}s", delay));
+    }
+
+    fn cancel_sleep(&self) {
+        if self.sleep_pending.replace(false) {
+            unsafe {
+                KillTimer(self.hwnd, SLEEP_TIMER_ID);
+            }
+            self.logger.log("Lid opened, scheduled sleep cancelled");
+        }
+    }
+
+    fn sleep_now(&self) {
+        unsafe {
+            KillTimer(self.hwnd, SLEEP_TIMER_ID);
+        }
+        self.sleep_pending.set(false);
+
+        let attempt = self.sleep_attempts.get() + 1;
+        self.sleep_attempts.set(attempt);
+        let retries = self.config.sleep_retry.as_ref().map_or(0, |retry| retry.retries);
+        if attempt == 1 {
+            self.logger.log("Suspending after lock");
+        } else {
+            self.logger.log(&format!("Suspending after lock, attempt {} of {}", attempt, retries + 1));
+        }
+        let suspended = unsafe {
+            SetSuspendState(BOOLEAN::from(false), BOOLEAN::from(false), BOOLEAN::from(false))
+        };
+        if suspended.0 != 0 {
+            if attempt > 1 {
+                self.logger.log(&format!("Suspended on attempt {}", attempt));
+            }
+            return;
+        }
+
+        let error = windows::core::Error::from_win32();
+        if let Some(retry) = self.config.sleep_retry.as_ref().filter(|_| attempt <= retries) {
+            self.logger.warn(&format!(
+                "Failed to suspend: {}, retrying in {}s ({} left)",
+                error.message(),
+                retry.interval_seconds,
+                retries + 1 - attempt
+            ));
+            // Pending again, so opening the lid still cancels it
+            if unsafe { SetTimer(self.hwnd, SLEEP_TIMER_ID, retry.interval_seconds.saturating_mul(1000), None) } != 0 {
+                self.sleep_pending.set(true);
+                return;
+            }
+            self.logger.error("Failed to schedule the next suspend attempt");
+        }
+        self.logger.error(&format!("Failed to suspend after {} attempt(s): {}", attempt, error.message()));
+    }
+
+    fn on_log_failed(&self, error: &str, exit: bool) {
+        if exit {
+            self.shutdown("log file became unwritable");
+            std::process::exit(LOG_FAILURE_EXIT_CODE);
+        }
+        let text = format!("lidlock on {} can no longer write its log: {}", hostname(), error);
+        if let Some(alert) = &self.alert {
+            alert.send(text.clone());
+        }
+        if let Some(tray) = &self.tray {
+            if let Err(e) = tray.notify(APP_NAME, &text) {
+                self.logger.warn(&format!("Failed to show log failure notification: {}", describe_error(&e)));
+            }
+        }
+        if self.alert.is_none() && self.tray.is_none() {
+            self.logger.warn("on_log_failure = \"alert\" needs alert_webhook_url or the tray icon, nothing was sent");
+        }
+    }
+
+    fn on_wifi_changed(&self, state: usize, ssid: &str) {
+        if state != WIFI_DISCONNECTED {
+            self.logger.log(&format!("Wi-Fi connected to {}", ssid));
+            return;
+        }
+        self.logger.log(&format!("Wi-Fi disconnected from {}", ssid));
+        let watched = self
+            .config
+            .wifi
+            .as_ref()
+            .is_some_and(|wifi| wifi.lock_on_disconnect.iter().any(|watched| watched == ssid));
+        if watched {
+            self.decide_and_act(Trigger::WifiDisconnect, 0);
+        }
+    }
+
+    // DBT_CONFIGCHANGED doesn't say which way the profile changed, so compare
+    // the dock state with the one seen last
+    fn on_dock_changed(&self) {
+        if !self.config.lock_on_undock {
+            return;
+        }
+        let docked = dock::is_docked();
+        let was_docked = self.docked.replace(docked);
+        self.logger.log(&format!(
+            "Hardware profile changed docked={} previously={}",
+            describe_flag(docked),
+            describe_flag(was_docked)
+        ));
+        if docked == Some(false) && was_docked != Some(false) {
+            self.decide_and_act(Trigger::Undock, 0);
+        }
+    }
+
+    // Replies to `--dump` with the number of events written, or -1
+    fn dump_events(&self, path: &Path) -> isize {
+        match self.history.write_to(path) {
+            Ok(count) => {
+                self.logger.log(&format!("Wrote {} recent events to {}", count, path.display()));
+                count as isize
+            }
+            Err(e) => {
+                self.logger.error(&format!("Failed to write recent events to {}: {}", path.display(), e));
+                -1
+            }
+        }
+    }
+
+    fn send_log_tail(&self, count: usize, reply_to: HWND) -> isize {
+        let Some(lines) = self.logger.tail(count) else {
+            self.logger.warn("Log tail requested, but no memory log sink is configured");
+            return -1;
+        };
+        let text = lines.concat();
+        let data = COPYDATASTRUCT {
+            dwData: LOG_TAIL_REPLY,
+            cbData: text.len() as u32,
+            lpData: text.as_ptr() as *mut _,
+        };
+        let sent = unsafe {
+            SendMessageTimeoutW(
+                reply_to,
+                WM_COPYDATA,
+                WPARAM(self.hwnd.0 as usize),
+                LPARAM(&data as *const COPYDATASTRUCT as isize),
+                SMTO_ABORTIFHUNG,
+                LOG_TAIL_TIMEOUT_MS,
+                None,
+            )
+        };
+        if sent.0 == 0 {
+            self.logger.warn(&format!(
+                "Failed to send the log tail: {}",
+                describe_error(&windows::core::Error::from_win32())
+            ));
+            return -1;
+        }
+        self.logger.log(&format!("Sent the last {} log lines", lines.len()));
+        lines.len() as isize
+    }
+
+    // Every skip goes through here so each one logs exactly one `reason=` line
+    fn skip(&self, reason: SkipReason) -> Decision {
+        self.logger.log(&format!("Skipping lock reason={}", reason));
+        Decision::Skipped(reason)
+    }
+
+    unsafe extern "system" fn window_proc(
+        hwnd: HWND,
+        msg: u32,
+        wparam: WPARAM,
+        lparam: LPARAM,
+    ) -> LRESULT {
+        let window = GetWindowLongPtrW(hwnd, GWLP_USERDATA) as *const Self;
+        if window.is_null() {
+            return DefWindowProcW(hwnd, msg, wparam, lparam);
+        }
+        let window = &*window;
+
+        match msg {
+            WM_POWERBROADCAST => {
+                window.logger.log("Received WM_POWERBROADCAST");
+
+                // The lid may have changed while asleep without a notification
+                if wparam.0 == PBT_APMRESUMEAUTOMATIC as usize && window.config.recheck_on_resume {
+                    window.reconcile_after_resume();
+                }
+
+                if wparam.0 == PBT_POWERSETTINGCHANGE as usize {
+                    window.logger.log("Received PBT_POWERSETTINGCHANGE");
+
+                    let (guid, data) = match read_power_broadcast(lparam.0 as *const POWERBROADCAST_SETTING) {
+                        Ok(broadcast) => broadcast,
+                        Err(e) => {
+                            window.logger.warn(&format!("Ignoring malformed power broadcast: {}", e));
+                            return LRESULT(0);
+                        }
+                    };
+                    window.logger.debug(&format!(
+                        "Power setting {} data_length={} data={}",
+                        format_guid(&guid),
+                        data.len(),
+                        data.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()
+                    ));
+                    let (trigger, state) = match parse_power_setting(&guid, data) {
+                        Ok(parsed) => parsed,
+                        Err(e) => {
+                            window.logger.log(&format!("Ignoring power setting change: {}", e));
+                            return LRESULT(0);
+                        }
+                    };
+
+                    window.logger.log(&describe_power_state(trigger, state));
+                    if trigger == Trigger::MonitorPower && state == 1 {
+                        window.cancel_escalation();
+                    }
+                    match trigger {
+                        Trigger::LidSwitch => window.update_presence(PresenceInput::Lid, state == 0),
+                        Trigger::MonitorPower => window.update_presence(PresenceInput::Display, state == 0),
+                        _ => {}
+                    }
+                    if !window.power_trigger_enabled(trigger) {
+                        return LRESULT(0);
+                    }
+                    if trigger == Trigger::LowBattery {
+                        // Crossing the threshold behaves like a power setting switching off
+                        if window.battery_crossed_threshold(state)
+                            && matches!(window.decide_and_act(trigger, 0), Decision::Skipped(_))
+                        {
+                            window.battery.borrow_mut().release();
+                        }
+                    } else {
+                        window.decide_and_act(trigger, state);
+                    }
+                }
+            }
+            WM_DESTROY => {
+                window.logger.log("Window destroyed, leaving message loop");
+                PostQuitMessage(0);
+            }
+            WM_TIMER => match wparam.0 {
+                IDLE_TIMER_ID | BLANK_TIMER_ID => {
+                    if let Some(idle) = &window.idle {
+                        let lock = if wparam.0 == IDLE_TIMER_ID {
+                            idle.check(&window.logger)
+                        } else {
+                            idle.finish_blank()
+                        };
+                        if lock || idle.blanking() {
+                            window.publish_state("idle");
+                        }
+                        if lock {
+                            // Idle behaves like a power setting switching off
+                            window.decide_and_act(Trigger::Idle, 0);
+                        }
+                    }
+                }
+                SLEEP_TIMER_ID => window.sleep_now(),
+                ESCALATION_TIMER_ID => window.escalate(),
+                INPUT_DEFER_TIMER_ID => window.check_input_defer(),
+                OVERLAY_TIMER_ID => window.overlay_expired(),
+                PRESENCE_TIMER_ID => {
+                    if let Some(presence) = &window.config.presence {
+                        let idle = u64::from(idle_duration_ms()) >= presence.idle_seconds.saturating_mul(1000);
+                        window.update_presence(PresenceInput::Idle, idle);
+                    }
+                }
+                HOLD_TIMER_ID => window.hold_elapsed(),
+                COUNTDOWN_TIMER_ID => window.update_countdown(),
+                SCHEDULE_TIMER_ID => window.check_scheduled_locks(),
+                LOCK_CONFIRM_TIMER_ID => window.lock_confirmation_elapsed(),
+                PROMPT_TIMER_ID => {
+                    let lock = window.prompt.as_ref().is_some_and(ConfirmPrompt::default_locks);
+                    window.finish_prompt(lock, "timed out");
+                }
+                _ => {}
+            },
+            WM_IDLE_INPUT => {
+                if window.idle.as_ref().is_some_and(|idle| idle.cancel(&window.logger)) {
+                    window.leave_idle_state();
+                }
+            }
+            WM_COMMAND_DONE => {
+                let outcome = Box::from_raw(lparam.0 as *mut CommandOutcome);
+                match wparam.0 {
+                    PANIC_COMMAND => window.panic_command_finished(*outcome),
+                    LID_OPEN_COMMAND => window.lid_open_command_finished(*outcome),
+                    _ => window.command_finished(*outcome),
+                }
+            }
+            WM_HOTKEY if wparam.0 == PANIC_HOTKEY_ID as usize => window.panic_lock(),
+            WM_HOTKEY if wparam.0 == ARM_HOTKEY_ID as usize => window.set_armed(true),
+            WM_ARM => window.set_armed(wparam.0 != 0),
+            WM_PROMPT_RESULT => window.finish_prompt(wparam.0 != 0, "answered"),
+            WM_COPYDATA => {
+                let data = &*(lparam.0 as *const COPYDATASTRUCT);
+                if data.dwData == LOG_TAIL_REQUEST
+                    && !data.lpData.is_null()
+                    && data.cbData as usize >= std::mem::size_of::<u32>()
+                {
+                    let count = std::ptr::read_unaligned(data.lpData as *const u32);
+                    return LRESULT(window.send_log_tail(count as usize, HWND(wparam.0 as isize)));
+                }
+                if data.dwData == OVERLAY_REQUEST && !data.lpData.is_null() {
+                    let json = std::slice::from_raw_parts(data.lpData as *const u8, data.cbData as usize);
+                    return LRESULT(window.apply_overlay(&String::from_utf8_lossy(json)));
+                }
+                if data.dwData != DUMP_EVENTS_REQUEST || data.lpData.is_null() {
+                    return DefWindowProcW(hwnd, msg, wparam, lparam);
+                }
+                let path = std::slice::from_raw_parts(
+                    data.lpData as *const u16,
+                    data.cbData as usize / std::mem::size_of::<u16>(),
+                );
+                let len = path.iter().position(|&c| c == 0).unwrap_or(path.len());
+                let path = String::from_utf16_lossy(&path[..len]);
+                return LRESULT(window.dump_events(Path::new(&path)));
+            }
+            WM_DOCK_CHANGED => window.on_dock_changed(),
+            // With NOTIFYICON_VERSION_4 the low word carries the event
+            WM_TRAY => window.on_tray(lparam.0 as u32 & 0xFFFF),
+            WM_SESSION_ENDING => {
+                let signing_out = lparam.0 as u32 & ENDSESSION_LOGOFF != 0;
+                window.shutdown(if signing_out { SIGNING_OUT } else { "system shutting down" });
+            }
+            WM_LOG_FAILED => {
+                let error = Box::from_raw(lparam.0 as *mut String);
+                window.on_log_failed(&error, wparam.0 != 0);
+            }
+            WM_WIFI_CHANGED => {
+                let ssid = Box::from_raw(lparam.0 as *mut String);
+                window.on_wifi_changed(wparam.0, &ssid);
+            }
+            WM_WTSSESSION_CHANGE => window.on_session_change(wparam.0 as u32),
+            #[cfg(feature = "motion")]
+            WM_MOTION => {
+                window.logger.log("Machine moved");
+                window.decide_and_act(Trigger::Motion, 0);
+            }
+            #[cfg(feature = "http-status")]
+            WM_REMOTE_LOCK => {
+                window.decide_and_act(Trigger::Manual, 0);
+            }
+            #[cfg(feature = "http-status")]
+            WM_REMOTE_PAUSE => window.set_paused(wparam.0 != 0),
+            _ => return DefWindowProcW(hwnd, msg, wparam, lparam),
+        }
+        LRESULT(0)
+    }
+}
+
+// yes/no, or unknown when the state couldn't be read
+fn describe_flag(flag: Option<bool>) -> &'static str {
+    match flag {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "unknown",
+    }
+}
+
+fn describe_power_state(trigger: Trigger, state: u32) -> String {
+    match (trigger, state) {
+        (Trigger::LidSwitch, 0) => "Lid closed".to_string(),
+        (Trigger::LidSwitch, 1) => "Lid opened".to_string(),
+        (Trigger::MonitorPower, 0) => "Display off".to_string(),
+        (Trigger::MonitorPower, 1) => "Display on".to_string(),
+        (Trigger::MonitorPower, 2) => "Display dimmed".to_string(),
+        (Trigger::LowBattery, percent) => format!("Battery at {}%", percent),
+        _ => format!("{} state: {}", trigger, state),
+    }
+}
+
+/// Reads the setting and its payload from a `PBT_POWERSETTINGCHANGE` lparam,
+/// refusing a null pointer or a payload longer than any setting carries.
+///
+/// # Safety
+/// A non-null `setting` must point to a broadcast with at least `DataLength`
+/// bytes after the header, as the system delivers it.
+unsafe fn read_power_broadcast<'a>(
+    setting: *const POWERBROADCAST_SETTING,
+) -> Result<(windows::core::GUID, &'a [u8]), String> {
+    if setting.is_null() {
+        return Err("lparam is null".to_string());
+    }
+    // The header isn't guaranteed to be aligned
+    let header = std::ptr::read_unaligned(setting);
+    let length = header.DataLength as usize;
+    if length > MAX_POWER_SETTING_DATA {
+        return Err(format!(
+            "{} carries {} bytes, at most {} expected",
+            format_guid(&header.PowerSetting),
+            length,
+            MAX_POWER_SETTING_DATA
+        ));
+    }
+    // DataLength bytes follow the header, however long the declared array is
+    let data = std::ptr::addr_of!((*setting).Data).cast::<u8>();
+    Ok((header.PowerSetting, std::slice::from_raw_parts(data, length)))
+}
+
+/// Maps a power setting notification to its trigger and state, checking the
+/// payload is the size that setting is documented to carry.
+fn parse_power_setting(guid: &windows::core::GUID, data: &[u8]) -> Result<(Trigger, u32), String> {
+    let trigger = if *guid == GUID_LIDSWITCH_STATE_CHANGE {
+        Trigger::LidSwitch
+    } else if *guid == GUID_MONITOR_POWER_ON {
+        Trigger::MonitorPower
+    } else if *guid == GUID_BATTERY_PERCENTAGE_REMAINING {
+        Trigger::LowBattery
+    } else {
+        return Err(format!("unexpected setting {}", format_guid(guid)));
+    };
+
+    // Every setting carries a single DWORD
+    let bytes: [u8; 4] = data.try_into().map_err(|_| {
+        format!(
+            "{} carries {} bytes, expected {}",
+            trigger,
+            data.len(),
+            std::mem::size_of::<u32>()
+        )
+    })?;
+    Ok((trigger, u32::from_ne_bytes(bytes)))
+}
+
+// Runs on a thread of its own; closing the window shuts down like --stop does
+unsafe extern "system" fn console_ctrl_handler(event: u32) -> BOOL {
+    if event != CTRL_C_EVENT && event != CTRL_BREAK_EVENT {
+        return BOOL::from(false);
+    }
+    let hwnd = HWND(CTRL_C_TARGET.load(Ordering::Relaxed));
+    BOOL::from(hwnd.0 != 0 && PostMessageW(hwnd, WM_CLOSE, WPARAM(0), LPARAM(0)).as_bool())
+}
+
+fn format_guid(guid: &windows::core::GUID) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{}",
+        guid.data1,
+        guid.data2,
+        guid.data3,
+        guid.data4[0],
+        guid.data4[1],
+        guid.data4[2..].iter().map(|byte| format!("{:02x}", byte)).collect::<String>()
+    )
+}
+
+impl Drop for LidLockWindow {
+    fn drop(&mut self) {
+        self.shutdown("exiting");
+        unsafe {
+            // Detach before destroying so window_proc never sees a dangling pointer
+            SetWindowLongPtrW(self.hwnd, GWLP_USERDATA, 0);
+            if IsWindow(self.hwnd).as_bool() {
+                DestroyWindow(self.hwnd);
+            }
+        }
+        Self::unregister_class(&self.class_name);
+        self.logger.log("Window destroyed");
+        self.logger.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::offset_of;
+    use windows::core::GUID;
+
+    const UNKNOWN_SETTING: GUID = GUID::from_u128(0x01234567_89ab_cdef_0123_456789abcdef);
+
+    // Lays out a POWERBROADCAST_SETTING as Windows sends it, declaring
+    // `data_length` bytes whatever `data` actually holds. One byte in so the
+    // header is misaligned like it may be in the real lparam.
+    fn broadcast(guid: &GUID, data_length: u32, data: &[u8]) -> Vec<u8> {
+        let start = 1;
+        let data_offset = start + offset_of!(POWERBROADCAST_SETTING, Data);
+        let mut buffer = vec![0u8; data_offset + data.len().max(1)];
+        unsafe {
+            let setting = buffer.as_mut_ptr().add(start);
+            std::ptr::write_unaligned(setting.add(offset_of!(POWERBROADCAST_SETTING, PowerSetting)).cast(), *guid);
+            std::ptr::write_unaligned(setting.add(offset_of!(POWERBROADCAST_SETTING, DataLength)).cast(), data_length);
+        }
+        buffer[data_offset..data_offset + data.len()].copy_from_slice(data);
+        buffer
+    }
+
+    fn read(buffer: &[u8]) -> Result<(GUID, Vec<u8>), String> {
+        let setting = unsafe { buffer.as_ptr().add(1) }.cast::<POWERBROADCAST_SETTING>();
+        unsafe { read_power_broadcast(setting) }.map(|(guid, data)| (guid, data.to_vec()))
+    }
+
+    fn shutdown_position(step: ShutdownStep) -> usize {
+        SHUTDOWN_STEPS.iter().position(|other| *other == step).unwrap()
+    }
+
+    #[test]
+    fn shutdown_runs_every_step_once() {
+        for step in SHUTDOWN_STEPS {
+            assert_eq!(SHUTDOWN_STEPS.iter().filter(|other| **other == step).count(), 1, "{:?}", step);
+        }
+    }
+
+    #[test]
+    fn shutdown_stops_input_before_acting_and_flushes_last() {
+        use ShutdownStep::*;
+        assert_eq!(SHUTDOWN_STEPS.first(), Some(&UnregisterNotifications));
+        assert_eq!(SHUTDOWN_STEPS.last(), Some(&FlushLog));
+        // No remote lock or pause may arrive while locking on stop
+        assert!(shutdown_position(StopServers) < shutdown_position(LockOnStop));
+        // A failed lock on stop can still alert and be counted
+        assert!(shutdown_position(LockOnStop) < shutdown_position(StopWorkers));
+        // A new instance may only start once this one no longer acts
+        for step in [LockOnStop, StopWorkers, RestoreLockScreen] {
+            assert!(shutdown_position(step) < shutdown_position(ReleaseSingleton), "{:?}", step);
+        }
+    }
+
+    #[test]
+    fn null_broadcast_is_rejected() {
+        assert!(unsafe { read_power_broadcast(std::ptr::null()) }.is_err());
+    }
+
+    #[test]
+    fn oversized_data_length_is_rejected_before_reading() {
+        // Declares far more than the buffer holds; nothing past the header may be read
+        let buffer = broadcast(&GUID_LIDSWITCH_STATE_CHANGE, u32::MAX, &[]);
+        assert!(read(&buffer).is_err());
+        let buffer = broadcast(&GUID_LIDSWITCH_STATE_CHANGE, MAX_POWER_SETTING_DATA as u32 + 1, &[]);
+        assert!(read(&buffer).is_err());
+    }
+
+    #[test]
+    fn crafted_broadcast_reads_declared_bytes() {
+        let buffer = broadcast(&GUID_LIDSWITCH_STATE_CHANGE, 4, &1u32.to_ne_bytes());
+        let (guid, data) = read(&buffer).unwrap();
+        assert_eq!(guid, GUID_LIDSWITCH_STATE_CHANGE);
+        assert_eq!(parse_power_setting(&guid, &data), Ok((Trigger::LidSwitch, 1)));
+    }
+
+    #[test]
+    fn wrong_payload_length_is_rejected() {
+        let buffer = broadcast(&GUID_MONITOR_POWER_ON, 1, &[0]);
+        let (guid, data) = read(&buffer).unwrap();
+        assert_eq!(data.len(), 1);
+        assert!(parse_power_setting(&guid, &data).is_err());
+    }
+
+    #[test]
+    fn each_setting_maps_to_its_trigger() {
+        let settings = [
+            (GUID_LIDSWITCH_STATE_CHANGE, Trigger::LidSwitch, 0),
+            (GUID_MONITOR_POWER_ON, Trigger::MonitorPower, 2),
+            (GUID_BATTERY_PERCENTAGE_REMAINING, Trigger::LowBattery, 15),
+        ];
+        for (guid, trigger, state) in settings {
+            assert_eq!(parse_power_setting(&guid, &u32::to_ne_bytes(state)), Ok((trigger, state)));
+        }
+    }
+
+    #[test]
+    fn every_setting_checks_its_payload_size() {
+        for guid in [GUID_LIDSWITCH_STATE_CHANGE, GUID_MONITOR_POWER_ON, GUID_BATTERY_PERCENTAGE_REMAINING] {
+            for length in [0, 1, 3, 5, 8] {
+                assert!(parse_power_setting(&guid, &vec![0; length]).is_err(), "{} bytes", length);
+            }
+        }
+    }
+
+    // Sends crafted broadcasts through the real window_proc. No actions are
+    // configured, so a decision is recorded without locking anything.
+    #[cfg(windows)]
+    #[test]
+    fn window_proc_acts_only_on_well_formed_broadcasts() {
+        let config = Config {
+            actions: Some(Vec::new()),
+            min_uptime_seconds: 0,
+            ..Config::default()
+        };
+        let window = LidLockWindow::new_once(Logger::new(None), config).unwrap();
+        let send = |buffer: &[u8]| unsafe {
+            let setting = buffer.as_ptr().add(1);
+            SendMessageW(window.hwnd, WM_POWERBROADCAST, WPARAM(PBT_POWERSETTINGCHANGE as usize), LPARAM(setting as isize));
+        };
+
+        send(&broadcast(&GUID_LIDSWITCH_STATE_CHANGE, u32::MAX, &[]));
+        send(&broadcast(&GUID_LIDSWITCH_STATE_CHANGE, 1, &[0]));
+        send(&broadcast(&UNKNOWN_SETTING, 4, &0u32.to_ne_bytes()));
+        assert!(window.last_decision().is_none());
+
+        send(&broadcast(&GUID_LIDSWITCH_STATE_CHANGE, 4, &0u32.to_ne_bytes()));
+        let last = window.last_decision().unwrap();
+        assert_eq!((last.event.trigger, last.event.state), (Trigger::LidSwitch, 0));
+        assert_eq!(last.event.decision, Decision::Skipped(SkipReason::NoActions));
+    }
+
+    #[test]
+    fn unknown_setting_is_rejected() {
+        let buffer = broadcast(&UNKNOWN_SETTING, 4, &0u32.to_ne_bytes());
+        let (guid, data) = read(&buffer).unwrap();
+        assert!(parse_power_setting(&guid, &data).is_err());
+    }
+}
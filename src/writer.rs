@@ -0,0 +1,56 @@
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use crate::logger::Logger;
+
+/// Writes records to a file on a background thread so disk access never blocks
+/// the message loop. Used by the stats file and the decision log.
+pub(crate) struct BackgroundWriter<T> {
+    // Both None once stopped
+    sender: RefCell<Option<Sender<T>>>,
+    thread: RefCell<Option<JoinHandle<()>>>,
+}
+
+impl<T: Send + 'static> BackgroundWriter<T> {
+    /// Starts the thread, which hands each record to `write` in the order sent.
+    /// Failures are logged after `failure` and the path.
+    pub(crate) fn start<E: Display>(
+        path: PathBuf,
+        logger: Arc<Logger>,
+        failure: &'static str,
+        write: impl Fn(&Path, T) -> Result<(), E> + Send + 'static,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel::<T>();
+
+        let thread = std::thread::spawn(move || {
+            for record in receiver {
+                if let Err(e) = write(&path, record) {
+                    logger.log(&format!("{} {}: {}", failure, path.display(), e));
+                }
+            }
+        });
+
+        BackgroundWriter {
+            sender: RefCell::new(Some(sender)),
+            thread: RefCell::new(Some(thread)),
+        }
+    }
+
+    pub(crate) fn send(&self, record: T) {
+        if let Some(sender) = &*self.sender.borrow() {
+            let _ = sender.send(record);
+        }
+    }
+
+    /// Writes out what is queued and ends the thread.
+    pub(crate) fn stop(&self) {
+        self.sender.borrow_mut().take();
+        if let Some(thread) = self.thread.borrow_mut().take() {
+            let _ = thread.join();
+        }
+    }
+}